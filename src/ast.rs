@@ -0,0 +1,245 @@
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+use crate::{format_number, operator_symbol, to_rpn, tokenize, Calculator, Error, Token};
+
+/// An expression as a tree, so that it can be built in code instead of
+/// parsed from text. Operators are the same characters as in `Token`, e.g.
+/// `'^'` or the percentage `PERCENT`.
+///
+/// The arithmetic operators build trees too, so
+/// `Expr::Num(2.0) * Expr::Var("x".to_string()) + 1.0.into()` is `2 * x + 1`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Num(f64),
+    /// A variable or a constant such as `e`.
+    Var(String),
+    BinOp(char, Box<Expr>, Box<Expr>),
+    UnaryOp(char, Box<Expr>),
+    /// A function call with its arguments, e.g. `hypot(3, 4)`.
+    Call(String, Vec<Expr>),
+}
+
+impl Expr {
+    pub fn binary(op: char, left: Expr, right: Expr) -> Self {
+        Expr::BinOp(op, Box::new(left), Box::new(right))
+    }
+
+    pub fn unary(op: char, operand: Expr) -> Self {
+        Expr::UnaryOp(op, Box::new(operand))
+    }
+
+    /// Draws the tree in ASCII, one node per line with its operands indented
+    /// below it, so that what is computed first is deepest:
+    ///
+    /// ```text
+    /// +
+    /// |-- 2
+    /// `-- *
+    ///     |-- 3
+    ///     `-- 4
+    /// ```
+    pub fn tree(&self) -> Vec<String> {
+        let mut lines = vec![self.label()];
+        self.push_children(&mut lines, "");
+        lines
+    }
+
+    fn label(&self) -> String {
+        match self {
+            Expr::Num(num) => format_number(*num, None),
+            Expr::Var(name) => name.clone(),
+            Expr::BinOp(op, ..) | Expr::UnaryOp(op, _) => operator_symbol(*op),
+            Expr::Call(name, _) => format!("{}()", name),
+        }
+    }
+
+    fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Num(_) | Expr::Var(_) => Vec::new(),
+            Expr::BinOp(_, left, right) => vec![left, right],
+            Expr::UnaryOp(_, operand) => vec![operand],
+            Expr::Call(_, args) => args.iter().collect(),
+        }
+    }
+
+    fn push_children(&self, lines: &mut Vec<String>, indent: &str) {
+        let children = self.children();
+        for (i, child) in children.iter().enumerate() {
+            let last = i + 1 == children.len();
+            let (branch, continuation) = if last {
+                ("`-- ", "    ")
+            } else {
+                ("|-- ", "|   ")
+            };
+            lines.push(format!("{}{}{}", indent, branch, child.label()));
+            child.push_children(lines, &format!("{}{}", indent, continuation));
+        }
+    }
+
+    /// Builds the tree for expression tokens already in RPN. Vectors,
+    /// durations and quantities have no place in the tree.
+    pub(crate) fn from_rpn(tokens: &[Token]) -> Result<Self, Error> {
+        let not_enough =
+            || Error::InvalidExpression("Not enough operands for operator".to_string());
+        let mut stack = Vec::new();
+        for token in tokens {
+            let expr = match token {
+                Token::Number(num) => Expr::Num(*num),
+                Token::Variable(name) => Expr::Var(name.clone()),
+                Token::UnaryOperator(op) => Expr::unary(*op, stack.pop().ok_or_else(not_enough)?),
+                Token::Operator(op) => {
+                    let right = stack.pop().ok_or_else(not_enough)?;
+                    let left = stack.pop().ok_or_else(not_enough)?;
+                    Expr::binary(*op, left, right)
+                }
+                Token::Call(name, count) => {
+                    if stack.len() < *count {
+                        return Err(Error::WrongArgumentCount(name.clone(), *count, stack.len()));
+                    }
+                    Expr::Call(name.clone(), stack.split_off(stack.len() - count))
+                }
+                _ => {
+                    return Err(Error::InvalidExpression(format!(
+                        "{} cannot be part of an Expr",
+                        token
+                    )))
+                }
+            };
+            stack.push(expr);
+        }
+
+        match stack.len() {
+            0 => Err(Error::EmptyExpression),
+            1 => Ok(stack.remove(0)),
+            _ => Err(Error::TooManyOperators),
+        }
+    }
+
+    /// The expression as RPN tokens, ready to evaluate.
+    pub(crate) fn to_rpn(&self) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        self.push_rpn(&mut tokens);
+        tokens
+    }
+
+    fn push_rpn(&self, tokens: &mut Vec<Token>) {
+        match self {
+            Expr::Num(num) => tokens.push(Token::Number(*num)),
+            Expr::Var(name) => tokens.push(Token::Variable(name.clone())),
+            Expr::BinOp(op, left, right) => {
+                left.push_rpn(tokens);
+                right.push_rpn(tokens);
+                tokens.push(Token::Operator(*op));
+            }
+            Expr::UnaryOp(op, operand) => {
+                operand.push_rpn(tokens);
+                tokens.push(Token::UnaryOperator(*op));
+            }
+            Expr::Call(name, args) => {
+                for arg in args {
+                    arg.push_rpn(tokens);
+                }
+                tokens.push(Token::Call(name.clone(), args.len()));
+            }
+        }
+    }
+}
+
+impl From<f64> for Expr {
+    fn from(num: f64) -> Self {
+        Expr::Num(num)
+    }
+}
+
+macro_rules! binary_operator {
+    ($trait:ident, $method:ident, $op:literal) => {
+        impl $trait for Expr {
+            type Output = Expr;
+
+            fn $method(self, right: Expr) -> Expr {
+                Expr::binary($op, self, right)
+            }
+        }
+    };
+}
+
+binary_operator!(Add, add, '+');
+binary_operator!(Sub, sub, '-');
+binary_operator!(Mul, mul, '*');
+binary_operator!(Div, div, '/');
+
+impl Neg for Expr {
+    type Output = Expr;
+
+    fn neg(self) -> Expr {
+        Expr::unary('-', self)
+    }
+}
+
+/// Parses an expression into a tree, with the same syntax and precedence as
+/// `parse_expression`.
+pub fn parse_to_ast(input: &str) -> Result<Expr, Error> {
+    Expr::from_rpn(&to_rpn(&tokenize(input)?)?)
+}
+
+/// Evaluates a tree with a new calculator, so only constants can be used as
+/// variables.
+pub fn eval_ast(expr: &Expr) -> Result<f64, Error> {
+    Calculator::new().eval_ast(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_to_ast() {
+        assert_eq!(
+            parse_to_ast("2 + 3 * x"),
+            Ok(Expr::Num(2.0) + Expr::Num(3.0) * Expr::Var("x".to_string()))
+        );
+        assert_eq!(
+            parse_to_ast("-hypot(3, 4) ^ 2"),
+            Ok(-Expr::binary(
+                '^',
+                Expr::Call("hypot".to_string(), vec![3.0.into(), 4.0.into()]),
+                2.0.into()
+            ))
+        );
+        assert!(matches!(
+            parse_to_ast("[1, 2] + 1"),
+            Err(Error::InvalidExpression(_))
+        ));
+        assert_eq!(parse_to_ast(""), Err(Error::EmptyExpression));
+    }
+
+    #[test]
+    fn test_tree() {
+        assert_eq!(
+            parse_to_ast("2 + 3 * 4").unwrap().tree(),
+            vec!["+", "|-- 2", "`-- *", "    |-- 3", "    `-- 4"]
+        );
+        assert_eq!(
+            parse_to_ast("hypot(1 - 2, 4) <= -x").unwrap().tree(),
+            vec![
+                "<=",
+                "|-- hypot()",
+                "|   |-- -",
+                "|   |   |-- 1",
+                "|   |   `-- 2",
+                "|   `-- 4",
+                "`-- -",
+                "    `-- x",
+            ]
+        );
+        assert_eq!(Expr::Num(0.5).tree(), vec!["0.5"]);
+    }
+
+    #[test]
+    fn test_to_rpn_round_trip() {
+        for input in ["2 - 3 - 4", "2 ^ 3 ^ 2", "200 + 10%", "sqrt(16) |> sq"] {
+            let rpn = to_rpn(&tokenize(input).unwrap()).unwrap();
+            assert_eq!(Expr::from_rpn(&rpn).unwrap().to_rpn(), rpn);
+        }
+    }
+}
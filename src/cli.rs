@@ -0,0 +1,481 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use crate::repl::parse_base;
+use crate::{strip_comment, AngleMode, Error, NumberFormat, ReplConfig, RoundingMode};
+
+/// Text printed for `--version`.
+pub const VERSION: &str = concat!("calculator-cli ", env!("CARGO_PKG_VERSION"));
+
+/// What the command line asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub config: ReplConfig,
+    /// Expression given as positional arguments, evaluated instead of
+    /// starting the interactive calculator.
+    pub expression: Option<String>,
+    /// Script whose lines are run in order instead of starting the
+    /// interactive calculator.
+    pub file: Option<PathBuf>,
+    /// File that results are written to instead of stdout, for `--file` and
+    /// command-line expressions. Errors still go to stderr.
+    pub output: Option<PathBuf>,
+    /// Unix domain socket to answer expressions on instead of starting the
+    /// interactive calculator.
+    pub socket: Option<PathBuf>,
+    /// Show the prompt and banner even when stdin is not a terminal.
+    pub interactive: bool,
+    /// Print the version and exit.
+    pub version: bool,
+}
+
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Options, Error> {
+    parse_args_with(args, ReplConfig::default())
+}
+
+/// Like `parse_args`, but starting from `config`, e.g. as read from the
+/// config file, so that flags override it.
+pub fn parse_args_with<I: IntoIterator<Item = String>>(
+    args: I,
+    mut config: ReplConfig,
+) -> Result<Options, Error> {
+    let mut words = Vec::new();
+    let mut interactive = false;
+    let mut version = false;
+    let mut file = None;
+    let mut output = None;
+    let mut socket = None;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--quiet" => config.quiet = true,
+            // Nothing but the results, e.g. for a display that shows them
+            "--bare-output" => {
+                config.bare_output = true;
+                config.quiet = true;
+                config.prompt.clear();
+            }
+            "--format" => {
+                let value = flag_value(&mut args, &arg)?;
+                if value == "json-lines" {
+                    config.json_lines = true;
+                    config.quiet = true;
+                    config.prompt.clear();
+                } else {
+                    config.format = NumberFormat::preset(&value)
+                        .ok_or_else(|| Error::InvalidArgument(format!("--format {}", value)))?;
+                }
+            }
+            "--stats" => config.show_stats = true,
+            "--exact" => config.exact = true,
+            "--decimal-comma" => config.decimal_comma = true,
+            "--strict" => config.strict = true,
+            // Turn off what the config file turned on
+            "--no-exact" => config.exact = false,
+            "--no-decimal-comma" => config.decimal_comma = false,
+            "--no-strict" => config.strict = false,
+            "--no-bool-display" => config.bool_display = false,
+            "--mode" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.angle_mode = match value.as_str() {
+                    "deg" | "degrees" => AngleMode::Degrees,
+                    "rad" | "radians" => AngleMode::Radians,
+                    _ => return Err(Error::InvalidArgument(format!("--mode {}", value))),
+                };
+            }
+            "--ambiguous-report" => config.ambiguous_report = true,
+            "--propagate-nan" => config.propagate_nan = true,
+            "--round-intermediate" => config.round_intermediate = true,
+            "--kahan" => config.kahan = true,
+            "--physics" => config.physics = true,
+            "--explain-precedence" => config.explain_precedence = true,
+            "--interactive" => interactive = true,
+            "--version" => version = true,
+            "--precision" => {
+                let value = flag_value(&mut args, &arg)?;
+                let precision = value
+                    .parse()
+                    .map_err(|_| Error::InvalidArgument(format!("--precision {}", value)))?;
+                config.format.precision = Some(precision);
+            }
+            "--precision-auto" => config.format.trim_zeros = true,
+            "--base" => {
+                let value = flag_value(&mut args, &arg)?;
+                config.base = parse_base(&value)
+                    .ok_or_else(|| Error::InvalidArgument(format!("--base {}", value)))?;
+            }
+            "--file" => file = Some(PathBuf::from(flag_value(&mut args, &arg)?)),
+            "--output" => output = Some(PathBuf::from(flag_value(&mut args, &arg)?)),
+            "--socket" => socket = Some(PathBuf::from(flag_value(&mut args, &arg)?)),
+            "--timeout-ms" => {
+                let value = flag_value(&mut args, &arg)?;
+                let millis = value
+                    .parse()
+                    .map_err(|_| Error::InvalidArgument(format!("--timeout-ms {}", value)))?;
+                config.timeout = Some(Duration::from_millis(millis));
+            }
+            "--seed" => {
+                let value = flag_value(&mut args, &arg)?;
+                let seed = value
+                    .parse()
+                    .map_err(|_| Error::InvalidArgument(format!("--seed {}", value)))?;
+                config.seed = Some(seed);
+            }
+            "--rc" => config.rc_file = Some(PathBuf::from(flag_value(&mut args, &arg)?)),
+            "-e" => config.startup.push(flag_value(&mut args, &arg)?),
+            _ if arg.starts_with("--") => return Err(Error::InvalidArgument(arg)),
+            _ => words.push(arg),
+        }
+    }
+
+    Ok(Options {
+        config,
+        expression: (!words.is_empty()).then(|| words.join(" ")),
+        file,
+        output,
+        socket,
+        interactive,
+        version,
+    })
+}
+
+/// Reads default settings from a config file, returning them with a message
+/// for each line that could not be used.
+pub fn load_config(path: &Path) -> (ReplConfig, Vec<String>) {
+    let mut config = ReplConfig::default();
+    let messages = match fs::read_to_string(path) {
+        Ok(contents) => apply_config(&contents, &mut config)
+            .into_iter()
+            .map(|(line, e)| format!("{}:{}: {}", path.display(), line, e))
+            .collect(),
+        Err(e) => vec![format!("Could not read {}: {}", path.display(), e)],
+    };
+    (config, messages)
+}
+
+/// Applies `key = value` settings, one per line, to `config`:
+///
+/// ```text
+/// precision = 4      # or none
+/// mode = deg         # or rad
+/// rounding = half-up # or half-even, truncate
+/// format = currency  # or default
+/// base = 16          # or 10, 8, 2
+/// exact = on         # also strict, decimal-comma and bool-display
+/// ```
+///
+/// Blank lines and `#` comments are skipped. Returns the line number and
+/// error for each line that could not be applied; the other lines still are.
+pub fn apply_config(contents: &str, config: &mut ReplConfig) -> Vec<(usize, Error)> {
+    let mut errors = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Err(e) = apply_setting(line, config) {
+            errors.push((index + 1, e));
+        }
+    }
+    errors
+}
+
+fn apply_setting(line: &str, config: &mut ReplConfig) -> Result<(), Error> {
+    let invalid = || Error::InvalidArgument(line.to_string());
+    let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+    let (key, value) = (key.trim(), value.trim());
+    let switch = || match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(invalid()),
+    };
+
+    match key {
+        "precision" if value == "none" => config.format.precision = None,
+        "precision" => config.format.precision = Some(value.parse().map_err(|_| invalid())?),
+        "mode" => {
+            config.angle_mode = match value {
+                "deg" | "degrees" => AngleMode::Degrees,
+                "rad" | "radians" => AngleMode::Radians,
+                _ => return Err(invalid()),
+            }
+        }
+        "rounding" => {
+            config.format.rounding = match value {
+                "half-up" => RoundingMode::HalfUp,
+                "half-even" => RoundingMode::HalfEven,
+                "truncate" => RoundingMode::Truncate,
+                _ => return Err(invalid()),
+            }
+        }
+        "format" => config.format = NumberFormat::preset(value).ok_or_else(invalid)?,
+        "base" => config.base = parse_base(value).ok_or_else(invalid)?,
+        "exact" => config.exact = switch()?,
+        "strict" => config.strict = switch()?,
+        "decimal-comma" => config.decimal_comma = switch()?,
+        "bool-display" => config.bool_display = switch()?,
+        _ => return Err(invalid()),
+    }
+    Ok(())
+}
+
+/// Hides the prompt and banner when input is piped in, unless `--interactive`
+/// asked for the full REPL anyway.
+pub fn apply_terminal_detection(options: &mut Options, stdin_is_terminal: bool) {
+    if !stdin_is_terminal && !options.interactive {
+        options.config.quiet = true;
+        options.config.prompt.clear();
+    }
+}
+
+fn flag_value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, Error> {
+    args.next()
+        .ok_or_else(|| Error::InvalidArgument(format!("{} requires a value", flag)))
+}
+
+/// Maps the outcome of a run to the process exit code: success, 1 when an
+/// evaluation failed, or 2 when reading input or writing output failed.
+pub fn exit_code(outcome: &io::Result<bool>) -> ExitCode {
+    match outcome {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(1),
+        Err(_) => ExitCode::from(2),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(args(&["--stats", "--precision", "3", "2", "+", "2"])).unwrap();
+        assert!(options.config.show_stats);
+        assert_eq!(options.config.format.precision, Some(3));
+        assert_eq!(options.expression.as_deref(), Some("2 + 2"));
+
+        assert!(matches!(
+            parse_args(args(&["--precision"])),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            parse_args(args(&["--bogus"])),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_precision_auto_flag() {
+        let options = parse_args(args(&["--precision", "4", "--precision-auto"])).unwrap();
+        assert!(options.config.format.trim_zeros);
+    }
+
+    #[test]
+    fn test_base_flag() {
+        assert_eq!(parse_args(args(&["--base", "2"])).unwrap().config.base, 2);
+        assert!(matches!(
+            parse_args(args(&["--base", "12"])),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_decimal_comma_flag() {
+        let options = parse_args(args(&["--decimal-comma", "3,14", "*", "2"])).unwrap();
+        assert!(options.config.decimal_comma);
+        assert!(!options.config.strict);
+        assert_eq!(options.expression.as_deref(), Some("3,14 * 2"));
+    }
+
+    #[test]
+    fn test_switch_flags() {
+        let config = |flag: &str| parse_args(args(&[flag])).unwrap().config;
+        assert!(config("--strict").strict);
+        assert!(config("--propagate-nan").propagate_nan);
+        assert!(config("--physics").physics);
+        assert!(config("--kahan").kahan);
+        assert!(config("--round-intermediate").round_intermediate);
+        assert!(config("--explain-precedence").explain_precedence);
+        assert!(config("--ambiguous-report").ambiguous_report);
+    }
+
+    #[test]
+    fn test_format_flag() {
+        let json = parse_args(args(&["--format", "json-lines"]))
+            .unwrap()
+            .config;
+        assert!(json.json_lines && json.quiet);
+        let currency = parse_args(args(&["--format", "currency"])).unwrap().config;
+        assert_eq!(currency.format, NumberFormat::preset("currency").unwrap());
+        assert!(!currency.json_lines);
+        assert!(matches!(
+            parse_args(args(&["--format", "xml"])),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_bare_output_flag() {
+        let bare = parse_args(args(&["--bare-output"])).unwrap().config;
+        assert!(bare.bare_output && bare.quiet);
+        assert_eq!(bare.prompt, "");
+    }
+
+    #[test]
+    fn test_timeout_flag() {
+        let options = parse_args(args(&["--timeout-ms", "250"])).unwrap();
+        assert_eq!(options.config.timeout, Some(Duration::from_millis(250)));
+        assert_eq!(parse_args(args(&[])).unwrap().config.timeout, None);
+        assert!(matches!(
+            parse_args(args(&["--timeout-ms", "soon"])),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_seed_flag() {
+        let options = parse_args(args(&["--seed", "42", "rand()"])).unwrap();
+        assert_eq!(options.config.seed, Some(42));
+        assert_eq!(parse_args(args(&[])).unwrap().config.seed, None);
+        assert!(matches!(
+            parse_args(args(&["--seed", "-1"])),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_path_flags() {
+        let options = parse_args(args(&["--rc", "defs.txt"])).unwrap();
+        assert_eq!(options.config.rc_file, Some(PathBuf::from("defs.txt")));
+        assert_eq!(options.file, None);
+
+        let options = parse_args(args(&["--file", "sheet.calc"])).unwrap();
+        assert_eq!(options.file, Some(PathBuf::from("sheet.calc")));
+        assert_eq!(options.expression, None);
+        assert_eq!(options.output, None);
+
+        let options = parse_args(args(&["--file", "in.calc", "--output", "out.txt"])).unwrap();
+        assert_eq!(options.output, Some(PathBuf::from("out.txt")));
+        assert_eq!(options.socket, None);
+        let options = parse_args(args(&["--socket", "/tmp/calc.sock"])).unwrap();
+        assert_eq!(options.socket, Some(PathBuf::from("/tmp/calc.sock")));
+        assert!(matches!(
+            parse_args(args(&["--output"])),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_startup_flag() {
+        let options = parse_args(args(&["-e", "x = 5", "-e", "y = 2", "x", "*", "y"])).unwrap();
+        assert_eq!(options.config.startup, vec!["x = 5", "y = 2"]);
+        assert_eq!(options.expression.as_deref(), Some("x * y"));
+        assert!(matches!(
+            parse_args(args(&["-e"])),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply_config() {
+        let contents = "\
+# Defaults for every session
+precision = 4
+mode = deg
+rounding = truncate  # not half-even
+base = 16
+
+exact = on
+bool-display = on
+colour = red
+base = 3
+precision = lots
+";
+        let mut config = ReplConfig::default();
+        let errors = apply_config(contents, &mut config);
+        assert_eq!(config.format.precision, Some(4));
+        assert_eq!(config.format.rounding, RoundingMode::Truncate);
+        assert_eq!(config.angle_mode, AngleMode::Degrees);
+        assert!(config.exact);
+        assert_eq!(config.base, 16);
+        let lines: Vec<usize> = errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![9, 10, 11]);
+        assert_eq!(
+            errors[0].1,
+            Error::InvalidArgument("colour = red".to_string())
+        );
+
+        // Flags override the file
+        let options = parse_args_with(args(&["--precision", "2"]), config).unwrap();
+        assert_eq!(options.config.format.precision, Some(2));
+        assert_eq!(options.config.angle_mode, AngleMode::Degrees);
+
+        let mut config = options.config;
+        config.strict = true;
+        config.decimal_comma = true;
+        let flags = [
+            "--no-exact",
+            "--no-strict",
+            "--no-decimal-comma",
+            "--no-bool-display",
+            "--mode",
+            "rad",
+        ];
+        let options = parse_args_with(args(&flags), config).unwrap();
+        assert!(!options.config.exact);
+        assert!(!options.config.strict);
+        assert!(!options.config.decimal_comma);
+        assert!(!options.config.bool_display);
+        assert_eq!(options.config.angle_mode, AngleMode::Radians);
+        assert!(matches!(
+            parse_args(args(&["--mode", "grad"])),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_version_flag() {
+        let options = parse_args(args(&["--version"])).unwrap();
+        assert!(options.version);
+        assert!(!parse_args(args(&["2", "+", "2"])).unwrap().version);
+        assert_eq!(
+            VERSION,
+            format!("calculator-cli {}", env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[test]
+    fn test_terminal_detection() {
+        let mut options = parse_args(args(&[])).unwrap();
+        apply_terminal_detection(&mut options, true);
+        assert!(!options.config.quiet);
+        assert_eq!(options.config.prompt, "> ");
+
+        let mut options = parse_args(args(&[])).unwrap();
+        apply_terminal_detection(&mut options, false);
+        assert!(options.config.quiet);
+        assert_eq!(options.config.prompt, "");
+
+        let mut options = parse_args(args(&["--interactive"])).unwrap();
+        assert!(options.interactive);
+        apply_terminal_detection(&mut options, false);
+        assert!(!options.config.quiet);
+        assert_eq!(options.config.prompt, "> ");
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(&Ok(true)), ExitCode::SUCCESS);
+        assert_eq!(exit_code(&Ok(false)), ExitCode::from(1));
+        let broken_pipe = io::Error::from(io::ErrorKind::BrokenPipe);
+        assert_eq!(exit_code(&Err(broken_pipe)), ExitCode::from(2));
+    }
+}
@@ -0,0 +1,464 @@
+use std::fmt::{Display, Formatter};
+
+use crate::{Error, Value};
+
+/// How results are rounded to a fixed number of decimal places.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoundingMode {
+    /// Ties round away from zero: 2.5 becomes 3 and -2.5 becomes -3.
+    HalfUp,
+    /// Ties round to the nearest even digit (banker's rounding): 2.5 becomes 2.
+    #[default]
+    HalfEven,
+    /// Extra digits are dropped: 2.9 becomes 2.
+    Truncate,
+}
+
+impl Display for RoundingMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoundingMode::HalfUp => write!(f, "half-up"),
+            RoundingMode::HalfEven => write!(f, "half-even"),
+            RoundingMode::Truncate => write!(f, "truncate"),
+        }
+    }
+}
+
+/// Settings for displaying results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NumberFormat {
+    /// Number of decimal places to show, if fixed.
+    pub precision: Option<usize>,
+    pub rounding: RoundingMode,
+    /// Drop trailing zeros after rounding, so `1.50` shows as `1.5`.
+    pub trim_zeros: bool,
+    /// Separate thousands with commas, so `1234567` shows as `1,234,567`.
+    pub grouping: bool,
+    /// Show whole numbers with `precision` decimals too, so `1234` shows as
+    /// `1234.00` like other amounts.
+    pub pad_whole: bool,
+}
+
+/// Named combinations of format settings, chosen with the `format` command.
+pub const FORMAT_PRESETS: &[(&str, NumberFormat)] = &[
+    (
+        "default",
+        NumberFormat {
+            precision: None,
+            rounding: RoundingMode::HalfEven,
+            trim_zeros: false,
+            grouping: false,
+            pad_whole: false,
+        },
+    ),
+    (
+        "currency",
+        NumberFormat {
+            precision: Some(2),
+            rounding: RoundingMode::HalfUp,
+            trim_zeros: false,
+            grouping: true,
+            pad_whole: true,
+        },
+    ),
+];
+
+impl NumberFormat {
+    /// The preset in `FORMAT_PRESETS` with this name, ignoring case.
+    pub fn preset(name: &str) -> Option<Self> {
+        FORMAT_PRESETS
+            .iter()
+            .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+            .map(|(_, format)| *format)
+    }
+
+    /// Formats a number for display. Whole numbers show no decimals (`4`,
+    /// not `4.0` or `4.00`) unless `pad_whole` is set; other numbers are
+    /// rounded to `precision` decimal places if given. Negative zero, as
+    /// from `0 * -1`, shows as `0`.
+    pub fn format(&self, value: f64) -> String {
+        let value = if value == 0.0 { 0.0 } else { value };
+        let text = self.format_ungrouped(value);
+        if self.grouping {
+            group_thousands(text)
+        } else {
+            text
+        }
+    }
+
+    fn format_ungrouped(&self, value: f64) -> String {
+        let padded = self.pad_whole && self.precision.is_some() && value.is_finite();
+        if value.fract() == 0.0 && !padded {
+            return format!("{}", value);
+        }
+
+        match self.precision {
+            Some(precision) if value.is_finite() => {
+                let rounded = round_decimal(value, precision, self.rounding);
+                if self.trim_zeros {
+                    trim_trailing_zeros(rounded)
+                } else {
+                    rounded
+                }
+            }
+            _ => format!("{}", value),
+        }
+    }
+
+    /// Formats a number, or each element of a vector, e.g. `[0.5, 2]`.
+    pub fn format_value(&self, value: &Value) -> String {
+        match value {
+            Value::Scalar(value) => self.format(*value),
+            Value::Duration(minutes) => format_duration(*minutes),
+            Value::Quantity(value, unit) => format!("{}{}", self.format(*value), unit),
+            Value::Vector(values) => {
+                let elements: Vec<String> =
+                    values.iter().map(|value| self.format(*value)).collect();
+                format!("[{}]", elements.join(", "))
+            }
+        }
+    }
+}
+
+/// Formats a duration in minutes as `h:mm`, or as `h:mm:ss` when it is not a
+/// whole number of minutes, to the nearest second.
+pub fn format_duration(minutes: f64) -> String {
+    let seconds = (minutes.abs() * 60.0).round() as u64;
+    let sign = if minutes < 0.0 && seconds > 0 {
+        "-"
+    } else {
+        ""
+    };
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if seconds == 0 {
+        format!("{}{}:{:02}", sign, hours, minutes)
+    } else {
+        format!("{}{}:{:02}:{:02}", sign, hours, minutes, seconds)
+    }
+}
+
+/// Shows a whole number in decimal, hexadecimal, octal and binary, one base
+/// per line, e.g. `hex: 0xff` for 255. Other numbers, and those too large for
+/// 64 bits, are only shown in decimal.
+pub fn format_bases(value: f64) -> Vec<String> {
+    let decimal = format!("decimal: {}", format_number(value, None));
+    if value.fract() != 0.0 || !value.is_finite() {
+        return vec![format!("{} (not a whole number)", decimal)];
+    }
+    if value.abs() >= 2f64.powi(64) {
+        return vec![format!("{} (too large for other bases)", decimal)];
+    }
+
+    vec![
+        decimal,
+        format!("hex: {}", in_base(value, "hex")),
+        format!("octal: {}", in_base(value, "octal")),
+        format!("binary: {}", in_base(value, "binary")),
+    ]
+}
+
+/// A whole number that fits in 64 bits, in `hex`, `octal` or `binary` with
+/// its prefix, e.g. `-0b1000` for -8.
+fn in_base(value: f64, base: &str) -> String {
+    let sign = if value < 0.0 { "-" } else { "" };
+    let magnitude = value.abs() as u64;
+    match base {
+        "hex" => format!("{}0x{:x}", sign, magnitude),
+        "octal" => format!("{}0o{:o}", sign, magnitude),
+        _ => format!("{}0b{:b}", sign, magnitude),
+    }
+}
+
+/// Formats a single result the way a trailing `as` clause asks for: `hex`,
+/// `octal` or `binary` (or `oct`, `bin`) for whole numbers, `dec` for
+/// decimal, or `sci` for scientific notation such as `1e6`, with `precision`
+/// digits after the point if given.
+pub fn format_as(value: f64, target: &str, precision: Option<usize>) -> Result<String, Error> {
+    let target = match target.to_lowercase().as_str() {
+        "oct" => "octal".to_string(),
+        "bin" => "binary".to_string(),
+        "decimal" => "dec".to_string(),
+        other => other.to_string(),
+    };
+    match target.as_str() {
+        "dec" => Ok(format_number(value, precision)),
+        "sci" => Ok(match precision {
+            Some(precision) => format!("{:.*e}", precision, value),
+            None => format!("{:e}", value),
+        }),
+        base @ ("hex" | "octal" | "binary") => {
+            if value.fract() != 0.0 || !value.is_finite() || value.abs() >= 2f64.powi(64) {
+                return Err(Error::DomainError(format!(
+                    "{} needs a whole number that fits in 64 bits, not {}",
+                    base,
+                    format_number(value, None)
+                )));
+            }
+            Ok(in_base(value, base))
+        }
+        _ => Err(Error::InvalidArgument(format!("as {}", target))),
+    }
+}
+
+/// Formats a number with the default rounding mode.
+pub fn format_number(value: f64, precision: Option<usize>) -> String {
+    NumberFormat {
+        precision,
+        ..NumberFormat::default()
+    }
+    .format(value)
+}
+
+/// Whether two results show the same when rounded to `decimals` places, so
+/// that `0.1 + 0.2` equals `0.3` at 10 decimals even though the floats differ.
+/// NaN equals nothing, and infinities only equal themselves.
+pub fn results_equal_at_precision(a: f64, b: f64, decimals: usize) -> bool {
+    if !a.is_finite() || !b.is_finite() {
+        return a == b;
+    }
+    round_decimal(a, decimals, RoundingMode::HalfEven)
+        == round_decimal(b, decimals, RoundingMode::HalfEven)
+}
+
+/// Rounds a number to `decimals` places as `format` would show it, so that
+/// calculating with the result gives what was displayed.
+pub(crate) fn round_to(value: f64, decimals: usize, mode: RoundingMode) -> f64 {
+    if !value.is_finite() || value.fract() == 0.0 {
+        return value;
+    }
+    round_decimal(value, decimals, mode)
+        .parse()
+        .unwrap_or(value)
+}
+
+/// Rounds the shortest decimal representation of `value`, so that what gets
+/// rounded is the number as the user would write it (1.005 rather than the
+/// nearest binary fraction 1.00499999...).
+fn round_decimal(value: f64, decimals: usize, mode: RoundingMode) -> String {
+    let text = format!("{}", value.abs());
+    let (whole, fraction) = text.split_once('.').unwrap_or((&text, ""));
+
+    let mut digits: Vec<u8> = whole
+        .bytes()
+        .chain(
+            fraction
+                .bytes()
+                .chain(std::iter::repeat(b'0'))
+                .take(decimals),
+        )
+        .map(|b| b - b'0')
+        .collect();
+    let dropped: Vec<u8> = fraction.bytes().skip(decimals).map(|b| b - b'0').collect();
+
+    let round_up = match (mode, dropped.split_first()) {
+        (RoundingMode::Truncate, _) | (_, None) => false,
+        (RoundingMode::HalfUp, Some((&first, _))) => first >= 5,
+        (RoundingMode::HalfEven, Some((&first, rest))) => {
+            let is_tie = first == 5 && rest.iter().all(|&d| d == 0);
+            let last_is_odd = digits.last().is_some_and(|d| d % 2 == 1);
+            first > 5 || (first == 5 && !is_tie) || (is_tie && last_is_odd)
+        }
+    };
+
+    if round_up {
+        let mut i = digits.len();
+        loop {
+            if i == 0 {
+                digits.insert(0, 1);
+                break;
+            }
+            i -= 1;
+            if digits[i] == 9 {
+                digits[i] = 0;
+            } else {
+                digits[i] += 1;
+                break;
+            }
+        }
+    }
+
+    let whole_len = digits.len() - decimals;
+    let mut result = String::new();
+    if value < 0.0 && digits.iter().any(|&d| d != 0) {
+        result.push('-');
+    }
+    result.extend(digits[..whole_len].iter().map(|d| char::from(b'0' + d)));
+    if decimals > 0 {
+        result.push('.');
+        result.extend(digits[whole_len..].iter().map(|d| char::from(b'0' + d)));
+    }
+    result
+}
+
+/// Inserts a comma between each group of three digits before the decimal
+/// point. Text that is not a number, such as `inf`, is left as it is.
+fn group_thousands(text: String) -> String {
+    let (sign, unsigned) = match text.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", text.as_str()),
+    };
+    let (whole, rest) = unsigned.split_at(unsigned.find('.').unwrap_or(unsigned.len()));
+    if whole.is_empty() || !whole.bytes().all(|b| b.is_ascii_digit()) {
+        return text;
+    }
+
+    let mut grouped = String::from(sign);
+    for (i, digit) in whole.chars().enumerate() {
+        if i > 0 && (whole.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped.push_str(rest);
+    grouped
+}
+
+fn trim_trailing_zeros(mut text: String) -> String {
+    if text.contains('.') {
+        let trimmed = text.trim_end_matches('0').trim_end_matches('.').len();
+        text.truncate(trimmed);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rounded(value: f64, decimals: usize, rounding: RoundingMode) -> String {
+        NumberFormat {
+            precision: Some(decimals),
+            rounding,
+            ..NumberFormat::default()
+        }
+        .format(value)
+    }
+
+    #[test]
+    fn test_rounding_modes() {
+        assert_eq!(rounded(2.5, 0, RoundingMode::HalfUp), "3");
+        assert_eq!(rounded(2.5, 0, RoundingMode::HalfEven), "2");
+        assert_eq!(rounded(2.5, 0, RoundingMode::Truncate), "2");
+
+        assert_eq!(rounded(3.5, 0, RoundingMode::HalfEven), "4");
+        assert_eq!(rounded(-2.5, 0, RoundingMode::HalfUp), "-3");
+        assert_eq!(rounded(2.51, 0, RoundingMode::HalfEven), "3");
+        assert_eq!(rounded(1.005, 2, RoundingMode::HalfUp), "1.01");
+        assert_eq!(rounded(9.996, 2, RoundingMode::HalfEven), "10.00");
+        assert_eq!(rounded(-0.001, 2, RoundingMode::Truncate), "0.00");
+        assert_eq!(rounded(0.25, 4, RoundingMode::Truncate), "0.2500");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(135.0), "2:15");
+        assert_eq!(format_duration(5.0), "0:05");
+        assert_eq!(format_duration(22.5), "0:22:30");
+        assert_eq!(format_duration(-15.0), "-0:15");
+        assert_eq!(format_duration(1500.0), "25:00");
+        assert_eq!(format_duration(-0.0001), "0:00");
+    }
+
+    #[test]
+    fn test_negative_zero() {
+        assert_eq!(format_number(-0.0, None), "0");
+        assert_eq!(format_number(-0.0, Some(2)), "0");
+        assert_eq!(format_number(0.0, None), "0");
+        assert_eq!(
+            NumberFormat::default().format_value(&Value::Vector(vec![-0.0, -1.0])),
+            "[0, -1]"
+        );
+    }
+
+    #[test]
+    fn test_grouping() {
+        let grouped = NumberFormat {
+            grouping: true,
+            ..NumberFormat::default()
+        };
+        assert_eq!(grouped.format(1234567.0), "1,234,567");
+        assert_eq!(grouped.format(-1234.5), "-1,234.5");
+        assert_eq!(grouped.format(999.0), "999");
+        assert_eq!(grouped.format(0.25), "0.25");
+        assert_eq!(grouped.format(f64::INFINITY), "inf");
+
+        let currency = NumberFormat::preset("currency").unwrap();
+        assert_eq!(currency.format(1234.5), "1,234.50");
+        assert_eq!(currency.format(1234.565), "1,234.57");
+        assert_eq!(currency.format(-0.5), "-0.50");
+        assert_eq!(currency.format(1234.0), "1,234.00");
+        assert_eq!(currency.format(-3.0), "-3.00");
+        assert_eq!(currency.format(0.0), "0.00");
+        assert_eq!(NumberFormat::default().format(1234.0), "1234");
+        assert_eq!(
+            NumberFormat::preset("Default"),
+            Some(NumberFormat::default())
+        );
+        assert_eq!(NumberFormat::preset("bogus"), None);
+    }
+
+    #[test]
+    fn test_format_bases() {
+        assert_eq!(
+            format_bases(255.0),
+            vec![
+                "decimal: 255",
+                "hex: 0xff",
+                "octal: 0o377",
+                "binary: 0b11111111"
+            ]
+        );
+        assert_eq!(format_bases(-8.0)[3], "binary: -0b1000");
+        assert_eq!(format_bases(2.5), vec!["decimal: 2.5 (not a whole number)"]);
+        assert_eq!(format_bases(1e20).len(), 1);
+    }
+
+    #[test]
+    fn test_format_as() {
+        assert_eq!(format_as(255.0, "hex", None), Ok("0xff".to_string()));
+        assert_eq!(format_as(-8.0, "Binary", None), Ok("-0b1000".to_string()));
+        assert_eq!(format_as(8.0, "octal", None), Ok("0o10".to_string()));
+        assert_eq!(format_as(5.0, "bin", None), Ok("0b101".to_string()));
+        assert_eq!(format_as(0.125, "dec", Some(2)), Ok("0.12".to_string()));
+        assert_eq!(format_as(1000000.0, "sci", None), Ok("1e6".to_string()));
+        assert_eq!(format_as(1234.5, "sci", Some(2)), Ok("1.23e3".to_string()));
+        assert_eq!(format_as(0.00025, "sci", None), Ok("2.5e-4".to_string()));
+        assert!(matches!(
+            format_as(2.5, "hex", None),
+            Err(Error::DomainError(_))
+        ));
+        assert_eq!(
+            format_as(2.0, "roman", None),
+            Err(Error::InvalidArgument("as roman".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_results_equal_at_precision() {
+        assert!(results_equal_at_precision(0.1 + 0.2, 0.3, 10));
+        assert!(!results_equal_at_precision(0.1 + 0.2, 0.3, 17));
+        assert!(results_equal_at_precision(1.234, 1.2349, 2));
+        assert!(!results_equal_at_precision(1.234, 1.2351, 2));
+        assert!(results_equal_at_precision(2.4, 1.6, 0));
+        assert!(!results_equal_at_precision(2.6, 2.4, 0));
+        assert!(results_equal_at_precision(-0.001, 0.001, 2));
+        assert!(results_equal_at_precision(f64::INFINITY, f64::INFINITY, 3));
+        assert!(!results_equal_at_precision(f64::NAN, f64::NAN, 3));
+    }
+
+    #[test]
+    fn test_trim_zeros() {
+        let format = NumberFormat {
+            precision: Some(4),
+            trim_zeros: true,
+            ..NumberFormat::default()
+        };
+        assert_eq!(format.format(1.5), "1.5");
+        assert_eq!(format.format(2.25), "2.25");
+        assert_eq!(format.format(8.0), "8");
+        assert_eq!(format.format(1.0 / 3.0), "0.3333");
+        assert_eq!(format.format(1.99999), "2");
+        assert_eq!(format.format(-0.00001), "0");
+        assert_eq!(format.format(10.10001), "10.1");
+    }
+}
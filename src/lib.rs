@@ -1,11 +1,163 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
 
-#[derive(Debug, PartialOrd, PartialEq, Copy, Clone)]
+mod ast;
+mod cli;
+mod format;
+mod rational;
+mod registry;
+mod repl;
+mod units;
+
+pub use ast::{eval_ast, parse_to_ast, Expr};
+pub use cli::{
+    apply_config, apply_terminal_detection, exit_code, load_config, parse_args, parse_args_with,
+    Options, VERSION,
+};
+pub use format::{
+    format_as, format_bases, format_duration, format_number, results_equal_at_precision,
+    NumberFormat, RoundingMode, FORMAT_PRESETS,
+};
+pub use rational::Rational;
+pub use registry::FunctionRegistry;
+pub use repl::{
+    append_history, default_config_path, default_history_path, default_rc_path, evaluate_once,
+    load_history, open_output, run_file, run_repl, run_socket, Repl, ReplConfig,
+};
+pub use units::{Dimension, Unit, UNITS};
+
+#[derive(Debug, PartialOrd, PartialEq, Clone)]
 pub enum Token {
     Number(f64),
+    /// A duration written `h:mm`, in minutes.
+    Duration(f64),
+    /// A number written with a unit, e.g. `5km`.
+    Quantity(f64, Unit),
+    /// An operator with two operands, e.g. `+`.
     Operator(char),
+    /// An operator with one operand, written before it (`-x`, `not x`) or
+    /// after it (`5!`, `50%`).
+    UnaryOperator(char),
+    Variable(String),
+    /// A function name in infix input, always followed by `LeftParen`.
+    Function(String),
+    /// A function call in RPN, with its argument count.
+    Call(String, usize),
+    /// `|>` with the name of the function it passes the value before it to,
+    /// e.g. `|> sqrt`.
+    Pipe(String),
+    /// A vector literal in RPN, with its element count.
+    Vector(usize),
+    Comma,
     LeftParen,
     RightParen,
+    LeftBracket,
+    RightBracket,
+}
+
+impl Token {
+    /// The character of an operator token, e.g. `'+'`. Postfix percent is
+    /// the full-width `'％'`, to tell it apart from the `%` remainder.
+    pub fn operator_char(&self) -> Option<char> {
+        match self {
+            Token::Operator(op) | Token::UnaryOperator(op) => Some(*op),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(num) => write!(f, "{}", format_number(*num, None)),
+            Token::Duration(minutes) => write!(f, "{}", format_duration(*minutes)),
+            Token::Quantity(num, unit) => write!(f, "{}{}", format_number(*num, None), unit),
+            Token::Operator(op) | Token::UnaryOperator(op) => {
+                write!(f, "{}", operator_symbol(*op))
+            }
+            Token::Variable(name) | Token::Function(name) | Token::Call(name, _) => {
+                write!(f, "{}", name)
+            }
+            Token::Pipe(name) => write!(f, "|> {}", name),
+            Token::Vector(_) => write!(f, "[]"),
+            Token::Comma => write!(f, ","),
+            Token::LeftParen => write!(f, "("),
+            Token::RightParen => write!(f, ")"),
+            Token::LeftBracket => write!(f, "["),
+            Token::RightBracket => write!(f, "]"),
+        }
+    }
+}
+
+/// Writes infix tokens back out as an expression in a normalized form, with
+/// spaces around binary operators and none inside brackets, e.g. `2 + 3 * 4`
+/// or `-sqrt(x) + 50%`. Parsing the result gives the same tokens.
+pub fn to_expression_string(tokens: &[Token]) -> String {
+    let mut text = String::new();
+    let mut previous: Option<&Token> = None;
+
+    for token in tokens {
+        let joins_previous = match token {
+            Token::RightParen | Token::RightBracket | Token::Comma => true,
+            Token::UnaryOperator(op) => is_postfix(*op),
+            Token::LeftParen => matches!(previous, Some(Token::Function(_))),
+            _ => false,
+        };
+        let previous_joins_next = match previous {
+            None | Some(Token::Function(_) | Token::LeftParen | Token::LeftBracket) => true,
+            Some(Token::UnaryOperator(op)) => !is_postfix(*op) && *op != '¬',
+            _ => false,
+        };
+        if !joins_previous && !previous_joins_next {
+            text.push(' ');
+        }
+        text.push_str(&token.to_string());
+        previous = Some(token);
+    }
+
+    text
+}
+
+/// The result of an expression: a number, a vector of numbers written as
+/// `[1, 2, 3]`, a duration written as `h:mm`, or a quantity with a unit
+/// written as `5km`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(f64),
+    Vector(Vec<f64>),
+    /// A length of time in minutes.
+    Duration(f64),
+    Quantity(f64, Unit),
+}
+
+impl Value {
+    /// The number, failing if this is a vector, a duration or a quantity.
+    pub fn scalar(&self) -> Result<f64, Error> {
+        match self {
+            Value::Scalar(value) => Ok(*value),
+            Value::Vector(_) => Err(Error::InvalidExpression(format!(
+                "{} is a vector, not a number",
+                self
+            ))),
+            Value::Duration(_) => Err(Error::InvalidExpression(format!(
+                "{} is a duration, not a number",
+                self
+            ))),
+            Value::Quantity(..) => Err(Error::InvalidExpression(format!(
+                "{} has a unit, so it is not a plain number",
+                self
+            ))),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", NumberFormat::default().format_value(self))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -20,6 +172,48 @@ pub enum Error {
     MismatchedBracket,
     EmptyBrackets,
     NotFinite,
+    UndefinedVariable(String),
+    InvalidAssignment(String),
+    UnknownFunction(String),
+    WrongArgumentCount(String, usize, usize),
+    InvalidArgument(String),
+    DomainError(String),
+    EvaluationLimitExceeded,
+    /// Evaluation took longer than the calculator's time limit.
+    Timeout(Duration),
+    /// A user function called itself, directly or not, more than
+    /// `MAX_CALL_DEPTH` times.
+    RecursionLimit(String),
+    Overflow,
+    /// Vectors of different lengths were combined elementwise.
+    ShapeMismatch(usize, usize),
+    /// A whole number was needed, e.g. by `calculate_i64`.
+    NotAnInteger(String),
+    /// Quantities with units of different dimensions, such as `km` and `kg`,
+    /// were added, subtracted or compared.
+    IncompatibleUnits(String, String),
+    /// An error raised while calling the named function.
+    InFunction(String, Box<Error>),
+}
+
+impl Error {
+    fn in_function(self, name: &str) -> Error {
+        match self {
+            // These already name the function
+            Error::UnknownFunction(_)
+            | Error::WrongArgumentCount(..)
+            | Error::RecursionLimit(_) => self,
+            _ => Error::InFunction(name.to_string(), Box::new(self)),
+        }
+    }
+
+    /// The error that caused this one, without any context it was wrapped in.
+    pub fn root_cause(&self) -> &Error {
+        match self {
+            Error::InFunction(_, cause) => cause.root_cause(),
+            _ => self,
+        }
+    }
 }
 
 impl Display for Error {
@@ -38,192 +232,2861 @@ impl Display for Error {
             Error::MismatchedBracket => write!(f, "Mismatched brackets"),
             Error::EmptyBrackets => write!(f, "Empty brackets"),
             Error::NotFinite => write!(f, "Result is not finite"),
+            Error::UndefinedVariable(name) => write!(f, "Undefined variable: {}", name),
+            Error::InvalidAssignment(msg) => write!(f, "Invalid assignment: {}", msg),
+            Error::UnknownFunction(name) => write!(f, "Unknown function: {}", name),
+            Error::WrongArgumentCount(name, expected, found) => write!(
+                f,
+                "{} expects {} argument(s) but was given {}",
+                name, expected, found
+            ),
+            Error::InvalidArgument(arg) => write!(f, "Invalid argument: {}", arg),
+            Error::DomainError(msg) => write!(f, "Domain error: {}", msg),
+            Error::EvaluationLimitExceeded => {
+                write!(f, "The expression takes too many steps to evaluate")
+            }
+            Error::RecursionLimit(name) => write!(
+                f,
+                "{} is nested more than {} calls deep",
+                name, MAX_CALL_DEPTH
+            ),
+            Error::Timeout(limit) => write!(
+                f,
+                "The expression took longer than {} ms to evaluate",
+                limit.as_millis()
+            ),
+            Error::Overflow => write!(f, "Result is too large to represent"),
+            Error::ShapeMismatch(left, right) => write!(
+                f,
+                "Vectors must have the same length, not {} and {}",
+                left, right
+            ),
+            Error::NotAnInteger(value) => write!(f, "{} is not a whole number", value),
+            Error::IncompatibleUnits(left, right) => {
+                write!(f, "Cannot combine {} and {}", left, right)
+            }
+            Error::InFunction(name, cause) => write!(f, "{}: {}", name, cause),
+        }
+    }
+}
+
+/// A suggestion for fixing an error, to show below its message.
+pub fn describe_error(error: &Error) -> &'static str {
+    match error {
+        Error::InvalidNumber(_) => "Write numbers like 42, 3.14, 1e-3 or 0xff.",
+        Error::InvalidOperator(_) => "Type 'help operators' to see the supported operators.",
+        Error::InvalidExpression(_) => "Check for typos and unsupported characters.",
+        Error::ConsecutiveOperators => "Put a number between each pair of operators.",
+        Error::DivisionByZero => "Check that the divisor is not zero.",
+        Error::TooManyOperators => "Check that every number is joined to the next by an operator.",
+        Error::EmptyExpression => "Enter an expression such as 2 + 2, or 'help' for help.",
+        Error::MismatchedBracket => "Check that every opening bracket has a matching closing one.",
+        Error::EmptyBrackets => "Put an expression inside the brackets, or remove them.",
+        Error::NotFinite => "The result is too large or undefined; try smaller numbers.",
+        Error::UndefinedVariable(_) => {
+            "Assign the variable first (e.g., x = 2), or use default(x, 0)."
+        }
+        Error::InvalidAssignment(_) => {
+            "Variable names start with a letter or _ and cannot be keywords."
+        }
+        Error::UnknownFunction(_) => "Type 'help functions' to see the available functions.",
+        Error::WrongArgumentCount(..) => "Check how many arguments the function takes.",
+        Error::InvalidArgument(_) => "Check the command-line options and their values.",
+        Error::DomainError(_) => "The function is not defined for this input.",
+        Error::EvaluationLimitExceeded => "Simplify the expression, e.g. use smaller factorials.",
+        Error::RecursionLimit(_) => "Check that the function does not call itself forever.",
+        Error::Timeout(_) => "Simplify the expression, or allow more time with --timeout-ms.",
+        Error::Overflow => {
+            "Use smaller numbers, or floating point ('exact off') for very large exact results."
+        }
+        Error::ShapeMismatch(..) => "Combine vectors of the same length, or a vector and a number.",
+        Error::NotAnInteger(_) => "Use \\ for whole number division (e.g., 7 \\ 2 = 3).",
+        Error::IncompatibleUnits(..) => {
+            "Only quantities of the same kind, such as two lengths, can be combined."
+        }
+        Error::InFunction(_, cause) => describe_error(cause),
+    }
+}
+
+pub fn parse_expression(expression: Vec<&str>) -> Result<Vec<Token>, Error> {
+    let tokens = tokenize(&expression.join(" "))?;
+
+    // Without a calculator only built-in constants can be resolved
+    let unknown = tokens
+        .iter()
+        .find(|t| matches!(t, Token::Variable(name) if constant(name).is_none()));
+    if let Some(Token::Variable(name)) = unknown {
+        return Err(Error::InvalidExpression(name.clone()));
+    }
+
+    Ok(tokens)
+}
+
+/// Removes a trailing `#` comment from a line of input.
+pub fn strip_comment(input: &str) -> &str {
+    match input.find('#') {
+        Some(index) => &input[..index],
+        None => input,
+    }
+}
+
+/// Maps typographic symbols that end up in copy-pasted text to the ASCII
+/// operators they stand for, e.g. the minus sign in `5 − 3`.
+fn normalize_char(c: char) -> char {
+    match c {
+        // Minus sign, hyphen, en dash, small and full-width hyphen-minus
+        '\u{2212}' | '\u{2010}' | '\u{2013}' | '\u{FE63}' | '\u{FF0D}' => '-',
+        '\u{00D7}' => '*',
+        '\u{00F7}' => '/',
+        _ => c,
+    }
+}
+
+/// Cleans up a line of raw input the way the calculator reads it: drops a
+/// `#` comment, maps typographic operators such as `×` and `−` to ASCII and
+/// collapses runs of whitespace to single spaces, so `" 5 − 3\t# note"`
+/// becomes `"5 - 3"`. Frontends can use it before their own parsing.
+pub fn preprocess_input(input: &str) -> String {
+    let normalized: String = strip_comment(input).chars().map(normalize_char).collect();
+    normalized.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Settings that change how input is read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Syntax {
+    /// `,` is the decimal point (`3,14`) and `;` separates function arguments
+    /// (`mod(7; 3)`). A comma is always taken as a decimal point in this
+    /// mode, before any other meaning it could have.
+    decimal_comma: bool,
+    /// What separates function arguments and vector elements, `,` or `;`.
+    arg_separator: char,
+    /// Reject shorthand that lenient mode accepts: implicit multiplication
+    /// (`2x`, `2(3 + 1)`) and `%` as a percentage (`50%`).
+    strict: bool,
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Self {
+            decimal_comma: false,
+            arg_separator: ',',
+            strict: false,
+        }
+    }
+}
+
+impl Syntax {
+    /// The argument separator in use, which is always `;` with a decimal
+    /// comma.
+    fn separator(&self) -> char {
+        if self.decimal_comma {
+            ';'
+        } else {
+            self.arg_separator
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, Error> {
+    tokenize_with(input, Syntax::default(), &|_| false)
+}
+
+/// Tokenizes with the given syntax. A name straight after a number is a unit
+/// (`2m`) unless `is_variable` says it is a variable, which keeps `2m` the
+/// implicit multiplication `2 * m` once `m` is defined.
+fn tokenize_with(
+    input: &str,
+    syntax: Syntax,
+    is_variable: &dyn Fn(&str) -> bool,
+) -> Result<Vec<Token>, Error> {
+    let chars: Vec<char> = strip_comment(input)
+        .chars()
+        .map(normalize_char)
+        .map(|c| match c {
+            ',' if syntax.decimal_comma => '.',
+            _ => c,
+        })
+        .collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+        } else if matches!(c, '-' | '+') && is_unary_sign(&chars, i, tokens.last()) {
+            tokens.push(Token::UnaryOperator(c));
+            i += 1;
+        } else if let Some((minutes, len)) = lex_duration(&chars[i..])? {
+            tokens.push(Token::Duration(minutes));
+            i += len;
+        } else if starts_number(&chars, i) {
+            let (num, len) = lex_number(&chars[i..])?;
+            i += len;
+            match units::lex_unit(&chars[i..]).filter(|(unit, _)| !is_variable(unit.name)) {
+                Some((unit, len)) => {
+                    tokens.push(Token::Quantity(num, unit));
+                    i += len;
+                }
+                None => tokens.push(Token::Number(num)),
+            }
+        } else if c == '(' {
+            tokens.push(Token::LeftParen);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::RightParen);
+            i += 1;
+        } else if c == '[' {
+            tokens.push(Token::LeftBracket);
+            i += 1;
+        } else if c == ']' {
+            tokens.push(Token::RightBracket);
+            i += 1;
+        } else if c == syntax.separator() {
+            tokens.push(Token::Comma);
+            i += 1;
+        } else if let Some((op, len)) = lex_comparison(&chars[i..]) {
+            tokens.push(Token::Operator(op));
+            i += len;
+        } else if let Some(power) = superscript_power(c) {
+            // `5²` is read as `5 ^ 2`
+            tokens.push(Token::Operator('^'));
+            tokens.push(Token::Number(power));
+            i += 1;
+        } else if c == '!' {
+            tokens.push(Token::UnaryOperator(c));
+            i += 1;
+        } else if c == '|' && chars.get(i + 1) == Some(&'>') {
+            let (name, len) = lex_pipe(&chars[i..])?;
+            tokens.push(Token::Pipe(name));
+            i += len;
+        } else if "+-*/\\^%≤≥≠".contains(c) {
+            tokens.push(Token::Operator(c));
+            i += 1;
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = chars[i..]
+                .iter()
+                .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                .count();
+            let name: String = chars[i..i + len].iter().collect();
+            i += len;
+
+            // A name directly followed by a bracket is a function call
+            let next = chars[i..].iter().find(|c| !c.is_whitespace());
+            if let Some(operator) = keyword_operator(&name) {
+                tokens.push(operator);
+            } else if next == Some(&'(') {
+                tokens.push(Token::Function(name));
+            } else {
+                tokens.push(Token::Variable(name));
+            }
+        } else {
+            return Err(Error::InvalidExpression(c.to_string()));
+        }
+    }
+
+    // Check if we have at least one number and one operator
+    if tokens.is_empty() {
+        return Err(Error::EmptyExpression);
+    }
+
+    resolve_shorthand(tokens, syntax.strict)
+}
+
+/// Lexes `|>` and the function name after it, returning the name and the
+/// length of both.
+fn lex_pipe(chars: &[char]) -> Result<(String, usize), Error> {
+    let spaces = chars[2..].iter().take_while(|c| c.is_whitespace()).count();
+    let start = 2 + spaces;
+    let len = chars[start..]
+        .iter()
+        .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+        .count();
+    let name: String = chars[start..start + len].iter().collect();
+    if !is_identifier(&name) {
+        return Err(Error::InvalidExpression(
+            "|> must be followed by a function name".to_string(),
+        ));
+    }
+    Ok((name, start + len))
+}
+
+fn superscript_power(c: char) -> Option<f64> {
+    match c {
+        '²' => Some(2.0),
+        '³' => Some(3.0),
+        _ => None,
+    }
+}
+
+/// Postfix percent is stored as the full-width percent sign, to tell it
+/// apart from the `%` remainder operator.
+const PERCENT: char = '\u{FF05}';
+
+/// Turns `%` with no right operand into a percentage (`50%` is 0.5) and
+/// inserts the `*` of implicit multiplication (`2x`, `2(3 + 1)`,
+/// `(1 + 2)(3 + 4)`), or rejects both in strict mode. Two numbers in a row
+/// are never multiplied, as `2 3` is more likely a typo.
+fn resolve_shorthand(tokens: Vec<Token>, strict: bool) -> Result<Vec<Token>, Error> {
+    let mut resolved: Vec<Token> = Vec::with_capacity(tokens.len());
+    let mut tokens = tokens.into_iter().peekable();
+
+    while let Some(mut token) = tokens.next() {
+        let next = tokens.peek();
+
+        let has_right_operand = match next {
+            None
+            | Some(Token::RightParen | Token::RightBracket | Token::Comma | Token::Pipe(_)) => {
+                false
+            }
+            Some(Token::Operator(_)) => false,
+            Some(Token::UnaryOperator(op)) => !is_postfix(*op),
+            _ => true,
+        };
+        if token == Token::Operator('%') && !has_right_operand {
+            if strict {
+                return Err(Error::InvalidExpression(
+                    "% is the remainder; write / 100 for a percentage".to_string(),
+                ));
+            }
+            token = Token::UnaryOperator(PERCENT);
+        }
+
+        let ends_operand = match &token {
+            Token::Number(_)
+            | Token::Duration(_)
+            | Token::Quantity(..)
+            | Token::Variable(_)
+            | Token::Pipe(_)
+            | Token::RightParen
+            | Token::RightBracket => true,
+            Token::UnaryOperator(op) => is_postfix(*op),
+            _ => false,
+        };
+        let implicit_multiplication = ends_operand
+            && match next {
+                Some(Token::Variable(_) | Token::Function(_) | Token::LeftParen) => true,
+                Some(Token::Number(_) | Token::Quantity(..)) => {
+                    matches!(token, Token::RightParen | Token::RightBracket)
+                }
+                _ => false,
+            };
+
+        resolved.push(token);
+        if implicit_multiplication {
+            if strict {
+                return Err(Error::InvalidExpression(
+                    "implicit multiplication; write * between the factors".to_string(),
+                ));
+            }
+            resolved.push(Token::Operator('*'));
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// One way of reading an ambiguous expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reading {
+    /// What the ambiguous part is taken to mean, e.g. `% as a percentage`.
+    pub description: &'static str,
+    pub tokens: Vec<Token>,
+}
+
+fn percent_reading(percent: bool, tokens: Vec<Token>) -> Reading {
+    let description = if percent {
+        "% as a percentage"
+    } else {
+        "% as the remainder"
+    };
+    Reading {
+        description,
+        tokens,
+    }
+}
+
+/// Swaps the meaning of the first `%` that could be read either way, giving
+/// its position and what replaces it and the token after it: `50 % -3` could
+/// also be `50% - 3`, `50 % (2)` could be `50% * (2)`, and `50% - 3` could be
+/// `50 % -3`.
+fn other_percent_reading(tokens: &[Token]) -> Option<(usize, Vec<Token>)> {
+    tokens.windows(2).enumerate().find_map(|(i, pair)| {
+        let replacement = match pair {
+            [Token::Operator('%'), Token::UnaryOperator(sign @ ('-' | '+'))] => {
+                vec![Token::UnaryOperator(PERCENT), Token::Operator(*sign)]
+            }
+            [Token::Operator('%'), next @ (Token::LeftParen | Token::Variable(_) | Token::Function(_))] => {
+                vec![Token::UnaryOperator(PERCENT), Token::Operator('*'), next.clone()]
+            }
+            [Token::UnaryOperator(PERCENT), Token::Operator(sign @ ('-' | '+'))] => {
+                vec![Token::Operator('%'), Token::UnaryOperator(*sign)]
+            }
+            _ => return None,
+        };
+        Some((i, replacement))
+    })
+}
+
+/// Comparison operators that are typed with two characters are stored as the
+/// matching single character, e.g. `<=` as `≤` and `==` as `=`.
+fn lex_comparison(chars: &[char]) -> Option<(char, usize)> {
+    match (chars.first()?, chars.get(1)) {
+        ('<', Some('=')) => Some(('≤', 2)),
+        ('>', Some('=')) => Some(('≥', 2)),
+        ('=', Some('=')) => Some(('=', 2)),
+        ('!', Some('=')) => Some(('≠', 2)),
+        ('<', _) => Some(('<', 1)),
+        ('>', _) => Some(('>', 1)),
+        _ => None,
+    }
+}
+
+/// The logical operators are written as words.
+fn keyword_operator(name: &str) -> Option<Token> {
+    match name {
+        "and" => Some(Token::Operator('&')),
+        "or" => Some(Token::Operator('|')),
+        "not" => Some(Token::UnaryOperator('¬')),
+        _ => None,
+    }
+}
+
+/// Names that cannot be assigned to.
+fn is_reserved(name: &str) -> bool {
+    keyword_operator(name).is_some() || name == MEMORY_RECALL
+}
+
+/// How an operator is written in input.
+fn operator_symbol(op: char) -> String {
+    match op {
+        '≤' => "<=".to_string(),
+        '≥' => ">=".to_string(),
+        '=' => "==".to_string(),
+        '≠' => "!=".to_string(),
+        '&' => "and".to_string(),
+        '|' => "or".to_string(),
+        '¬' => "not".to_string(),
+        PERCENT => "%".to_string(),
+        _ => op.to_string(),
+    }
+}
+
+/// A `-` or `+` is a sign rather than a binary operator when nothing it could
+/// subtract from or add to comes before it, e.g. in `-x`, `2 * -3` and
+/// `mod(-7, 3)`. A `%` before it may still turn out to be a percentage, so
+/// there only a sign written against its number counts (`50 % -3`, but
+/// `50% - 3`).
+fn is_unary_sign(chars: &[char], i: usize, previous: Option<&Token>) -> bool {
+    match previous {
+        None | Some(Token::LeftParen | Token::LeftBracket | Token::Comma) => true,
+        Some(Token::UnaryOperator(op)) => !is_postfix(*op),
+        Some(Token::Operator('%')) => starts_number(chars, i + 1),
+        Some(Token::Operator(_)) => true,
+        _ => false,
+    }
+}
+
+/// A number starts with a digit or a point.
+fn starts_number(chars: &[char], i: usize) -> bool {
+    chars
+        .get(i)
+        .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+}
+
+/// Lexes the number at the start of `chars`, returning it with its length.
+fn lex_number(chars: &[char]) -> Result<(f64, usize), Error> {
+    if chars.starts_with(&['0', 'x']) || chars.starts_with(&['0', 'X']) {
+        return lex_hex_number(chars);
+    }
+
+    let mut len = chars
+        .iter()
+        .take_while(|c| c.is_ascii_digit() || **c == '.')
+        .count();
+
+    // Only treat `e` as an exponent when digits follow it
+    if matches!(chars.get(len), Some('e' | 'E')) {
+        let sign = usize::from(matches!(chars.get(len + 1), Some('-' | '+')));
+        if chars
+            .get(len + 1 + sign)
+            .is_some_and(|c| c.is_ascii_digit())
+        {
+            len += 1 + sign;
+            len += chars[len..]
+                .iter()
+                .take_while(|c| c.is_ascii_digit())
+                .count();
+        }
+    }
+
+    let text: String = chars[..len].iter().collect();
+    if text.matches('.').count() > 1 {
+        return Err(Error::InvalidNumber(format!(
+            "{} has more than one decimal point",
+            text
+        )));
+    }
+    text.parse::<f64>()
+        .map(|num| (num, len))
+        .map_err(|_| Error::InvalidNumber(text))
+}
+
+/// Lexes a duration such as `1:30` or `1:30:15` at the start of `chars`,
+/// returning it in minutes with its length, or `None` if `chars` does not
+/// start with one.
+fn lex_duration(chars: &[char]) -> Result<Option<(f64, usize)>, Error> {
+    let digits_at = |i: usize| {
+        chars[i.min(chars.len())..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count()
+    };
+
+    let hours = digits_at(0);
+    if hours == 0 || chars.get(hours) != Some(&':') || digits_at(hours + 1) != 2 {
+        return Ok(None);
+    }
+    let mut len = hours + 3;
+    if chars.get(len) == Some(&':') && digits_at(len + 1) == 2 {
+        len += 3;
+    }
+
+    let text: String = chars[..len].iter().collect();
+    let invalid = || Error::InvalidNumber(text.clone());
+    let parts = text
+        .split(':')
+        .map(|part| part.parse::<f64>().map_err(|_| invalid()))
+        .collect::<Result<Vec<f64>, Error>>()?;
+    if parts[1..].iter().any(|part| *part >= 60.0) {
+        return Err(invalid());
+    }
+
+    let seconds = parts.get(2).copied().unwrap_or(0.0);
+    Ok(Some((parts[0] * 60.0 + parts[1] + seconds / 60.0, len)))
+}
+
+/// Lexes a C-style hexadecimal literal such as `0xff` or `0x1.8p3`, where
+/// the optional `p` exponent is a power of two.
+fn lex_hex_number(chars: &[char]) -> Result<(f64, usize), Error> {
+    let mut len = 2;
+    while let Some(&c) = chars.get(len) {
+        let exponent_sign = matches!(c, '-' | '+') && matches!(chars[len - 1], 'p' | 'P');
+        if c.is_ascii_alphanumeric() || c == '.' || exponent_sign {
+            len += 1;
+        } else {
+            break;
+        }
+    }
+
+    let text: String = chars[..len].iter().collect();
+    let invalid = || Error::InvalidNumber(text.clone());
+
+    let body = &text[2..];
+    let (mantissa, exponent) = match body.split_once(['p', 'P']) {
+        Some((mantissa, exponent)) => (mantissa, exponent.parse::<i32>().map_err(|_| invalid())?),
+        None => (body, 0),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return Err(invalid());
+    }
+
+    let mut value = 0.0;
+    for c in whole.chars() {
+        value = value * 16.0 + f64::from(c.to_digit(16).ok_or_else(invalid)?);
+    }
+    let mut scale = 1.0 / 16.0;
+    for c in fraction.chars() {
+        value += f64::from(c.to_digit(16).ok_or_else(invalid)?) * scale;
+        scale /= 16.0;
+    }
+
+    value *= 2f64.powi(exponent);
+    Ok((value, len))
+}
+
+/// Reads a single number written in any base: decimal or hexadecimal as the
+/// tokenizer reads them (`2.5e3`, `0xff`, `0x1.8p1`), or whole octal (`0o17`)
+/// or binary (`0b1010`), with an optional sign.
+pub fn parse_radix(text: &str) -> Result<f64, Error> {
+    let invalid = || Error::InvalidNumber(text.to_string());
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+
+    let radix = match digits.get(..2).map(str::to_ascii_lowercase).as_deref() {
+        Some("0o") => 8,
+        Some("0b") => 2,
+        _ => 10,
+    };
+    let value = if radix == 10 {
+        let chars: Vec<char> = digits.chars().collect();
+        match lex_number(&chars) {
+            Ok((value, len)) if len == chars.len() => value,
+            _ => return Err(invalid()),
+        }
+    } else {
+        // `from_str_radix` would also take a sign after the prefix, as in `0b+1`
+        if digits[2..].starts_with(['+', '-']) {
+            return Err(invalid());
+        }
+        u64::from_str_radix(&digits[2..], radix).map_err(|_| invalid())? as f64
+    };
+    let value = finite(value, false)?;
+    Ok(if negative { -value } else { value })
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_') && !is_reserved(name)
+        }
+        _ => false,
+    }
+}
+
+/// The parts of a `name = expression` or `name += expression` line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Assignment<'a> {
+    pub name: &'a str,
+    /// The operator of a compound assignment such as `+=`.
+    pub operator: Option<char>,
+    pub expression: &'a str,
+}
+
+/// Splits `name = expression` (or `name += expression`, `-=`, `*=`, `/=`)
+/// into its target and expression parts.
+/// Returns `Ok(None)` when the input is not an assignment.
+pub fn parse_assignment(input: &str) -> Result<Option<Assignment<'_>>, Error> {
+    let input = strip_comment(input);
+    // Skip the `=` in comparisons such as `==`, `<=` and `!=`
+    let is_assignment = |&(i, _): &(usize, &str)| {
+        !input[..i].ends_with(['<', '>', '!', '=']) && !input[i + 1..].starts_with('=')
+    };
+    let Some((i, _)) = input.match_indices('=').find(is_assignment) else {
+        return Ok(None);
+    };
+    let (target, expression) = (&input[..i], &input[i + 1..]);
+
+    let target = target.trim();
+    let (name, operator) = match target.strip_suffix(['+', '-', '*', '/']) {
+        Some(name) => (name.trim_end(), target.chars().last()),
+        None => (target, None),
+    };
+
+    if is_identifier(name) {
+        Ok(Some(Assignment {
+            name,
+            operator,
+            expression,
+        }))
+    } else {
+        Err(Error::InvalidAssignment(target.to_string()))
+    }
+}
+
+fn precedence(op: char) -> i32 {
+    // Define operator precedence
+    match op {
+        '+' | '-' => 1,
+        '*' | '/' | '\\' | '%' => 2,
+        '^' => 3,
+        // Comparisons and logical operators bind more loosely than arithmetic,
+        // so `1 + 1 == 2 and not 0` is `((1 + 1) == 2) and (not 0)`
+        '<' | '>' | '≤' | '≥' | '=' | '≠' => -1,
+        '&' => -3,
+        '|' => -4,
+        _ => 0,
+    }
+}
+
+fn unary_precedence(op: char) -> i32 {
+    match op {
+        '!' | PERCENT => 4,
+        // A sign binds more loosely than `^`, so `-2 ^ 2` is -4
+        '-' | '+' => 3,
+        '¬' => -2,
+        _ => 0,
+    }
+}
+
+/// Postfix operators take a single operand written before them, e.g. `5 !`.
+/// Other unary operators are prefix operators, written before their operand.
+fn is_postfix(op: char) -> bool {
+    op == '!' || op == PERCENT
+}
+
+/// Comparison and logical operators, whose results are 1 for true and 0 for
+/// false.
+fn is_boolean(op: char) -> bool {
+    "<>≤≥=≠&|¬".contains(op)
+}
+
+/// Whether the last operation in an expression is a comparison or logical
+/// operator, so that its result is a truth value, as in `3 > 2`.
+pub fn is_boolean_expression(tokens: &[Token]) -> bool {
+    let last = to_rpn(tokens).ok().and_then(|rpn| rpn.last().cloned());
+    matches!(last, Some(Token::Operator(op) | Token::UnaryOperator(op)) if is_boolean(op))
+}
+
+fn truth(condition: bool) -> f64 {
+    if condition {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Which side an operator groups towards when it is repeated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Assoc {
+    /// `8 - 3 - 2` is `(8 - 3) - 2`.
+    Left,
+    /// `2 ^ 3 ^ 2` is `2 ^ (3 ^ 2)`.
+    Right,
+}
+
+fn associativity(op: char) -> Assoc {
+    match op {
+        '^' => Assoc::Right,
+        _ => Assoc::Left,
+    }
+}
+
+fn apply_operator(op: char, b: f64, a: f64) -> Result<f64, Error> {
+    match op {
+        '+' => Ok(a + b),
+        '-' => Ok(a - b),
+        '*' => Ok(a * b),
+        '/' => {
+            if b == 0.0 {
+                Err(Error::DivisionByZero)
+            } else {
+                Ok(a / b)
+            }
+        }
+        // Integer division rounds down like Python's //, so -7 \ 2 is -4
+        '\\' => {
+            if b == 0.0 {
+                Err(Error::DivisionByZero)
+            } else {
+                Ok((a / b).floor())
+            }
+        }
+        // Remainder: the sign of the result follows the dividend, so -7 % 3 is -1
+        '%' => {
+            if b == 0.0 {
+                Err(Error::DivisionByZero)
+            } else {
+                Ok(a % b)
+            }
         }
+        '^' => power(a, b),
+        '<' => Ok(truth(a < b)),
+        '>' => Ok(truth(a > b)),
+        '≤' => Ok(truth(a <= b)),
+        '≥' => Ok(truth(a >= b)),
+        '=' => Ok(truth(a == b)),
+        '≠' => Ok(truth(a != b)),
+        // Any non-zero number counts as true
+        '&' => Ok(truth(a != 0.0 && b != 0.0)),
+        '|' => Ok(truth(a != 0.0 || b != 0.0)),
+        _ => Err(Error::InvalidOperator(operator_symbol(op))),
+    }
+}
+
+/// Largest odd denominator that `power` looks for in an exponent.
+const MAX_ROOT_DENOMINATOR: u32 = 99;
+
+/// `a ^ b`. A negative base only has a real power when the exponent is a
+/// fraction with an odd denominator, e.g. `(-8) ^ (1/3)` is the real cube
+/// root -2; other powers of a negative base are rejected rather than NaN.
+fn power(a: f64, b: f64) -> Result<f64, Error> {
+    if a >= 0.0 || b.fract() == 0.0 || !b.is_finite() {
+        return Ok(a.powf(b));
+    }
+
+    for denominator in (3..=MAX_ROOT_DENOMINATOR).step_by(2) {
+        let numerator = b * f64::from(denominator);
+        if (numerator - numerator.round()).abs() < 1e-9 {
+            // cbrt is exact for perfect cubes, where powf may be off by an ulp
+            let magnitude = if denominator == 3 {
+                (-a).cbrt().powf(numerator.round())
+            } else {
+                (-a).powf(b)
+            };
+            return Ok(if numerator.round() % 2.0 == 0.0 {
+                magnitude
+            } else {
+                -magnitude
+            });
+        }
+    }
+
+    Err(Error::DomainError(format!(
+        "{} ^ {} has no real value",
+        format_number(a, None),
+        format_number(b, None)
+    )))
+}
+
+fn apply_unary_operator(op: char, a: f64) -> Result<f64, Error> {
+    match op {
+        '-' => Ok(-a),
+        '+' => Ok(a),
+        '¬' => Ok(truth(a == 0.0)),
+        '!' => factorial(a),
+        PERCENT => Ok(a / 100.0),
+        _ => Err(Error::InvalidOperator(operator_symbol(op))),
+    }
+}
+
+/// Product of 1..=n, defined for non-negative whole numbers. From 171 on it
+/// is larger than `f64::MAX`, which is an `Overflow` rather than infinity.
+fn factorial(n: f64) -> Result<f64, Error> {
+    if n < 0.0 || n.fract() != 0.0 {
+        return Err(Error::DomainError(format!(
+            "factorial is only defined for non-negative integers, not {}",
+            n
+        )));
+    }
+
+    let mut result: f64 = 1.0;
+    let mut i = 2.0;
+    while i <= n {
+        result *= i;
+        if result > f64::MAX {
+            return Err(Error::Overflow);
+        }
+        i += 1.0;
+    }
+    Ok(result)
+}
+
+/// Coefficients of the Lanczos approximation with g = 7 and 9 terms, as
+/// published by Paul Godfrey.
+const LANCZOS_G: f64 = 7.0;
+const LANCZOS_COEFFICIENTS: [f64; 9] = [
+    0.999_999_999_999_809_9,
+    676.520_368_121_885_1,
+    -1_259.139_216_722_402_8,
+    771.323_428_777_653_1,
+    -176.615_029_162_140_6,
+    12.507_343_278_686_905,
+    -0.138_571_095_265_720_12,
+    9.984_369_578_019_572e-6,
+    1.505_632_735_149_311_6e-7,
+];
+
+/// The gamma function, which extends the factorial to non-integers:
+/// gamma(n) = (n - 1)! for whole numbers n. Whole numbers use `factorial`
+/// and are exact; other values use the Lanczos approximation, which is good
+/// to about 15 significant digits. Zero and negative integers are poles.
+fn gamma(x: f64) -> Result<f64, Error> {
+    if x <= 0.0 && x.fract() == 0.0 {
+        return Err(Error::DomainError(format!(
+            "gamma is not defined for zero or negative integers like {}",
+            x
+        )));
+    }
+    if x.fract() == 0.0 {
+        return factorial(x - 1.0);
+    }
+
+    if x < 0.5 {
+        // Reflection formula: gamma(x) * gamma(1 - x) = pi / sin(pi * x)
+        let pi = std::f64::consts::PI;
+        return Ok(pi / ((pi * x).sin() * gamma(1.0 - x)?));
+    }
+
+    let x = x - 1.0;
+    let t = x + LANCZOS_G + 0.5;
+    let series = LANCZOS_COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(LANCZOS_COEFFICIENTS[0], |sum, (i, c)| {
+            sum + c / (x + i as f64 + 1.0)
+        });
+    Ok((2.0 * std::f64::consts::PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * series)
+}
+
+/// Mathematical modulo: unlike the `%` remainder, the sign of the result
+/// follows the divisor, so mod(-7, 3) is 2.
+fn modulo(a: f64, b: f64) -> Result<f64, Error> {
+    if b == 0.0 {
+        Err(Error::DivisionByZero)
+    } else {
+        Ok(((a % b) + b) % b)
+    }
+}
+
+fn constant(name: &str) -> Option<f64> {
+    match name {
+        "e" => Some(std::f64::consts::E),
+        _ => None,
+    }
+}
+
+/// Physical constants in SI units, with what they are. All but `G` and `gn`
+/// are exact by the 2019 definition of the SI units; `G` is the CODATA 2018
+/// value and `gn` is standard gravity as defined by the CGPM.
+pub const PHYSICAL_CONSTANTS: &[(&str, f64, &str)] = &[
+    ("c", 299_792_458.0, "speed of light in vacuum, m/s"),
+    ("h", 6.626_070_15e-34, "Planck constant, J s"),
+    ("k", 1.380_649e-23, "Boltzmann constant, J/K"),
+    ("NA", 6.022_140_76e23, "Avogadro constant, 1/mol"),
+    ("qe", 1.602_176_634e-19, "elementary charge, C"),
+    ("G", 6.674_30e-11, "gravitational constant, m^3/(kg s^2)"),
+    ("gn", 9.806_65, "standard acceleration of gravity, m/s^2"),
+];
+
+fn physical_constant(name: &str) -> Option<f64> {
+    PHYSICAL_CONSTANTS
+        .iter()
+        .find(|(constant, _, _)| *constant == name)
+        .map(|(_, value, _)| *value)
+}
+
+/// `default(x, fallback)` is `x`, or `fallback` when `x` uses an undefined
+/// variable. Any other error in `x` is still reported.
+fn default<T>(args: Vec<Result<T, Error>>) -> Result<Result<T, Error>, Error> {
+    match <[_; 2]>::try_from(args) {
+        Ok([Err(Error::UndefinedVariable(_)), fallback]) => Ok(fallback),
+        Ok([value, _]) => Ok(value),
+        Err(args) => Err(Error::WrongArgumentCount(
+            "default".to_string(),
+            2,
+            args.len(),
+        )),
+    }
+}
+
+/// A fraction written as `frac(numerator, denominator)`. Unlike `/` it is a
+/// single number, and its parts must be whole numbers, so in exact mode it is
+/// always exact.
+fn fraction(numerator: f64, denominator: f64) -> Result<f64, Error> {
+    if numerator.fract() != 0.0 || denominator.fract() != 0.0 {
+        return Err(Error::DomainError(format!(
+            "a fraction needs whole number parts, not {} and {}",
+            numerator, denominator
+        )));
+    }
+    apply_operator('/', denominator, numerator)
+}
+
+fn sqrt(x: f64) -> Result<f64, Error> {
+    if x < 0.0 {
+        Err(Error::DomainError(format!(
+            "sqrt is only defined for non-negative numbers, not {}",
+            x
+        )))
+    } else {
+        Ok(x.sqrt())
+    }
+}
+
+fn ln(x: f64) -> Result<f64, Error> {
+    if x <= 0.0 {
+        Err(Error::DomainError(format!(
+            "ln is only defined for positive numbers, not {}",
+            x
+        )))
+    } else {
+        Ok(x.ln())
+    }
+}
+
+struct Function {
+    name: &'static str,
+    arity: usize,
+    apply: fn(&[f64]) -> Result<f64, Error>,
+}
+
+const FUNCTIONS: &[Function] = &[
+    Function {
+        name: "mod",
+        arity: 2,
+        apply: |args| modulo(args[0], args[1]),
+    },
+    Function {
+        name: "inv",
+        arity: 1,
+        apply: |args| apply_operator('/', args[0], 1.0),
+    },
+    Function {
+        name: "sq",
+        arity: 1,
+        apply: |args| Ok(args[0] * args[0]),
+    },
+    Function {
+        name: "neg",
+        arity: 1,
+        apply: |args| apply_unary_operator('-', args[0]),
+    },
+    Function {
+        name: "gamma",
+        arity: 1,
+        apply: |args| gamma(args[0]),
+    },
+    Function {
+        name: "factorial",
+        arity: 1,
+        apply: |args| gamma(args[0] + 1.0),
+    },
+    Function {
+        name: "frac",
+        arity: 2,
+        apply: |args| fraction(args[0], args[1]),
+    },
+    Function {
+        name: "sqrt",
+        arity: 1,
+        apply: |args| sqrt(args[0]),
+    },
+    Function {
+        name: "exp",
+        arity: 1,
+        apply: |args| Ok(args[0].exp()),
+    },
+    Function {
+        name: "ln",
+        arity: 1,
+        apply: |args| ln(args[0]),
+    },
+    Function {
+        name: "hypot",
+        arity: 2,
+        apply: |args| Ok(args[0].hypot(args[1])),
+    },
+    Function {
+        name: "bool",
+        arity: 1,
+        apply: |args| Ok(truth(args[0] != 0.0)),
+    },
+    Function {
+        name: "isnan",
+        arity: 1,
+        apply: |args| Ok(truth(args[0].is_nan())),
+    },
+    Function {
+        name: "isinf",
+        arity: 1,
+        apply: |args| Ok(truth(args[0].is_infinite())),
+    },
+];
+
+fn to_rpn(tokens: &[Token]) -> Result<Vec<Token>, Error> {
+    let mut output = Vec::new();
+    let mut operators: Vec<Token> = Vec::new();
+    // Separators seen so far for each open bracket, or `None` for brackets
+    // that only group and do not hold function arguments or vector elements
+    let mut brackets: Vec<Option<usize>> = Vec::new();
+    let mut previous_token: Option<&Token> = None;
+
+    for token in tokens {
+        match token {
+            Token::Number(_) | Token::Duration(_) | Token::Quantity(..) | Token::Variable(_) => {
+                output.push(token.clone())
+            }
+            Token::Function(_) => operators.push(token.clone()),
+            Token::Call(..) | Token::Vector(_) => {
+                return Err(Error::InvalidExpression(
+                    "RPN token found in infix expression".to_string(),
+                ))
+            }
+            Token::LeftParen => {
+                let is_call = matches!(previous_token, Some(Token::Function(_)));
+                brackets.push(is_call.then_some(0));
+                operators.push(Token::LeftParen);
+            }
+            Token::Comma => {
+                if matches!(
+                    previous_token,
+                    Some(Token::LeftParen | Token::LeftBracket | Token::Comma)
+                ) {
+                    return Err(Error::InvalidExpression(
+                        "Missing function argument".to_string(),
+                    ));
+                }
+
+                pop_to_open_bracket(&mut operators, &mut output)?;
+                match brackets.last_mut() {
+                    Some(Some(separators)) => *separators += 1,
+                    _ => return Err(Error::InvalidExpression(",".to_string())),
+                }
+            }
+            Token::RightParen => {
+                let bracket = brackets.pop().ok_or(Error::MismatchedBracket)?;
+                let is_empty = matches!(previous_token, Some(Token::LeftParen));
+
+                if is_empty && bracket.is_none() {
+                    return Err(Error::EmptyBrackets);
+                }
+                if matches!(previous_token, Some(Token::Comma)) {
+                    return Err(Error::InvalidExpression(
+                        "Missing function argument".to_string(),
+                    ));
+                }
+
+                pop_to_open_bracket(&mut operators, &mut output)?;
+                if operators.pop() != Some(Token::LeftParen) {
+                    return Err(Error::MismatchedBracket);
+                }
+
+                if let Some(separators) = bracket {
+                    let args = if is_empty { 0 } else { separators + 1 };
+                    match operators.pop() {
+                        Some(Token::Function(name)) => output.push(Token::Call(name, args)),
+                        _ => return Err(Error::MismatchedBracket),
+                    }
+                }
+            }
+            Token::LeftBracket => {
+                brackets.push(Some(0));
+                operators.push(Token::LeftBracket);
+            }
+            Token::RightBracket => {
+                let separators = brackets.pop().flatten().ok_or(Error::MismatchedBracket)?;
+                if matches!(previous_token, Some(Token::Comma)) {
+                    return Err(Error::InvalidExpression(
+                        "Missing vector element".to_string(),
+                    ));
+                }
+
+                pop_to_open_bracket(&mut operators, &mut output)?;
+                if operators.pop() != Some(Token::LeftBracket) {
+                    return Err(Error::MismatchedBracket);
+                }
+
+                let is_empty = matches!(previous_token, Some(Token::LeftBracket));
+                output.push(Token::Vector(if is_empty { 0 } else { separators + 1 }));
+            }
+            // A pipe binds most loosely, so its function is applied to
+            // everything before it up to the innermost open bracket
+            Token::Pipe(name) => {
+                while let Some(Token::Operator(_) | Token::UnaryOperator(_)) = operators.last() {
+                    output.extend(operators.pop());
+                }
+                output.push(Token::Call(name.clone(), 1));
+            }
+            // Postfix operators already have their operand, so apply them next
+            Token::UnaryOperator(op) if is_postfix(*op) => output.push(token.clone()),
+            // Prefix operators wait for their operand like an open bracket
+            Token::UnaryOperator(_) => operators.push(token.clone()),
+            Token::Operator(op) => {
+                while let Some(top) = operators.last() {
+                    let top_precedence = match top {
+                        Token::Operator(top_op) => precedence(*top_op),
+                        Token::UnaryOperator(top_op) => unary_precedence(*top_op),
+                        Token::LeftParen | Token::LeftBracket => break,
+                        _ => {
+                            return Err(Error::InvalidExpression(
+                                "Invalid token on stack".to_string(),
+                            ))
+                        }
+                    };
+                    let should_pop = match associativity(*op) {
+                        Assoc::Left => top_precedence >= precedence(*op),
+                        Assoc::Right => top_precedence > precedence(*op),
+                    };
+
+                    if !should_pop {
+                        break;
+                    }
+                    output.extend(operators.pop());
+                }
+
+                operators.push(Token::Operator(*op));
+            }
+        }
+
+        previous_token = Some(token);
+    }
+
+    // Pop all remaining operators from the stack
+    while let Some(top) = operators.pop() {
+        match top {
+            Token::Operator(_) | Token::UnaryOperator(_) => output.push(top),
+            Token::LeftParen | Token::LeftBracket | Token::Function(_) => {
+                return Err(Error::MismatchedBracket)
+            }
+            _ => {
+                return Err(Error::InvalidExpression(
+                    "Invalid token on stack".to_string(),
+                ))
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+/// Moves operators to the output until the innermost open bracket, which is
+/// left on the stack.
+fn pop_to_open_bracket(operators: &mut Vec<Token>, output: &mut Vec<Token>) -> Result<(), Error> {
+    while let Some(top) = operators.last() {
+        match top {
+            Token::Operator(_) | Token::UnaryOperator(_) => {
+                output.push(top.clone());
+                operators.pop();
+            }
+            Token::LeftParen | Token::LeftBracket => return Ok(()),
+            _ => return Err(Error::MismatchedBracket),
+        }
+    }
+
+    Err(Error::MismatchedBracket)
+}
+
+/// Describes the order in which an expression's operations are applied,
+/// one line per step, e.g. `2 + 3 * 4` gives `Step 1: 3 * 4 (precedence 2)`
+/// then `Step 2: 2 + step 1 (precedence 1)`. Nothing is evaluated.
+pub fn explain_expression(expression: &[Token]) -> Result<Vec<String>, Error> {
+    let mut operands: Vec<String> = Vec::new();
+    let mut steps = Vec::new();
+
+    for token in to_rpn(expression)? {
+        let description = match &token {
+            Token::Number(_) | Token::Duration(_) | Token::Quantity(..) | Token::Variable(_) => {
+                operands.push(token.to_string());
+                continue;
+            }
+            Token::UnaryOperator(op) => {
+                let operand = operands.pop().ok_or_else(|| {
+                    Error::InvalidExpression("Not enough operands for operator".to_string())
+                })?;
+                if is_postfix(*op) {
+                    format!(
+                        "{} {} (precedence {})",
+                        operand,
+                        token,
+                        unary_precedence(*op)
+                    )
+                } else {
+                    format!(
+                        "{} {} (precedence {})",
+                        token,
+                        operand,
+                        unary_precedence(*op)
+                    )
+                }
+            }
+            Token::Operator(op) => {
+                if operands.len() < 2 {
+                    return Err(Error::InvalidExpression(
+                        "Not enough operands for operator".to_string(),
+                    ));
+                }
+                let right = operands.pop().unwrap();
+                let left = operands.pop().unwrap();
+                format!(
+                    "{} {} {} (precedence {})",
+                    left,
+                    token,
+                    right,
+                    precedence(*op)
+                )
+            }
+            Token::Call(name, count) => {
+                if operands.len() < *count {
+                    return Err(Error::InvalidExpression(
+                        "Not enough arguments for function".to_string(),
+                    ));
+                }
+                let args = operands.split_off(operands.len() - count);
+                format!("{}({})", name, args.join(", "))
+            }
+            Token::Vector(count) => {
+                if operands.len() < *count {
+                    return Err(Error::InvalidExpression(
+                        "Not enough vector elements".to_string(),
+                    ));
+                }
+                let elements = operands.split_off(operands.len() - count);
+                operands.push(format!("[{}]", elements.join(", ")));
+                continue;
+            }
+            _ => return Err(Error::InvalidExpression(token.to_string())),
+        };
+
+        steps.push(format!("Step {}: {}", steps.len() + 1, description));
+        operands.push(format!("step {}", steps.len()));
+    }
+
+    match operands.len() {
+        0 => return Err(Error::EmptyExpression),
+        1 => {}
+        _ => return Err(Error::TooManyOperators),
+    }
+
+    Ok(steps)
+}
+
+/// What a binary operator does, in words.
+fn operator_name(op: char) -> &'static str {
+    match op {
+        '+' => "addition",
+        '-' => "subtraction",
+        '*' => "multiplication",
+        '/' => "division",
+        '\\' => "whole number division",
+        '%' => "the remainder",
+        '^' => "exponentiation",
+        '&' => "and",
+        '|' => "or",
+        _ if is_boolean(op) => "comparison",
+        _ => "the operator",
+    }
+}
+
+/// Notes where operator precedence made an expression differ from reading
+/// it left to right, e.g. `2 + 3 * 4` gives `multiplication was applied
+/// before addition`. What is inside brackets counts separately, so
+/// `(2 + 3) * 4` gives no notes.
+pub fn precedence_notes(tokens: &[Token]) -> Vec<String> {
+    // Binary operators seen so far at each bracket depth
+    let mut levels: Vec<Vec<char>> = vec![Vec::new()];
+    let mut notes = Vec::new();
+
+    for token in tokens {
+        match token {
+            Token::LeftParen | Token::LeftBracket => levels.push(Vec::new()),
+            Token::RightParen | Token::RightBracket => {
+                levels.pop();
+                if levels.is_empty() {
+                    levels.push(Vec::new());
+                }
+            }
+            Token::Comma => levels.last_mut().into_iter().for_each(Vec::clear),
+            Token::Operator(op) => {
+                let Some(earlier) = levels.last_mut() else {
+                    continue;
+                };
+                for &before in earlier.iter() {
+                    let note = if precedence(*op) > precedence(before) {
+                        format!(
+                            "{} was applied before {}",
+                            operator_name(*op),
+                            operator_name(before)
+                        )
+                    } else if before == *op && associativity(*op) == Assoc::Right {
+                        format!("{} was applied from right to left", operator_name(*op))
+                    } else {
+                        continue;
+                    };
+                    if !notes.contains(&note) {
+                        notes.push(note);
+                    }
+                }
+                earlier.push(*op);
+            }
+            _ => {}
+        }
+    }
+
+    notes
+}
+
+pub fn evaluate_expression(expression: &[Token]) -> Result<f64, Error> {
+    Calculator::new().evaluate(expression)
+}
+
+/// Evaluates `input` with a new calculator and converts the result to an
+/// `i64`, failing with `NotAnInteger` if it has a fractional part or with
+/// `Overflow` if it is out of range.
+pub fn calculate_i64(input: &str) -> Result<i64, Error> {
+    to_i64_checked(Calculator::new().eval(input)?)
+}
+
+/// Estimates the derivative of `f` at `x` with a central difference,
+/// `(f(x + h) - f(x - h)) / 2h`. The step `h` scales with `x` and is the cube
+/// root of the machine epsilon, which balances truncation error against
+/// rounding error; results are good to about 10 significant digits for
+/// smooth functions.
+pub fn numeric_derivative(f: impl Fn(f64) -> f64, x: f64) -> f64 {
+    let h = f64::EPSILON.cbrt() * x.abs().max(1.0);
+    // Use the step as actually represented, so that x + h - x is exactly h
+    let (above, below) = (x + h, x - h);
+    (f(above) - f(below)) / (above - below)
+}
+
+/// Converts a number to an `i64` for integer operations, failing with
+/// `Overflow` if it is out of range, infinite included, or with
+/// `NotAnInteger` if it has a fractional part.
+pub(crate) fn to_i64_checked(value: f64) -> Result<i64, Error> {
+    // i64::MIN is -2^63 exactly, but i64::MAX rounds up to 2^63 as an f64
+    if value < i64::MIN as f64 || value >= i64::MAX as f64 {
+        return Err(Error::Overflow);
+    }
+    if value.fract() != 0.0 {
+        return Err(Error::NotAnInteger(format_number(value, None)));
+    }
+    Ok(value as i64)
+}
+
+/// A 64-bit linear congruential generator (Knuth's MMIX constants), which is
+/// plenty for `rand()` and keeps the crate free of dependencies.
+#[derive(Debug, Clone)]
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Returns a uniform value in [0, 1) from the top 53 bits of the state.
+    fn next_f64(&mut self) -> f64 {
+        self.state = self
+            .state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Default for Rng {
+    /// Seeds from the per-process random keys std uses for `HashMap`.
+    fn default() -> Self {
+        Self::new(RandomState::new().build_hasher().finish())
+    }
+}
+
+/// Unit that angles are given and returned in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AngleMode {
+    #[default]
+    Radians,
+    Degrees,
+}
+
+impl AngleMode {
+    fn convert_radians(self, angle: f64) -> f64 {
+        match self {
+            AngleMode::Radians => angle,
+            AngleMode::Degrees => angle.to_degrees(),
+        }
+    }
+}
+
+impl Display for AngleMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AngleMode::Radians => write!(f, "radians"),
+            AngleMode::Degrees => write!(f, "degrees"),
+        }
+    }
+}
+
+/// Called with the evaluation stack after each operation.
+type StackObserver<'a> = dyn FnMut(&[f64]) + 'a;
+
+/// One operation applied while evaluating an expression, e.g. for showing a
+/// worked solution.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepInfo {
+    /// The operator or function applied, as it is written, e.g. `*` or `sqrt`.
+    pub operation: String,
+    pub operands: Vec<f64>,
+    pub result: f64,
+}
+
+/// Name that recalls the memory register in expressions.
+const MEMORY_RECALL: &str = "MR";
+
+/// Default number of operation steps an evaluation may take.
+pub const DEFAULT_STEP_LIMIT: u64 = 1_000_000;
+
+/// How deeply user functions may call each other, so that a function which
+/// calls itself fails instead of overflowing the stack.
+pub const MAX_CALL_DEPTH: usize = 64;
+
+/// A function defined with `def`, such as `double(x) = x * 2`.
+#[derive(Debug, Clone)]
+struct UserFunction {
+    parameter: String,
+    /// The body in RPN, ready to evaluate.
+    body: Vec<Token>,
+}
+
+/// Fails with `Timeout` once more than `limit` has passed since `start`.
+fn check_timeout(start: Instant, limit: Option<Duration>) -> Result<(), Error> {
+    match limit {
+        Some(limit) if start.elapsed() > limit => Err(Error::Timeout(limit)),
+        _ => Ok(()),
+    }
+}
+
+fn spend(budget: &mut u64, steps: u64) -> Result<(), Error> {
+    *budget = budget
+        .checked_sub(steps)
+        .ok_or(Error::EvaluationLimitExceeded)?;
+    Ok(())
+}
+
+/// Rejects infinite and NaN results, unless `propagate` lets them through to
+/// the rest of the expression.
+fn finite(value: f64, propagate: bool) -> Result<f64, Error> {
+    if propagate || value.is_finite() {
+        Ok(value)
+    } else {
+        Err(Error::NotFinite)
+    }
+}
+
+/// Arithmetic with durations: they add to and subtract from each other, scale
+/// by numbers (`1:30 * 2` is `3:00`) and divide into a number of times
+/// (`3:00 / 1:30` is 2). Comparisons compare their lengths.
+fn apply_duration_operator(op: char, left: &Value, right: &Value) -> Result<Value, Error> {
+    let apply = |b: f64, a: f64| finite(apply_operator(op, b, a)?, false);
+    match (left, right, op) {
+        (Value::Duration(a), Value::Duration(b), '+' | '-') => Ok(Value::Duration(apply(*b, *a)?)),
+        (Value::Duration(a), Value::Duration(b), _) if op == '/' || is_boolean(op) => {
+            Ok(Value::Scalar(apply(*b, *a)?))
+        }
+        (Value::Duration(a), Value::Scalar(b), '*' | '/') => Ok(Value::Duration(apply(*b, *a)?)),
+        (Value::Scalar(a), Value::Duration(b), '*') => Ok(Value::Duration(apply(*b, *a)?)),
+        _ => Err(Error::InvalidExpression(format!(
+            "cannot apply {} to {} and {}",
+            operator_symbol(op),
+            left,
+            right
+        ))),
+    }
+}
+
+/// Applies `apply` to scalar arguments directly, or once per element when any
+/// argument is a vector, with scalars repeated to match (so `[1, 2] + 1` is
+/// `[2, 3]`). All vector arguments must have the same length. Durations are
+/// rejected; see `apply_duration_operator` for what they support.
+fn broadcast(
+    args: &[Value],
+    mut apply: impl FnMut(&[f64]) -> Result<f64, Error>,
+) -> Result<Value, Error> {
+    let mut length = None;
+    for arg in args {
+        match arg {
+            Value::Vector(values) => match length {
+                Some(length) if length != values.len() => {
+                    return Err(Error::ShapeMismatch(length, values.len()))
+                }
+                _ => length = Some(values.len()),
+            },
+            Value::Duration(_) => {
+                return Err(Error::InvalidExpression(
+                    "durations can only be added, subtracted, compared, multiplied \
+                     and divided"
+                        .to_string(),
+                ))
+            }
+            Value::Quantity(..) => {
+                return Err(Error::InvalidExpression(
+                    "quantities with units can only be added, subtracted, compared, \
+                     multiplied and divided"
+                        .to_string(),
+                ))
+            }
+            Value::Scalar(_) => {}
+        }
+    }
+
+    let element = |i: usize| -> Vec<f64> {
+        args.iter()
+            .map(|arg| match arg {
+                Value::Scalar(value) | Value::Duration(value) | Value::Quantity(value, _) => *value,
+                Value::Vector(values) => values[i],
+            })
+            .collect()
+    };
+    match length {
+        None => apply(&element(0)).map(Value::Scalar),
+        Some(length) => (0..length)
+            .map(|i| apply(&element(i)))
+            .collect::<Result<Vec<f64>, Error>>()
+            .map(Value::Vector),
+    }
+}
+
+/// Applies a binary operator to two values, which may be durations or
+/// vectors. Durations are always finite, whatever `propagate` says.
+fn apply_value_operator(
+    op: char,
+    left: Value,
+    right: Value,
+    budget: &mut u64,
+    propagate: bool,
+) -> Result<Value, Error> {
+    match (&left, &right) {
+        (Value::Quantity(..), _) | (_, Value::Quantity(..)) => {
+            spend(budget, 1)?;
+            units::apply_quantity_operator(op, &left, &right)
+        }
+        (Value::Duration(_), _) | (_, Value::Duration(_)) => {
+            spend(budget, 1)?;
+            apply_duration_operator(op, &left, &right)
+        }
+        _ => broadcast(&[left, right], |args| {
+            spend(budget, 1)?;
+            finite(apply_operator(op, args[1], args[0])?, propagate)
+        }),
+    }
+}
+
+/// Splits expression tokens in RPN into the terms of the sum they compute,
+/// each with whether it is subtracted, so `1 2 3 * + 4 -` gives `1`, `2 3 *`
+/// and `4` subtracted. Returns `None` for anything but a sum of at least two
+/// plain numbers: vectors, durations and quantities are left out, and so are
+/// relative percentages such as `200 + 10%`.
+fn sum_terms(tokens: &[Token]) -> Option<Vec<(bool, &[Token])>> {
+    let plain = tokens.iter().enumerate().all(|(i, token)| {
+        !matches!(
+            token,
+            Token::Vector(_) | Token::Duration(_) | Token::Quantity(..)
+        ) && !is_relative_percent(tokens, i)
+    });
+    if !plain {
+        return None;
+    }
+
+    // Split off operands depth first, leftmost first, so that the terms
+    // come out in the order they are written.
+    let mut terms = Vec::new();
+    let mut pending = vec![(false, tokens)];
+    while let Some((subtracted, tokens)) = pending.pop() {
+        match tokens.split_last() {
+            Some((Token::Operator(op @ ('+' | '-')), operands)) => {
+                let split = operand_start(operands)?;
+                pending.push((subtracted != (*op == '-'), &operands[split..]));
+                pending.push((subtracted, &operands[..split]));
+            }
+            _ => terms.push((subtracted, tokens)),
+        }
+    }
+    (terms.len() > 1).then_some(terms)
+}
+
+/// Where the last complete operand in RPN tokens starts, e.g. at `2` in
+/// `1 2 3 *`.
+fn operand_start(tokens: &[Token]) -> Option<usize> {
+    let mut needed = 1;
+    for (i, token) in tokens.iter().enumerate().rev() {
+        let inputs = match token {
+            Token::Operator(_) => 2,
+            Token::UnaryOperator(_) => 1,
+            Token::Call(_, count) | Token::Vector(count) => *count,
+            _ => 0,
+        };
+        needed = needed - 1 + inputs;
+        if needed == 0 {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// Adds numbers with Neumaier's improvement of Kahan summation: the rounding
+/// error of each addition is kept and added back at the end, so adding 0.1
+/// ten times gives exactly 1. Plain addition is off by one unit in the last
+/// place there, and the error grows with the number of terms.
+pub fn compensated_sum(values: &[f64]) -> f64 {
+    let mut sum = 0.0;
+    let mut compensation = 0.0;
+    for &value in values {
+        let total = sum + value;
+        compensation += if sum.abs() >= value.abs() {
+            (sum - total) + value
+        } else {
+            (value - total) + sum
+        };
+        sum = total;
+    }
+    sum + compensation
+}
+
+/// Whether the RPN token at `i` adds or subtracts a percentage of its left
+/// operand, as in `200 + 10%`, which is 200 plus 10% of 200.
+fn is_relative_percent(tokens: &[Token], i: usize) -> bool {
+    matches!(tokens[i], Token::Operator('+' | '-'))
+        && i > 0
+        && tokens[i - 1] == Token::UnaryOperator(PERCENT)
+}
+
+/// The outcome of one expression given to `Calculator::eval_batch`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchResult {
+    pub result: Result<f64, Error>,
+    /// How long parsing and evaluating the expression took, if timed.
+    pub elapsed: Option<Duration>,
+}
+
+/// Evaluation state that persists between expressions, such as variables.
+#[derive(Debug, Clone)]
+pub struct Calculator {
+    variables: HashMap<String, f64>,
+    rng: Rng,
+    step_limit: u64,
+    /// How long a single evaluation may take, if limited.
+    timeout: Option<Duration>,
+    angle_mode: AngleMode,
+    syntax: Syntax,
+    functions: FunctionRegistry,
+    user_functions: HashMap<String, UserFunction>,
+    /// How many user function calls are in progress.
+    call_depth: usize,
+    /// The memory register, like a handheld calculator's.
+    memory: f64,
+    /// Running total of accumulated results.
+    total: f64,
+    last_result: f64,
+    /// Let NaN and infinite intermediate results carry on through the
+    /// expression instead of stopping it with `NotFinite`.
+    propagate_nan: bool,
+    /// Decimal places each operator's result is rounded to, if any.
+    intermediate_rounding: Option<(usize, RoundingMode)>,
+    /// Add up the terms of a sum with compensated summation.
+    kahan: bool,
+    /// Whether `PHYSICAL_CONSTANTS` can be used in expressions.
+    physics: bool,
+}
+
+impl Default for Calculator {
+    fn default() -> Self {
+        Self {
+            variables: HashMap::new(),
+            rng: Rng::default(),
+            step_limit: DEFAULT_STEP_LIMIT,
+            timeout: None,
+            angle_mode: AngleMode::default(),
+            syntax: Syntax::default(),
+            functions: FunctionRegistry::new(),
+            user_functions: HashMap::new(),
+            call_depth: 0,
+            memory: 0.0,
+            total: 0.0,
+            last_result: 0.0,
+            propagate_nan: false,
+            intermediate_rounding: None,
+            kahan: false,
+            physics: false,
+        }
+    }
+}
+
+impl Calculator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn variable(&self, name: &str) -> Option<f64> {
+        self.variables.get(name).copied()
+    }
+
+    pub fn set_variable(&mut self, name: &str, value: f64) {
+        self.variables.insert(name.to_string(), value);
+    }
+
+    /// All variables, sorted by name.
+    pub fn variables(&self) -> Vec<(&str, f64)> {
+        let mut variables: Vec<(&str, f64)> = self
+            .variables
+            .iter()
+            .map(|(name, value)| (name.as_str(), *value))
+            .collect();
+        variables.sort_by(|a, b| a.0.cmp(b.0));
+        variables
+    }
+
+    /// The constant with this name, including the physical constants when
+    /// they are turned on.
+    fn named_constant(&self, name: &str) -> Option<f64> {
+        constant(name).or_else(|| physical_constant(name).filter(|_| self.physics))
+    }
+
+    /// Looks up a variable or the memory register.
+    fn lookup(&self, name: &str) -> Option<f64> {
+        if name == MEMORY_RECALL {
+            Some(self.memory)
+        } else {
+            self.variable(name)
+        }
+    }
+
+    /// The result of the last successful `eval`, or 0 before there is one.
+    pub fn last_result(&self) -> f64 {
+        self.last_result
+    }
+
+    pub fn memory(&self) -> f64 {
+        self.memory
+    }
+
+    /// Adds the last result to memory (M+).
+    pub fn memory_add(&mut self) {
+        self.memory += self.last_result;
+    }
+
+    /// Subtracts the last result from memory (M-).
+    pub fn memory_subtract(&mut self) {
+        self.memory -= self.last_result;
+    }
+
+    /// Clears memory (MC).
+    pub fn memory_clear(&mut self) {
+        self.memory = 0.0;
+    }
+
+    /// Replaces memory with the last result.
+    pub fn memory_store(&mut self) {
+        self.memory = self.last_result;
+    }
+
+    /// Makes memory the last result, as if it had just been calculated.
+    pub fn memory_recall(&mut self) {
+        self.last_result = self.memory;
+    }
+
+    /// Exchanges the last result and memory.
+    pub fn memory_swap(&mut self) {
+        std::mem::swap(&mut self.last_result, &mut self.memory);
+    }
+
+    pub fn total(&self) -> f64 {
+        self.total
+    }
+
+    /// Adds the last result to the running total.
+    pub fn accumulate(&mut self) {
+        self.total += self.last_result;
+    }
+
+    pub fn reset_total(&mut self) {
+        self.total = 0.0;
+    }
+
+    /// Tokenizes the expression part of `input`, ignoring any `name =` target.
+    /// A compound assignment such as `x += 3` is desugared to `x + (3)`.
+    pub fn parse(&self, input: &str) -> Result<Vec<Token>, Error> {
+        match parse_assignment(input)? {
+            Some(Assignment {
+                name,
+                operator: Some(op),
+                expression,
+            }) => {
+                let mut tokens = vec![
+                    Token::Variable(name.to_string()),
+                    Token::Operator(op),
+                    Token::LeftParen,
+                ];
+                tokens.extend(self.tokenize(expression)?);
+                tokens.push(Token::RightParen);
+                Ok(tokens)
+            }
+            Some(assignment) => self.tokenize(assignment.expression),
+            None => self.tokenize(input),
+        }
+    }
+
+    fn tokenize(&self, input: &str) -> Result<Vec<Token>, Error> {
+        tokenize_with(input, self.syntax, &|name| self.lookup(name).is_some())
+    }
+
+    /// The ways parsed tokens could be read when the first `%` in them could
+    /// be either the remainder or a percentage. The first reading is the one
+    /// `evaluate` uses. Empty when the expression has a single reading, as all
+    /// expressions do in strict mode.
+    pub fn readings(&self, tokens: &[Token]) -> Vec<Reading> {
+        if self.syntax.strict {
+            return Vec::new();
+        }
+        let (i, replacement) = match other_percent_reading(tokens) {
+            Some(reading) => reading,
+            None => return Vec::new(),
+        };
+
+        let as_percent = replacement[0] == Token::UnaryOperator(PERCENT);
+        let mut alternative = tokens[..i].to_vec();
+        alternative.extend(replacement);
+        alternative.extend_from_slice(&tokens[i + 2..]);
+        vec![
+            percent_reading(!as_percent, tokens.to_vec()),
+            percent_reading(as_percent, alternative),
+        ]
+    }
+
+    pub fn evaluate(&mut self, tokens: &[Token]) -> Result<f64, Error> {
+        self.evaluate_value(tokens)?.scalar()
+    }
+
+    /// Parses the expression part of `input` into a tree, with this
+    /// calculator's syntax settings.
+    pub fn parse_ast(&self, input: &str) -> Result<Expr, Error> {
+        Expr::from_rpn(&to_rpn(&self.parse(input)?)?)
+    }
+
+    /// Evaluates an expression tree, as built by hand or by `parse_to_ast`.
+    pub fn eval_ast(&mut self, expr: &Expr) -> Result<f64, Error> {
+        self.evaluate_rpn(&expr.to_rpn())?.scalar()
+    }
+
+    /// Like `evaluate`, but the result may also be a vector.
+    pub fn evaluate_value(&mut self, tokens: &[Token]) -> Result<Value, Error> {
+        let rpn_tokens = to_rpn(tokens)?;
+        self.evaluate_rpn(&rpn_tokens)
+    }
+
+    fn evaluate_rpn(&mut self, tokens: &[Token]) -> Result<Value, Error> {
+        let mut budget = self.step_limit;
+        self.evaluate_rpn_within(tokens, &mut budget, Instant::now())
+    }
+
+    /// Like `evaluate_rpn`, but taking steps from `budget` and timing from
+    /// `start`, so that the user functions an expression calls share its
+    /// limits instead of each getting their own.
+    fn evaluate_rpn_within(
+        &mut self,
+        tokens: &[Token],
+        budget: &mut u64,
+        start: Instant,
+    ) -> Result<Value, Error> {
+        if self.kahan {
+            if let Some(terms) = sum_terms(tokens) {
+                return self.evaluate_sum(&terms, budget, start);
+            }
+        }
+        self.run_rpn(tokens, None, budget, start)
+    }
+
+    /// Evaluates each term of a sum on its own and adds them up with
+    /// `compensated_sum`. Each `+` or `-` the sum replaces costs a step, and
+    /// the total is rounded like the result of any other operator.
+    fn evaluate_sum(
+        &mut self,
+        terms: &[(bool, &[Token])],
+        budget: &mut u64,
+        start: Instant,
+    ) -> Result<Value, Error> {
+        let mut values = Vec::with_capacity(terms.len());
+        for (subtracted, term) in terms {
+            check_timeout(start, self.timeout)?;
+            let value = self.run_rpn(term, None, budget, start)?.scalar()?;
+            values.push(if *subtracted { -value } else { value });
+        }
+        spend(budget, terms.len().saturating_sub(1) as u64)?;
+        let sum = finite(compensated_sum(&values), self.propagate_nan)?;
+        Ok(self.round_intermediate(Value::Scalar(sum)))
+    }
+
+    /// Like `evaluate`, but calls `observer` with the values on the stack
+    /// after each operator or function is applied, e.g. to show how the
+    /// result is built up. Values that are not known yet, such as an undefined
+    /// variable inside `default`, and vectors are shown as NaN.
+    pub fn evaluate_with_observer(
+        &mut self,
+        tokens: &[Token],
+        mut observer: impl FnMut(&[f64]),
+    ) -> Result<f64, Error> {
+        let rpn_tokens = to_rpn(tokens)?;
+        self.evaluate_rpn_with_observer(&rpn_tokens, Some(&mut observer))?
+            .scalar()
+    }
+
+    /// Evaluates expression tokens already in RPN, such as `2 3 4 * +`, and
+    /// describes each operation it applies, in order. Operands and results
+    /// that are not plain numbers, such as vectors, are given as NaN.
+    pub fn evaluate_rpn_steps(&mut self, tokens: &[Token]) -> Result<(f64, Vec<StepInfo>), Error> {
+        let number = |value: &Result<Value, Error>| match value {
+            Ok(Value::Scalar(value)) => *value,
+            _ => f64::NAN,
+        };
+        let mut stack: Vec<Result<Value, Error>> = Vec::new();
+        let mut budget = self.step_limit;
+        let mut steps = Vec::new();
+        let start = Instant::now();
+
+        for (i, token) in tokens.iter().enumerate() {
+            check_timeout(start, self.timeout)?;
+            let arity = match token {
+                Token::Operator(_) => 2,
+                Token::UnaryOperator(_) => 1,
+                Token::Call(_, count) => *count,
+                _ => 0,
+            };
+            let operands: Vec<f64> = stack[stack.len().saturating_sub(arity)..]
+                .iter()
+                .map(number)
+                .collect();
+
+            let relative_percent = is_relative_percent(tokens, i);
+            self.apply_token(token, relative_percent, &mut stack, &mut budget, start)?;
+
+            if arity > 0 || matches!(token, Token::Call(..)) {
+                steps.push(StepInfo {
+                    operation: token.to_string(),
+                    operands,
+                    result: stack.last().map_or(f64::NAN, number),
+                });
+            }
+        }
+
+        check_timeout(start, self.timeout)?;
+
+        let result = match stack.len() {
+            0 => Err(Error::EmptyExpression),
+            1 => stack.pop().unwrap(),
+            _ => Err(Error::TooManyOperators),
+        };
+        Ok((result?.scalar()?, steps))
+    }
+
+    fn evaluate_rpn_with_observer(
+        &mut self,
+        tokens: &[Token],
+        observer: Option<&mut StackObserver>,
+    ) -> Result<Value, Error> {
+        let mut budget = self.step_limit;
+        self.run_rpn(tokens, observer, &mut budget, Instant::now())
+    }
+
+    fn run_rpn(
+        &mut self,
+        tokens: &[Token],
+        mut observer: Option<&mut StackObserver>,
+        budget: &mut u64,
+        start: Instant,
+    ) -> Result<Value, Error> {
+        // An undefined variable is kept on the stack as an error rather than
+        // failing straight away, and so is anything computed from it, so that
+        // `default` can replace it.
+        let mut stack: Vec<Result<Value, Error>> = Vec::new();
+
+        for (i, token) in tokens.iter().enumerate() {
+            check_timeout(start, self.timeout)?;
+            let relative_percent = is_relative_percent(tokens, i);
+            self.apply_token(token, relative_percent, &mut stack, budget, start)?;
+
+            if let Some(observer) = observer.as_mut() {
+                if matches!(token, Token::Operator(_) | Token::Call(..)) {
+                    let values: Vec<f64> = stack
+                        .iter()
+                        .map(|value| match value {
+                            Ok(Value::Scalar(value)) => *value,
+                            _ => f64::NAN,
+                        })
+                        .collect();
+                    observer(&values);
+                }
+            }
+        }
+        check_timeout(start, self.timeout)?;
+
+        match stack.len() {
+            0 => Err(Error::EmptyExpression),
+            1 => stack.pop().unwrap(),
+            _ => Err(Error::TooManyOperators),
+        }
+    }
+
+    /// Rounds the result of an operator when intermediate rounding is on.
+    fn round_intermediate(&self, value: Value) -> Value {
+        let Some((decimals, mode)) = self.intermediate_rounding else {
+            return value;
+        };
+        let round = |value: f64| format::round_to(value, decimals, mode);
+        match value {
+            Value::Scalar(value) => Value::Scalar(round(value)),
+            Value::Vector(values) => Value::Vector(values.into_iter().map(round).collect()),
+            Value::Quantity(value, unit) => Value::Quantity(round(value), unit),
+            Value::Duration(_) => value,
+        }
+    }
+
+    /// Applies one RPN token to the evaluation stack. Operators and functions
+    /// given vectors are applied elementwise (see `broadcast`). A
+    /// `relative_percent` operator takes its right operand as a fraction of
+    /// its left one.
+    fn apply_token(
+        &mut self,
+        token: &Token,
+        relative_percent: bool,
+        stack: &mut Vec<Result<Value, Error>>,
+        budget: &mut u64,
+        start: Instant,
+    ) -> Result<(), Error> {
+        match token {
+            Token::Number(num) => stack.push(Ok(Value::Scalar(*num))),
+            Token::Duration(minutes) => stack.push(Ok(Value::Duration(*minutes))),
+            Token::Quantity(num, unit) => stack.push(Ok(Value::Quantity(*num, *unit))),
+            Token::Variable(name) => match self.lookup(name) {
+                Some(value) => stack.push(Ok(Value::Scalar(value))),
+                None => match self.named_constant(name) {
+                    Some(value) => stack.push(Ok(Value::Scalar(value))),
+                    None => stack.push(Err(Error::UndefinedVariable(name.clone()))),
+                },
+            },
+            Token::Vector(count) => {
+                if stack.len() < *count {
+                    return Err(Error::InvalidExpression(
+                        "Not enough vector elements".to_string(),
+                    ));
+                }
+                let elements = stack.split_off(stack.len() - count);
+                let vector = elements
+                    .into_iter()
+                    .map(|element| element?.scalar())
+                    .collect::<Result<Vec<f64>, Error>>()
+                    .map(Value::Vector);
+                stack.push(vector);
+            }
+            Token::UnaryOperator(op) => {
+                let operand = stack.pop().ok_or_else(|| {
+                    Error::InvalidExpression("Not enough operands for operator".to_string())
+                })?;
+                let Ok(operand) = operand else {
+                    stack.push(operand);
+                    return Ok(());
+                };
+                let signed = match (&operand, op) {
+                    (Value::Duration(minutes), '-' | '+') => {
+                        Some(Value::Duration(apply_unary_operator(*op, *minutes)?))
+                    }
+                    (Value::Quantity(value, unit), '-' | '+') => {
+                        Some(Value::Quantity(apply_unary_operator(*op, *value)?, *unit))
+                    }
+                    _ => None,
+                };
+                if let Some(result) = signed {
+                    spend(budget, 1)?;
+                    stack.push(Ok(result));
+                    return Ok(());
+                }
+                let result = broadcast(&[operand], |args| {
+                    // A factorial takes one multiplication per factor
+                    let steps = if *op == '!' {
+                        args[0].max(1.0) as u64
+                    } else {
+                        1
+                    };
+                    spend(budget, steps)?;
+                    finite(apply_unary_operator(*op, args[0])?, self.propagate_nan)
+                })?;
+                stack.push(Ok(result));
+            }
+            Token::Operator(op) => {
+                if stack.len() < 2 {
+                    return Err(Error::InvalidExpression(
+                        "Not enough operands for operator".to_string(),
+                    ));
+                }
+                let right = stack.pop().unwrap();
+                let left = stack.pop().unwrap();
+                let (right, left) = match (right, left) {
+                    (Ok(right), Ok(left)) => (right, left),
+                    (_, Err(e)) | (Err(e), _) => {
+                        stack.push(Err(e));
+                        return Ok(());
+                    }
+                };
+                let right = if relative_percent {
+                    apply_value_operator('*', left.clone(), right, budget, self.propagate_nan)?
+                } else {
+                    right
+                };
+                let result = apply_value_operator(*op, left, right, budget, self.propagate_nan)?;
+                stack.push(Ok(self.round_intermediate(result)));
+            }
+            Token::Call(name, count) => {
+                spend(budget, 1)?;
+                if stack.len() < *count {
+                    return Err(Error::InvalidExpression(
+                        "Not enough arguments for function".to_string(),
+                    ));
+                }
+                let args = stack.split_off(stack.len() - count);
+
+                if name == "default" {
+                    stack.push(default(args)?);
+                    return Ok(());
+                }
+
+                let args = match args.into_iter().collect::<Result<Vec<_>, _>>() {
+                    Ok(args) => args,
+                    Err(e) => {
+                        stack.push(Err(e));
+                        return Ok(());
+                    }
+                };
+                let result = broadcast(&args, |args| {
+                    finite(
+                        self.call_function(name, args, budget, start)?,
+                        self.propagate_nan,
+                    )
+                })
+                .map_err(|e| e.in_function(name))?;
+                stack.push(Ok(result));
+            }
+            Token::LeftParen | Token::RightParen | Token::LeftBracket | Token::RightBracket => {
+                return Err(Error::InvalidExpression("Bracket found in RPN".to_string()));
+            }
+            Token::Function(_) | Token::Comma | Token::Pipe(_) => {
+                return Err(Error::InvalidExpression(
+                    "Function syntax found in RPN".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Evaluates `tokens` with exact fractions instead of floating point, so
+    /// that `1/3 + 1/3 + 1/3` is exactly 1. Only the functions with exact
+    /// results (`mod`, `frac`, `inv`, `sq`, `neg` and `bool`) are available.
+    pub fn evaluate_exact(&mut self, tokens: &[Token]) -> Result<Rational, Error> {
+        let rpn_tokens = to_rpn(tokens)?;
+        let mut stack = Vec::new();
+        let mut budget = self.step_limit;
+        let start = Instant::now();
+
+        for (i, token) in rpn_tokens.iter().enumerate() {
+            check_timeout(start, self.timeout)?;
+            match token {
+                Token::Number(num) => stack.push(Rational::from_f64(*num)?),
+                Token::Duration(_) => {
+                    return Err(Error::InvalidExpression(
+                        "durations have no exact result".to_string(),
+                    ))
+                }
+                Token::Quantity(..) => {
+                    return Err(Error::InvalidExpression(
+                        "quantities with units have no exact result".to_string(),
+                    ))
+                }
+                Token::Variable(name) => match self.lookup(name) {
+                    Some(value) => stack.push(Rational::from_f64(value)?),
+                    None => match self.named_constant(name) {
+                        Some(_) => {
+                            return Err(Error::InvalidExpression(format!(
+                                "{} has no exact value",
+                                name
+                            )))
+                        }
+                        None => return Err(Error::UndefinedVariable(name.clone())),
+                    },
+                },
+                Token::UnaryOperator(op) => {
+                    let operand = stack.pop().ok_or_else(|| {
+                        Error::InvalidExpression("Not enough operands for operator".to_string())
+                    })?;
+                    let steps = if *op == '!' {
+                        operand.numer().max(1) as u64
+                    } else {
+                        1
+                    };
+                    spend(&mut budget, steps)?;
+                    stack.push(rational::apply_rational_unary_operator(*op, operand)?);
+                }
+                Token::Operator(op) => {
+                    spend(&mut budget, 1)?;
+                    if stack.len() < 2 {
+                        return Err(Error::InvalidExpression(
+                            "Not enough operands for operator".to_string(),
+                        ));
+                    }
+                    let right = stack.pop().unwrap();
+                    let left = stack.pop().unwrap();
+                    let right = if is_relative_percent(&rpn_tokens, i) {
+                        left.checked_mul(right)?
+                    } else {
+                        right
+                    };
+                    stack.push(rational::apply_rational_operator(*op, right, left)?);
+                }
+                Token::Call(name, count) => {
+                    spend(&mut budget, 1)?;
+                    if stack.len() < *count {
+                        return Err(Error::InvalidExpression(
+                            "Not enough arguments for function".to_string(),
+                        ));
+                    }
+                    let args = stack.split_off(stack.len() - count);
+                    let result = rational::apply_rational_function(name, &args)
+                        .map_err(|e| e.in_function(name))?;
+                    stack.push(result);
+                }
+                Token::Vector(_) => {
+                    return Err(Error::InvalidExpression(
+                        "vectors have no exact result".to_string(),
+                    ))
+                }
+                Token::LeftParen
+                | Token::RightParen
+                | Token::LeftBracket
+                | Token::RightBracket
+                | Token::Function(_)
+                | Token::Comma
+                | Token::Pipe(_) => {
+                    return Err(Error::InvalidExpression(
+                        "Infix syntax found in RPN".to_string(),
+                    ));
+                }
+            }
+        }
+        check_timeout(start, self.timeout)?;
+
+        match stack[..] {
+            [] => Err(Error::EmptyExpression),
+            [result] => Ok(result),
+            _ => Err(Error::TooManyOperators),
+        }
+    }
+
+    /// Calls a function, handling those that depend on calculator state
+    /// before falling back to the registered functions. User functions take
+    /// their steps from `budget` and their time from `start`.
+    fn call_function(
+        &mut self,
+        name: &str,
+        args: &[f64],
+        budget: &mut u64,
+        start: Instant,
+    ) -> Result<f64, Error> {
+        match (name, args) {
+            ("rand", []) => Ok(self.rng.next_f64()),
+            ("rand", [low, high]) => Ok(low + (high - low) * self.rng.next_f64()),
+            ("rand", _) => Err(Error::WrongArgumentCount(name.to_string(), 2, args.len())),
+            ("atan2", [y, x]) => Ok(self.angle_mode.convert_radians(y.atan2(*x))),
+            ("atan2", _) => Err(Error::WrongArgumentCount(name.to_string(), 2, args.len())),
+            _ if self.user_functions.contains_key(name) => {
+                self.call_user_function(name, args, budget, start)
+            }
+            _ => self.functions.call(name, args),
+        }
+    }
+
+    /// Evaluates a user function's body with its parameter set to the
+    /// argument. A variable with the same name is restored afterwards.
+    fn call_user_function(
+        &mut self,
+        name: &str,
+        args: &[f64],
+        budget: &mut u64,
+        start: Instant,
+    ) -> Result<f64, Error> {
+        let [arg] = args else {
+            return Err(Error::WrongArgumentCount(name.to_string(), 1, args.len()));
+        };
+        if self.call_depth >= MAX_CALL_DEPTH {
+            return Err(Error::RecursionLimit(name.to_string()));
+        }
+        let Some(function) = self.user_functions.get(name).cloned() else {
+            return Err(Error::UnknownFunction(name.to_string()));
+        };
+
+        let shadowed = self.variables.insert(function.parameter.clone(), *arg);
+        self.call_depth += 1;
+        let result = self
+            .evaluate_rpn_within(&function.body, budget, start)
+            .and_then(|value| value.scalar());
+        self.call_depth -= 1;
+        match shadowed {
+            Some(value) => self.variables.insert(function.parameter, value),
+            None => self.variables.remove(&function.parameter),
+        };
+        result
+    }
+
+    /// Defines a function of one argument from text such as
+    /// `double(x) = x * 2`, replacing any earlier definition with that name.
+    /// Built-in and registered functions cannot be redefined.
+    pub fn define_function(&mut self, definition: &str) -> Result<(), Error> {
+        let (head, body) = definition
+            .split_once('=')
+            .ok_or_else(|| Error::InvalidAssignment(definition.trim().to_string()))?;
+        let head = head.trim();
+        let invalid = || Error::InvalidAssignment(head.to_string());
+        let (name, parameter) = head
+            .strip_suffix(')')
+            .and_then(|head| head.split_once('('))
+            .ok_or_else(invalid)?;
+        let (name, parameter) = (name.trim(), parameter.trim());
+        if !is_identifier(name) || !is_identifier(parameter) {
+            return Err(invalid());
+        }
+        if self.functions.contains(name) || matches!(name, "rand" | "atan2" | "default") {
+            return Err(Error::InvalidAssignment(format!(
+                "{} is a built-in function",
+                name
+            )));
+        }
+
+        let is_variable = |name: &str| name == parameter || self.lookup(name).is_some();
+        let body = to_rpn(&tokenize_with(body, self.syntax, &is_variable)?)?;
+        let function = UserFunction {
+            parameter: parameter.to_string(),
+            body,
+        };
+        self.user_functions.insert(name.to_string(), function);
+        Ok(())
+    }
+
+    /// Names of the functions defined with `define_function`, sorted.
+    pub fn user_functions(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.user_functions.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn functions(&self) -> &FunctionRegistry {
+        &self.functions
+    }
+
+    /// Makes `name(...)` callable in expressions. `rand`, `atan2` and
+    /// `default` depend on the calculator's state and cannot be replaced.
+    pub fn register_function<F>(&mut self, name: &str, arity: usize, apply: F)
+    where
+        F: Fn(&[f64]) -> Result<f64, Error> + Send + Sync + 'static,
+    {
+        self.functions.register(name, arity, apply);
+    }
+
+    pub fn angle_mode(&self) -> AngleMode {
+        self.angle_mode
+    }
+
+    pub fn set_angle_mode(&mut self, mode: AngleMode) {
+        self.angle_mode = mode;
+    }
+
+    pub fn decimal_comma(&self) -> bool {
+        self.syntax.decimal_comma
     }
-}
 
-pub fn parse_expression(expression: Vec<&str>) -> Result<Vec<Token>, Error> {
-    let mut tokens = Vec::new();
+    /// Reads `,` as the decimal point and `;` as the argument separator, as
+    /// numbers are written in many European locales.
+    pub fn set_decimal_comma(&mut self, decimal_comma: bool) {
+        self.syntax.decimal_comma = decimal_comma;
+    }
 
-    for token in expression {
-        if let Ok(num) = token.parse::<f64>() {
-            tokens.push(Token::Number(num));
-        } else if token == "(" {
-            tokens.push(Token::LeftParen);
-        } else if token == ")" {
-            tokens.push(Token::RightParen);
-        } else if token.len() == 1 && "+-*/^".contains(token) {
-            tokens.push(Token::Operator(token.chars().next().unwrap()));
-        } else {
-            return Err(Error::InvalidExpression(token.to_string()));
+    pub fn arg_separator(&self) -> char {
+        self.syntax.arg_separator
+    }
+
+    /// Chooses whether `,` (the default) or `;` separates function arguments
+    /// and vector elements. With a decimal comma `;` is used either way.
+    pub fn set_arg_separator(&mut self, separator: char) -> Result<(), Error> {
+        if !matches!(separator, ',' | ';') {
+            return Err(Error::InvalidArgument(format!(
+                "argument separator {}",
+                separator
+            )));
         }
+        self.syntax.arg_separator = separator;
+        Ok(())
     }
 
-    // Check if we have at least one number and one operator
-    if tokens.is_empty() {
-        return Err(Error::EmptyExpression);
+    pub fn strict(&self) -> bool {
+        self.syntax.strict
     }
 
-    Ok(tokens)
-}
+    /// Rejects implicit multiplication and `%` as a percentage, which are
+    /// accepted by default.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.syntax.strict = strict;
+    }
 
-fn precedence(op: char) -> i32 {
-    // Define operator precedence
-    match op {
-        '+' | '-' => 1,
-        '*' | '/' => 2,
-        '^' => 3,
-        _ => 0,
+    pub fn physics(&self) -> bool {
+        self.physics
     }
-}
 
-fn is_right_associative(op: char) -> bool {
-    op == '^'
-}
+    /// Makes the physical constants in `PHYSICAL_CONSTANTS`, such as `c` and
+    /// `NA`, usable in expressions. They are off by default so that their
+    /// short names are free for variables, which always take precedence.
+    pub fn set_physics(&mut self, physics: bool) {
+        self.physics = physics;
+    }
 
-fn apply_operator(op: char, b: f64, a: f64) -> Result<f64, Error> {
-    match op {
-        '+' => Ok(a + b),
-        '-' => Ok(a - b),
-        '*' => Ok(a * b),
-        '/' => {
-            if b == 0.0 {
-                Err(Error::DivisionByZero)
-            } else {
-                Ok(a / b)
-            }
+    pub fn propagate_nan(&self) -> bool {
+        self.propagate_nan
+    }
+
+    /// Lets NaN and infinite values flow through an expression, e.g. so that
+    /// `isinf(1e308 * 10)` is 1, instead of failing with `NotFinite` as soon
+    /// as one is computed.
+    pub fn set_propagate_nan(&mut self, propagate: bool) {
+        self.propagate_nan = propagate;
+    }
+
+    pub fn kahan(&self) -> bool {
+        self.kahan
+    }
+
+    /// Adds up the terms of a sum such as `0.1 + 0.2 + 0.3` with
+    /// `compensated_sum` instead of one addition at a time, for less rounding
+    /// error over long chains. Sums inside function arguments or brackets
+    /// that are multiplied are still added plainly.
+    ///
+    /// Either way results are reproducible: arithmetic is IEEE 754 double
+    /// precision with round-to-nearest, so the same expression gives the same
+    /// bits on every platform. Only functions such as `exp` and `ln`, which
+    /// use the platform's math library, may differ in the last digit.
+    pub fn set_kahan(&mut self, kahan: bool) {
+        self.kahan = kahan;
+    }
+
+    pub fn intermediate_rounding(&self) -> Option<(usize, RoundingMode)> {
+        self.intermediate_rounding
+    }
+
+    /// Rounds the result of every operator to the given decimal places, as
+    /// a fixed-point calculation by hand would, instead of only rounding the
+    /// final result for display. `None` keeps full precision throughout.
+    pub fn set_intermediate_rounding(&mut self, rounding: Option<(usize, RoundingMode)>) {
+        self.intermediate_rounding = rounding;
+    }
+
+    /// Limits how many operation steps a single evaluation may take, to
+    /// guard against pathological input such as huge factorials.
+    pub fn set_step_limit(&mut self, limit: u64) {
+        self.step_limit = limit;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Limits how long a single evaluation may take, failing with `Timeout`
+    /// once it is exceeded. Time is checked between the steps of an
+    /// evaluation, so a slow function is only stopped after it returns.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Reseeds `rand()` so that the values it returns are reproducible.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Evaluates a line of input, storing the result when it is an assignment.
+    pub fn eval(&mut self, input: &str) -> Result<f64, Error> {
+        let tokens = self.parse(input)?;
+        let result = self.evaluate(&tokens)?;
+
+        if let Some(assignment) = parse_assignment(input)? {
+            self.set_variable(assignment.name, result);
         }
-        '^' => Ok(a.powf(b)),
-        _ => Err(Error::InvalidOperator(op.to_string())),
+
+        self.last_result = result;
+        Ok(result)
     }
-}
 
-fn to_rpn(tokens: &[Token]) -> Result<Vec<Token>, Error> {
-    let mut output = Vec::new();
-    let mut operators: Vec<Token> = Vec::new();
-    let mut previous_token: Option<Token> = None;
+    /// Like `eval`, but through a shared reference, so that several threads
+    /// can evaluate against one calculator at once. Each call works on its
+    /// own copy of the calculator: assignments are not stored, and `rand()`
+    /// gives the same numbers every time.
+    pub fn eval_shared(&self, input: &str) -> Result<f64, Error> {
+        let tokens = self.parse(input)?;
+        self.clone().evaluate(&tokens)
+    }
 
-    for &token in tokens {
-        match token {
-            Token::Number(_) => output.push(token),
-            Token::LeftParen => operators.push(Token::LeftParen),
-            Token::RightParen => {
-                if matches!(previous_token, Some(Token::LeftParen)) {
-                    return Err(Error::EmptyBrackets);
+    /// Evaluates each input in order with `eval`, so that variables assigned
+    /// by one are available to the next. With `timed`, each result also
+    /// says how long it took, for profiling.
+    pub fn eval_batch<'a>(
+        &mut self,
+        inputs: impl IntoIterator<Item = &'a str>,
+        timed: bool,
+    ) -> Vec<BatchResult> {
+        inputs
+            .into_iter()
+            .map(|input| {
+                let start = Instant::now();
+                let result = self.eval(input);
+                BatchResult {
+                    result,
+                    elapsed: timed.then(|| start.elapsed()),
                 }
+            })
+            .collect()
+    }
 
-                let mut found_left = false;
-                while let Some(top) = operators.pop() {
-                    match top {
-                        Token::Operator(op) => output.push(Token::Operator(op)),
-                        Token::LeftParen => {
-                            found_left = true;
-                            break;
-                        }
-                        _ => return Err(Error::MismatchedBracket),
-                    }
-                }
+    /// Like `eval`, but the result may also be a vector. Variables only hold
+    /// numbers, so a vector cannot be assigned.
+    pub fn eval_value(&mut self, input: &str) -> Result<Value, Error> {
+        let tokens = self.parse(input)?;
+        let result = self.evaluate_value(&tokens)?;
 
-                if !found_left {
-                    return Err(Error::MismatchedBracket);
-                }
+        if let Value::Scalar(value) = result {
+            if let Some(assignment) = parse_assignment(input)? {
+                self.set_variable(assignment.name, value);
             }
-            Token::Operator(op) => {
-                while let Some(top) = operators.last().copied() {
-                    match top {
-                        Token::Operator(top_op) => {
-                            let should_pop = if is_right_associative(op) {
-                                precedence(top_op) > precedence(op)
-                            } else {
-                                precedence(top_op) >= precedence(op)
-                            };
-
-                            if should_pop {
-                                operators.pop();
-                                output.push(Token::Operator(top_op));
-                            } else {
-                                break;
-                            }
-                        }
-                        Token::LeftParen => break,
-                        _ => {
-                            return Err(Error::InvalidExpression(
-                                "Invalid token on stack".to_string(),
-                            ))
-                        }
-                    }
-                }
+            self.last_result = value;
+        } else if let Some(assignment) = parse_assignment(input)? {
+            return Err(Error::InvalidAssignment(format!(
+                "{} cannot hold the vector {}",
+                assignment.name, result
+            )));
+        }
 
-                operators.push(Token::Operator(op));
-            }
+        Ok(result)
+    }
+
+    /// Like `eval`, but computes the result exactly with `evaluate_exact`.
+    /// Assigned variables hold the nearest floating point value.
+    pub fn eval_exact(&mut self, input: &str) -> Result<Rational, Error> {
+        let tokens = self.parse(input)?;
+        let result = self.evaluate_exact(&tokens)?;
+
+        if let Some(assignment) = parse_assignment(input)? {
+            self.set_variable(assignment.name, result.to_f64());
         }
 
-        previous_token = Some(token);
+        self.last_result = result.to_f64();
+        Ok(result)
     }
 
-    // Pop all remaining operators from the stack
-    while let Some(top) = operators.pop() {
-        match top {
-            Token::Operator(op) => output.push(Token::Operator(op)),
-            Token::LeftParen | Token::RightParen => return Err(Error::MismatchedBracket),
-            _ => {
-                return Err(Error::InvalidExpression(
-                    "Invalid token on stack".to_string(),
-                ))
+    /// Evaluates `name = expr` definitions one per line, skipping blank and
+    /// comment lines.
+    /// Failing lines are returned with their 1-based line number so that the
+    /// remaining definitions can still be loaded.
+    pub fn load_definitions(&mut self, contents: &str) -> Vec<(usize, Error)> {
+        let mut errors = Vec::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            if strip_comment(line).trim().is_empty() {
+                continue;
+            }
+
+            let result = match parse_assignment(line) {
+                Ok(Some(_)) => self.eval(line).map(|_| ()),
+                Ok(None) => Err(Error::InvalidAssignment(line.trim().to_string())),
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                errors.push((index + 1, e));
             }
         }
+
+        errors
     }
+}
 
-    Ok(output)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenStats {
+    pub numbers: usize,
+    pub operators: usize,
+}
+
+impl Display for TokenStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} numbers, {} operators", self.numbers, self.operators)
+    }
 }
 
-fn evaluate_rpn(tokens: &[Token]) -> Result<f64, Error> {
-    let mut stack = Vec::new();
+pub fn token_stats(tokens: &[Token]) -> TokenStats {
+    let mut stats = TokenStats {
+        numbers: 0,
+        operators: 0,
+    };
 
     for token in tokens {
         match token {
-            Token::Number(num) => stack.push(*num),
-            Token::Operator(op) => {
-                if stack.len() < 2 {
-                    return Err(Error::InvalidExpression(
-                        "Not enough operands for operator".to_string(),
-                    ));
-                }
-                let right = stack.pop().unwrap();
-                let left = stack.pop().unwrap();
-                let result = apply_operator(*op, right, left)?;
-                if !result.is_finite() {
-                    return Err(Error::NotFinite);
-                }
-                stack.push(result);
-            }
-            Token::LeftParen | Token::RightParen => {
-                return Err(Error::InvalidExpression(
-                    "Parenthesis found in RPN".to_string(),
-                ));
-            }
+            Token::Number(_) | Token::Duration(_) | Token::Quantity(..) => stats.numbers += 1,
+            Token::Operator(_) | Token::UnaryOperator(_) => stats.operators += 1,
+            _ => {}
         }
     }
 
-    if stack.len() != 1 {
-        return Err(Error::TooManyOperators);
-    }
-
-    Ok(stack[0])
+    stats
 }
 
-pub fn evaluate_expression(expression: &[Token]) -> Result<f64, Error> {
-    let rpn_tokens = to_rpn(expression)?;
-    evaluate_rpn(&rpn_tokens)
+/// What `estimate_cost` counts for a factorial of an expression, whose size
+/// is only known once it has been evaluated.
+const UNKNOWN_FACTORIAL_COST: u64 = 1000;
+
+/// What `estimate_cost` counts for a power.
+const POWER_COST: u64 = 10;
+
+/// A rough count of the operations evaluating parsed tokens would take,
+/// without evaluating them, so that expensive input can be turned away
+/// first. Each operator and function call counts 1, except that a power
+/// counts `POWER_COST` and a factorial counts its number (`100 !` is 100),
+/// like the step limit, or `UNKNOWN_FACTORIAL_COST` when it is not a plain
+/// number.
+pub fn estimate_cost(tokens: &[Token]) -> u64 {
+    let factorial_cost = |operand: Option<&Token>| match operand {
+        Some(Token::Number(n)) => n.max(1.0) as u64,
+        _ => UNKNOWN_FACTORIAL_COST,
+    };
+
+    let mut cost: u64 = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        let token_cost = match token {
+            Token::UnaryOperator('!') => factorial_cost(i.checked_sub(1).map(|j| &tokens[j])),
+            Token::Operator('^') => POWER_COST,
+            Token::Operator(_) | Token::UnaryOperator(_) | Token::Pipe(_) => 1,
+            Token::Function(name) if name == "factorial" => {
+                match (tokens.get(i + 2), tokens.get(i + 3)) {
+                    (operand, Some(Token::RightParen)) => factorial_cost(operand),
+                    _ => UNKNOWN_FACTORIAL_COST,
+                }
+            }
+            Token::Function(_) => 1,
+            _ => 0,
+        };
+        cost = cost.saturating_add(token_cost);
+    }
+    cost
 }
 
-pub fn print_help_doc() {
-    println!("Commands:");
-    println!(" Enter an expression to evaluate it (e.g., 2 + 2)");
-    println!(" Parentheses are supported (e.g., ( 2 + 3 ) * 4)");
-    println!(" Exponentiation is supported with ^ (e.g., 2 ^ 3 ^ 2)");
-    println!(" Type 'quit' or 'q' to exit the calculator");
-    println!(" Type 'help' to see this help message");
+pub const HELP_DOC: &str = "\
+Commands:
+ Enter an expression to evaluate it (e.g., 2 + 2)
+ Type 'help' followed by a topic for more:
+  help syntax     numbers, variables, vectors and comments
+  help operators  arithmetic, comparisons and logic
+  help functions  built-in functions and constants
+  help commands   settings, memory, history and other commands
+ Type 'quit' or 'q' to exit the calculator
+ Type 'help' to see this help message
+";
+
+/// Focused help for `help <topic>`, by topic name.
+pub const HELP_TOPICS: &[(&str, &str)] = &[
+    (
+        "syntax",
+        "\
+Syntax:
+ Parentheses are supported (e.g., ( 2 + 3 ) * 4)
+ The zero on either side of a decimal point can be left out (e.g., .5 + 5. = 5.5)
+ Hexadecimal literals are supported (e.g., 0xff, 0x1p4 = 16)
+ Durations are written h:mm or h:mm:ss; they can be added, subtracted, compared,
+ multiplied and divided by numbers (e.g., 1:30 + 0:45 = 2:15, 1:30 * 3 = 4:30)
+ Assign variables with = (e.g., x = 2 * 3), then use them (e.g., x + 1)
+ Update variables with +=, -=, *= or /= (e.g., x += 3 is x = x + 3)
+ Lengths (mm, cm, m, km) and masses (mg, g, kg) can have a unit written against
+ the number; results are in the unit on the left (e.g., 5km + 300m = 5.3km)
+ Vectors are written [1, 2, 3]; operators and functions apply to each element
+ (e.g., [1, 2, 3] + 1 = [2, 3, 4], sq([1, 2]) = [1, 4])
+ Multiplication can be implicit (e.g., 2(3 + 1) = 8) and a trailing % is a percentage
+ (e.g., 50% = 0.5), unless --strict is given; --ambiguous-report shows both
+ results when a % could be either (e.g., 50 % -3)
+ Adding or subtracting a percentage takes that percentage of the number before
+ it (e.g., 200 + 10% = 220, 200 - 10% = 180)
+ |> passes the value before it to a function (e.g., 16 |> sqrt |> sqrt = 2)
+ Anything after # is a comment (e.g., 2 + 3 # add them)
+",
+    ),
+    (
+        "operators",
+        "\
+Operators:
+ + - * / as usual, with * and / before + and -
+ Exponentiation is supported with ^ (e.g., 2 ^ 3 ^ 2)
+ - and + can also be signs, which bind more loosely than ^ (e.g., -2 ^ 2 = -4, --5 = 5)
+ ! is the factorial (e.g., 5 ! = 120)
+ \\ is integer division, rounding down (e.g., 7 \\ 2 = 3)
+ % is the remainder (e.g., -7 % 3 = -1); see mod() for the modulo
+ Superscripts square and cube (e.g., 5² = 25, 2³ = 8)
+ Typographic minus signs and dashes (−, –), × and ÷ work like -, * and /
+ Comparisons <, >, <=, >=, == and != give 1 for true and 0 for false
+ and, or and not treat any non-zero number as true (e.g., 1 and not 0 = 1)
+ Type 'bool-display on' to show their results as true and false
+",
+    ),
+    (
+        "functions",
+        "\
+Functions:
+ inv(x) = 1 / x, sq(x) = x * x, neg(x) = -x, exp(x), ln(x), sqrt(x), hypot(x, y), atan2(y, x)
+ mod(a, b) is the modulo, with the sign of b (e.g., mod(-7, 3) = 2)
+ gamma(x) extends the factorial, so factorial(x) = gamma(x + 1) also works for
+ non-integers (e.g., factorial(0.5) = 0.886...)
+ frac(3, 4) is the fraction 3/4 as a single number; its parts must be whole numbers
+ default(x, 0) is x, or 0 if x is not defined
+ rand() is uniform in [0, 1) and rand(a, b) in [a, b); type 'seed N' or start with
+ --seed N to make it repeatable
+ bool(x) is 1 if x is not zero and 0 if it is, for using truth in arithmetic
+ (e.g., bool(x > 2) * 5 + 1)
+ isnan(x) and isinf(x) are 1 if x is NaN or infinite, and 0 otherwise; such values
+ only reach them with --propagate-nan
+ The constant e is available (e.g., ln(e) = 1)
+ With --physics, so are c (speed of light, 299792458 m/s), h (Planck, 6.62607015e-34),
+ k (Boltzmann, 1.380649e-23), NA (Avogadro, 6.02214076e23), qe (elementary charge,
+ 1.602176634e-19), G (gravitational, 6.6743e-11) and gn (standard gravity, 9.80665)
+",
+    ),
+    (
+        "commands",
+        "\
+Commands:
+ Type 'mode deg' or 'mode rad' to choose the angle unit (radians by default)
+ Type '?' before an expression to see its result without changing the last
+ result, variables or history (e.g., ? 2 + 3)
+ Type 'explain' before an expression to see the order it is computed in
+ Type 'tree' before an expression to draw it as a tree, with what is computed
+ first at the bottom
+ Type 'bases' before an expression to show a whole number result in decimal,
+ hexadecimal, octal and binary
+ Type 'rounding half-up', 'rounding half-even' or 'rounding truncate' to choose
+ how results are rounded when a precision is set (half-even by default)
+ Type 'exact on' to compute with exact fractions (e.g., 1/3 + 1/3 + 1/3 = 1)
+ and 'exact off' to go back to floating point
+ Type 'arg-separator ;' to separate function arguments and vector elements with ;
+ (e.g., hypot(3; 4)) and 'arg-separator ,' to go back; with --decimal-comma it is ;
+ Type 'bool-display on' to show comparison results as true and false, and
+ 'bool-display off' to show them as 1 and 0
+ Type 'format currency' to show 2 decimal places with thousands separators
+ (e.g., 1,234.50) and 'format default' to go back
+ Type 'precision?', 'mode?' or 'base?' to show the current setting
+ Type 'base 16' to show whole-number results in hexadecimal (also 8 or 2) and
+ 'base 10' to go back
+ Type 'reset-precision' to go back to showing results in full
+ Type 'M+' or 'M-' to add the last result to memory or subtract it, 'MC' to clear
+ memory, and use MR in an expression to recall it (e.g., MR * 2)
+ Type 'store' to put the last result in memory, 'recall' to make memory the last
+ result and 'swap' to exchange them
+ Type 'empty-repeats on' to show the last result again when Enter is pressed on
+ an empty line, and 'empty-repeats off' to go back to ignoring empty lines
+ Type 'accumulate on' to add each result to a running total shown after it,
+ 'accumulate reset' to start the total again from 0 and 'accumulate off' to stop
+ Type 'again' to run the last expression again, e.g. after changing a setting
+ Type 'fix <search> <replace>' to run the last expression again with the first
+ <search> in it replaced (e.g., 'fix 3 4' after 2 + 3)
+ Type 'report' to list all variables and their values
+ Type 'begin' to collect the lines that follow, e.g. a pasted block of
+ calculations, and 'end' to run them all in order
+ Type 'convert <number> to hex|octal|binary|dec' to write a number in another
+ base (e.g., convert 0b1010 to dec = 10)
+ Type 'round-intermediate on' to round the result of every operation to the
+ precision, as when working to fixed decimals by hand, and 'round-intermediate off'
+ to only round what is shown
+ Type 'def name(x) = expression' to define a function of one argument
+ (e.g., def double(x) = x * 2, then double(5) = 10)
+ Type 'kahan on' to add up long sums with compensated (Kahan) summation, which
+ loses less to rounding (e.g., 0.1 added ten times is exactly 1), and 'kahan off'
+ to add one term at a time
+ Type 'history' to list previously entered expressions
+ End an expression with 'as hex', 'as octal', 'as binary' or 'as sci' to show just
+ that result differently (e.g., 255 as hex = 0xff, 1000000 as sci = 1e6)
+ Type 'stats' to show the count, sum, mean, minimum and maximum of the results
+ so far
+ Type 'seed N' to make rand() repeatable
+ Type 'quit' or 'q' to exit the calculator
+",
+    ),
+];
+
+/// The help text for a topic in `HELP_TOPICS`, ignoring case.
+pub fn help_topic(name: &str) -> Option<&'static str> {
+    HELP_TOPICS
+        .iter()
+        .find(|(topic, _)| topic.eq_ignore_ascii_case(name))
+        .map(|(_, text)| *text)
 }
 
 #[cfg(test)]
@@ -279,6 +3142,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_to_rpn_left_associative_subtraction() {
+        let tokens = parse_expression(vec!["8", "-", "3", "-", "2"]).unwrap();
+        let rpn = to_rpn(&tokens).unwrap();
+        assert_eq!(
+            rpn,
+            vec![
+                Token::Number(8.0),
+                Token::Number(3.0),
+                Token::Operator('-'),
+                Token::Number(2.0),
+                Token::Operator('-'),
+            ]
+        );
+    }
+
     #[test]
     fn test_to_rpn_right_associative_exponent() {
         let tokens = parse_expression(vec!["2", "^", "3", "^", "2"]).unwrap();
@@ -306,8 +3185,9 @@ mod tests {
             apply_operator('/', 0.0, 4.0),
             Err(Error::DivisionByZero)
         ));
+        assert_eq!(apply_operator('%', 3.0, 7.0), Ok(1.0));
         assert!(matches!(
-            apply_operator('%', 2.0, 1.0),
+            apply_operator('$', 2.0, 1.0),
             Err(Error::InvalidOperator(_))
         ));
     }
@@ -318,14 +3198,41 @@ mod tests {
         assert_eq!(precedence('-'), 1);
         assert_eq!(precedence('*'), 2);
         assert_eq!(precedence('/'), 2);
+        assert_eq!(precedence('\\'), 2);
+        assert_eq!(precedence('%'), 2);
         assert_eq!(precedence('^'), 3);
-        assert_eq!(precedence('%'), 0);
+        assert_eq!(precedence('$'), 0);
+    }
+
+    #[test]
+    fn test_evaluate_empty_rpn() {
+        let mut calculator = Calculator::new();
+        assert_eq!(calculator.evaluate_rpn(&[]), Err(Error::EmptyExpression));
+        assert_eq!(calculator.evaluate_exact(&[]), Err(Error::EmptyExpression));
+        assert_eq!(explain_expression(&[]), Err(Error::EmptyExpression));
+        assert_eq!(
+            calculator.evaluate_rpn(&[Token::Number(1.0), Token::Number(2.0)]),
+            Err(Error::TooManyOperators)
+        );
+    }
+
+    #[test]
+    fn test_gamma() {
+        assert_eq!(gamma(5.0), Ok(24.0));
+        assert_eq!(gamma(1.0), Ok(1.0));
+        let sqrt_pi = std::f64::consts::PI.sqrt();
+        assert!((gamma(0.5).unwrap() - sqrt_pi).abs() < 1e-14);
+        assert!((gamma(-0.5).unwrap() + 2.0 * sqrt_pi).abs() < 1e-13);
+        assert!((gamma(4.5).unwrap() - 11.631_728_396_567_45).abs() < 1e-12);
+        assert!(matches!(gamma(0.0), Err(Error::DomainError(_))));
+        assert!(matches!(gamma(-3.0), Err(Error::DomainError(_))));
     }
 
     #[test]
-    fn test_is_right_associative() {
-        assert!(is_right_associative('^'));
-        assert!(!is_right_associative('*'));
+    fn test_associativity() {
+        assert_eq!(associativity('^'), Assoc::Right);
+        assert_eq!(associativity('*'), Assoc::Left);
+        assert_eq!(associativity('-'), Assoc::Left);
     }
 
     #[test]
@@ -348,4 +3255,231 @@ mod tests {
             Err(Error::EmptyBrackets)
         ));
     }
+
+    #[test]
+    fn test_tokenize_function_call() {
+        let tokens = tokenize("mod(-7,3)").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Function("mod".to_string()),
+                Token::LeftParen,
+                Token::UnaryOperator('-'),
+                Token::Number(7.0),
+                Token::Comma,
+                Token::Number(3.0),
+                Token::RightParen,
+            ]
+        );
+
+        let rpn = to_rpn(&tokens).unwrap();
+        assert_eq!(
+            rpn,
+            vec![
+                Token::Number(7.0),
+                Token::UnaryOperator('-'),
+                Token::Number(3.0),
+                Token::Call("mod".to_string(), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_binary_minus() {
+        assert_eq!(
+            tokenize("3-2").unwrap(),
+            vec![Token::Number(3.0), Token::Operator('-'), Token::Number(2.0)]
+        );
+        assert_eq!(
+            tokenize("3 * -2").unwrap(),
+            vec![
+                Token::Number(3.0),
+                Token::Operator('*'),
+                Token::UnaryOperator('-'),
+                Token::Number(2.0)
+            ]
+        );
+        assert_eq!(
+            tokenize("50 % -3").unwrap(),
+            vec![
+                Token::Number(50.0),
+                Token::Operator('%'),
+                Token::UnaryOperator('-'),
+                Token::Number(3.0)
+            ]
+        );
+        assert_eq!(
+            tokenize("50% - 3").unwrap(),
+            vec![
+                Token::Number(50.0),
+                Token::UnaryOperator(PERCENT),
+                Token::Operator('-'),
+                Token::Number(3.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_rpn_unary_minus() {
+        let tokens = tokenize("-2 ^ 2 * --x").unwrap();
+        assert_eq!(
+            to_rpn(&tokens).unwrap(),
+            vec![
+                Token::Number(2.0),
+                Token::Number(2.0),
+                Token::Operator('^'),
+                Token::UnaryOperator('-'),
+                Token::Variable("x".to_string()),
+                Token::UnaryOperator('-'),
+                Token::UnaryOperator('-'),
+                Token::Operator('*'),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_hex_number() {
+        assert_eq!(tokenize("0x1p4").unwrap(), vec![Token::Number(16.0)]);
+        assert_eq!(tokenize("0x1.8p1").unwrap(), vec![Token::Number(3.0)]);
+        assert_eq!(
+            tokenize("-0X1P-2").unwrap(),
+            vec![Token::UnaryOperator('-'), Token::Number(0.25)]
+        );
+        assert_eq!(tokenize("0xff").unwrap(), vec![Token::Number(255.0)]);
+
+        for malformed in ["0x", "0x1p", "0xg", "0x1.8.1p2", "0x1p+"] {
+            assert_eq!(
+                tokenize(malformed),
+                Err(Error::InvalidNumber(malformed.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_lex_number() {
+        assert_eq!(tokenize("1.").unwrap(), vec![Token::Number(1.0)]);
+        assert_eq!(tokenize(".5").unwrap(), vec![Token::Number(0.5)]);
+
+        let error = tokenize("1.2.3").unwrap_err();
+        assert_eq!(
+            error,
+            Error::InvalidNumber("1.2.3 has more than one decimal point".to_string())
+        );
+        assert_eq!(
+            error.to_string(),
+            "Invalid number: 1.2.3 has more than one decimal point"
+        );
+        assert_eq!(
+            tokenize("2 * 1..5"),
+            Err(Error::InvalidNumber(
+                "1..5 has more than one decimal point".to_string()
+            ))
+        );
+        assert_eq!(tokenize(".").unwrap_err().to_string(), "Invalid number: .");
+    }
+
+    #[test]
+    fn test_to_i64_checked() {
+        assert_eq!(to_i64_checked(42.0), Ok(42));
+        assert_eq!(to_i64_checked(-9.0), Ok(-9));
+        assert_eq!(to_i64_checked(-(2f64.powi(63))), Ok(i64::MIN));
+        // Whole numbers come back exactly, up to the largest f64 below 2^63
+        let largest = 2f64.powi(63) - 1024.0;
+        for value in [0.0, 2f64.powi(53), largest, -largest] {
+            assert_eq!(to_i64_checked(value).map(|i| i as f64), Ok(value));
+        }
+
+        // Operands a future bitwise operator would reject, e.g. `1e20 & 1`
+        // and `2.5 | 1`
+        assert_eq!(to_i64_checked(1e20), Err(Error::Overflow));
+        assert_eq!(to_i64_checked(2f64.powi(63)), Err(Error::Overflow));
+        assert_eq!(to_i64_checked(f64::NEG_INFINITY), Err(Error::Overflow));
+        assert_eq!(
+            to_i64_checked(2.5),
+            Err(Error::NotAnInteger("2.5".to_string()))
+        );
+        assert!(matches!(
+            to_i64_checked(f64::NAN),
+            Err(Error::NotAnInteger(_))
+        ));
+    }
+
+    #[test]
+    fn test_factorial() {
+        assert_eq!(factorial(0.0), Ok(1.0));
+        assert_eq!(factorial(5.0), Ok(120.0));
+        assert!(matches!(factorial(2.5), Err(Error::DomainError(_))));
+        assert!(matches!(factorial(-1.0), Err(Error::DomainError(_))));
+    }
+
+    #[test]
+    fn test_to_rpn_postfix() {
+        let tokens = tokenize("2 ^ 3! - 1").unwrap();
+        assert_eq!(
+            to_rpn(&tokens).unwrap(),
+            vec![
+                Token::Number(2.0),
+                Token::Number(3.0),
+                Token::UnaryOperator('!'),
+                Token::Operator('^'),
+                Token::Number(1.0),
+                Token::Operator('-'),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_modulo() {
+        assert_eq!(modulo(-7.0, 3.0), Ok(2.0));
+        assert_eq!(modulo(7.0, -3.0), Ok(-2.0));
+        assert_eq!(modulo(7.0, 0.0), Err(Error::DivisionByZero));
+    }
+
+    #[test]
+    fn test_token_display() {
+        let tokens = tokenize("mod(8.0, 2.5) * x").unwrap();
+        let text: Vec<String> = tokens.iter().map(Token::to_string).collect();
+        assert_eq!(text, vec!["mod", "(", "8", ",", "2.5", ")", "*", "x"]);
+    }
+
+    #[test]
+    fn test_parse_assignment() {
+        assert_eq!(
+            parse_assignment("x = 2 + 3"),
+            Ok(Some(Assignment {
+                name: "x",
+                operator: None,
+                expression: " 2 + 3"
+            }))
+        );
+        assert_eq!(
+            parse_assignment("x *= 2"),
+            Ok(Some(Assignment {
+                name: "x",
+                operator: Some('*'),
+                expression: " 2"
+            }))
+        );
+        assert_eq!(parse_assignment("2 + 3"), Ok(None));
+        assert_eq!(parse_assignment("2 + 3 # a = b"), Ok(None));
+        assert!(matches!(
+            parse_assignment("2 = 3"),
+            Err(Error::InvalidAssignment(_))
+        ));
+        assert_eq!(parse_assignment("x == 3"), Ok(None));
+        assert_eq!(parse_assignment("x <= 3"), Ok(None));
+        assert_eq!(parse_assignment("x != 3"), Ok(None));
+        assert_eq!(
+            parse_assignment("x = y >= 3"),
+            Ok(Some(Assignment {
+                name: "x",
+                operator: None,
+                expression: " y >= 3"
+            }))
+        );
+        assert!(matches!(
+            parse_assignment("x ^= 3"),
+            Err(Error::InvalidAssignment(_))
+        ));
+    }
 }
@@ -1,54 +1,66 @@
-use std::io::{self, Write};
-
-use calculator_cli::{evaluate_expression, parse_expression, print_help_doc};
-
-fn main() {
-    println!("\nWelcome to the Calculator CLI project\n");
-    print_help_doc();
-
-    loop {
-        print!("> ");
-        io::stdout().flush().unwrap();
-
-        let mut user_input = String::new();
-        let lines = std::io::stdin().read_line(&mut user_input);
-
-        match lines {
-            Ok(_) => {
-                let args: Vec<&str> = user_input.split_whitespace().collect();
-
-                if args.is_empty() {
-                    continue;
-                }
-
-                match args[0].to_lowercase().as_str() {
-                    "quit" | "q" => {
-                        println!("Goodbye!");
-                        break;
-                    }
-                    "help" => {
-                        print_help_doc();
-                    }
-                    _ => match parse_expression(args.clone()) {
-                        Ok(tokens) => match evaluate_expression(&tokens) {
-                            Ok(result) => {
-                                println!("Result: {}", result);
-                            }
-                            Err(e) => {
-                                println!("Error evaluating expression: {}", e);
-                            }
-                        },
-                        Err(e) => {
-                            eprintln!("{}", e);
-                            continue;
-                        }
-                    },
-                }
+use std::io::{self, IsTerminal};
+use std::process::ExitCode;
+
+use calculator_cli::{
+    apply_terminal_detection, default_config_path, default_history_path, default_rc_path,
+    evaluate_once, exit_code, load_config, open_output, parse_args_with, run_file, run_repl,
+    run_socket, ReplConfig, VERSION,
+};
+
+fn main() -> ExitCode {
+    let defaults = match default_config_path() {
+        Some(path) => {
+            let (config, messages) = load_config(&path);
+            for message in messages {
+                eprintln!("{}", message);
             }
+            config
+        }
+        None => ReplConfig::default(),
+    };
+
+    let mut options = match parse_args_with(std::env::args().skip(1), defaults) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    if options.version {
+        println!("{}", VERSION);
+        return ExitCode::SUCCESS;
+    }
+
+    if options.config.rc_file.is_none() {
+        options.config.rc_file = default_rc_path();
+    }
+
+    if let Some(path) = options.socket.take() {
+        return match run_socket(options.config, &path, io::stderr()) {
+            Ok(()) => ExitCode::SUCCESS,
             Err(e) => {
-                println!("Error reading input: {}", e);
-                continue;
+                eprintln!("Input/output error: {}", e);
+                ExitCode::from(2)
             }
+        };
+    }
+
+    let outcome = match (options.file.take(), options.expression.take()) {
+        (Some(path), _) => open_output(options.output.as_deref())
+            .and_then(|output| run_file(options.config, &path, output, io::stderr())),
+        (None, Some(expression)) => open_output(options.output.as_deref())
+            .and_then(|output| evaluate_once(options.config, &expression, output)),
+        (None, None) => {
+            apply_terminal_detection(&mut options, io::stdin().is_terminal());
+            options.config.history_file = default_history_path();
+            run_repl(options.config).map(|_| true)
         }
+    };
+
+    if let Err(e) = &outcome {
+        eprintln!("Input/output error: {}", e);
     }
+
+    exit_code(&outcome)
 }
@@ -0,0 +1,354 @@
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+
+use crate::Error;
+
+/// An exact fraction, always kept in lowest terms with a positive
+/// denominator so that equal values compare equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rational {
+    numer: i64,
+    denom: i64,
+}
+
+impl Rational {
+    pub fn new(numer: i64, denom: i64) -> Result<Self, Error> {
+        Self::reduce(i128::from(numer), i128::from(denom))
+    }
+
+    pub fn integer(value: i64) -> Self {
+        Self {
+            numer: value,
+            denom: 1,
+        }
+    }
+
+    pub fn numer(&self) -> i64 {
+        self.numer
+    }
+
+    pub fn denom(&self) -> i64 {
+        self.denom
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.denom == 1
+    }
+
+    /// Converts the number as it is written in decimal, so that `0.1` becomes
+    /// exactly 1/10 rather than the nearest binary fraction.
+    pub fn from_f64(value: f64) -> Result<Self, Error> {
+        if !value.is_finite() {
+            return Err(Error::NotFinite);
+        }
+
+        let text = format!("{}", value.abs());
+        let (whole, fraction) = text.split_once('.').unwrap_or((&text, ""));
+
+        let mut numer: i128 = 0;
+        let mut denom: i128 = 1;
+        for digit in whole.bytes() {
+            numer = checked(numer.checked_mul(10))?;
+            numer = checked(numer.checked_add(i128::from(digit - b'0')))?;
+        }
+        for digit in fraction.bytes() {
+            numer = checked(numer.checked_mul(10))?;
+            numer = checked(numer.checked_add(i128::from(digit - b'0')))?;
+            denom = checked(denom.checked_mul(10))?;
+        }
+
+        if value < 0.0 {
+            numer = -numer;
+        }
+        Self::reduce(numer, denom)
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.numer as f64 / self.denom as f64
+    }
+
+    /// Builds a fraction from a possibly unreduced pair, failing when the
+    /// result does not fit in `i64`.
+    fn reduce(numer: i128, denom: i128) -> Result<Self, Error> {
+        if denom == 0 {
+            return Err(Error::DivisionByZero);
+        }
+
+        let divisor = gcd(numer, denom) * denom.signum();
+        let numer = i64::try_from(numer / divisor).map_err(|_| Error::Overflow)?;
+        let denom = i64::try_from(denom / divisor).map_err(|_| Error::Overflow)?;
+        Ok(Self { numer, denom })
+    }
+
+    fn parts(self) -> (i128, i128) {
+        (i128::from(self.numer), i128::from(self.denom))
+    }
+
+    pub fn checked_add(self, other: Self) -> Result<Self, Error> {
+        let ((a, b), (c, d)) = (self.parts(), other.parts());
+        Self::reduce(a * d + c * b, b * d)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Result<Self, Error> {
+        let ((a, b), (c, d)) = (self.parts(), other.parts());
+        Self::reduce(a * d - c * b, b * d)
+    }
+
+    pub fn checked_mul(self, other: Self) -> Result<Self, Error> {
+        let ((a, b), (c, d)) = (self.parts(), other.parts());
+        Self::reduce(a * c, b * d)
+    }
+
+    pub fn checked_div(self, other: Self) -> Result<Self, Error> {
+        let ((a, b), (c, d)) = (self.parts(), other.parts());
+        Self::reduce(a * d, b * c)
+    }
+
+    /// Largest integer not greater than the fraction.
+    pub fn floor(self) -> Self {
+        Self::integer(self.numer.div_euclid(self.denom))
+    }
+
+    /// The integer part of the fraction, rounding towards zero.
+    pub fn trunc(self) -> Self {
+        Self::integer(self.numer / self.denom)
+    }
+
+    /// Raises the fraction to a whole number power. Other powers are usually
+    /// irrational, so they are rejected rather than approximated.
+    pub fn checked_pow(self, exponent: Self) -> Result<Self, Error> {
+        if !exponent.is_integer() {
+            return Err(Error::DomainError(format!(
+                "exact powers need a whole number exponent, not {}",
+                exponent
+            )));
+        }
+
+        let power = u32::try_from(exponent.numer.unsigned_abs()).map_err(|_| Error::Overflow)?;
+        let numer = checked(self.numer.checked_pow(power))?;
+        let denom = checked(self.denom.checked_pow(power))?;
+
+        if exponent.numer < 0 {
+            Self::new(denom, numer)
+        } else {
+            Self::new(numer, denom)
+        }
+    }
+}
+
+impl Display for Rational {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_integer() {
+            write!(f, "{}", self.numer)
+        } else {
+            write!(f, "{}/{}", self.numer, self.denom)
+        }
+    }
+}
+
+impl Ord for Rational {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let ((a, b), (c, d)) = (self.parts(), other.parts());
+        (a * d).cmp(&(c * b))
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn checked<T>(value: Option<T>) -> Result<T, Error> {
+    value.ok_or(Error::Overflow)
+}
+
+fn gcd(mut a: i128, mut b: i128) -> i128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a.abs()
+}
+
+/// The exact counterpart of `apply_operator`, with the same argument order.
+pub(crate) fn apply_rational_operator(
+    op: char,
+    b: Rational,
+    a: Rational,
+) -> Result<Rational, Error> {
+    match op {
+        '+' => a.checked_add(b),
+        '-' => a.checked_sub(b),
+        '*' => a.checked_mul(b),
+        '/' => a.checked_div(b),
+        '\\' => Ok(a.checked_div(b)?.floor()),
+        '%' => a.checked_sub(b.checked_mul(a.checked_div(b)?.trunc())?),
+        '^' => a.checked_pow(b),
+        '<' => Ok(truth(a < b)),
+        '>' => Ok(truth(a > b)),
+        '≤' => Ok(truth(a <= b)),
+        '≥' => Ok(truth(a >= b)),
+        '=' => Ok(truth(a == b)),
+        '≠' => Ok(truth(a != b)),
+        '&' => Ok(truth(a != ZERO && b != ZERO)),
+        '|' => Ok(truth(a != ZERO || b != ZERO)),
+        _ => Err(Error::InvalidOperator(op.to_string())),
+    }
+}
+
+pub(crate) fn apply_rational_unary_operator(op: char, a: Rational) -> Result<Rational, Error> {
+    match op {
+        '-' => ZERO.checked_sub(a),
+        '+' => Ok(a),
+        '¬' => Ok(truth(a == ZERO)),
+        '!' => rational_factorial(a),
+        crate::PERCENT => a.checked_div(Rational::integer(100)),
+        _ => Err(Error::InvalidOperator(op.to_string())),
+    }
+}
+
+const ZERO: Rational = Rational { numer: 0, denom: 1 };
+
+fn truth(condition: bool) -> Rational {
+    Rational::integer(i64::from(condition))
+}
+
+fn rational_factorial(n: Rational) -> Result<Rational, Error> {
+    if n.numer < 0 || !n.is_integer() {
+        return Err(Error::DomainError(format!(
+            "factorial is only defined for non-negative integers, not {}",
+            n
+        )));
+    }
+
+    let mut result: i64 = 1;
+    for i in 2..=n.numer {
+        result = checked(result.checked_mul(i))?;
+    }
+    Ok(Rational::integer(result))
+}
+
+/// The functions that have exact results for exact arguments.
+pub(crate) fn apply_rational_function(name: &str, args: &[Rational]) -> Result<Rational, Error> {
+    match (name, args) {
+        ("mod", [a, b]) => {
+            let remainder = apply_rational_operator('%', *b, *a)?;
+            apply_rational_operator('%', *b, remainder.checked_add(*b)?)
+        }
+        ("frac", [a, b]) if a.is_integer() && b.is_integer() => Rational::new(a.numer, b.numer),
+        ("frac", [a, b]) => Err(Error::DomainError(format!(
+            "a fraction needs whole number parts, not {} and {}",
+            a, b
+        ))),
+        ("inv", [a]) => Rational::integer(1).checked_div(*a),
+        ("sq", [a]) => a.checked_mul(*a),
+        ("neg", [a]) => ZERO.checked_sub(*a),
+        ("bool", [a]) => Ok(truth(*a != ZERO)),
+        ("mod" | "frac", _) => Err(Error::WrongArgumentCount(name.to_string(), 2, args.len())),
+        ("inv" | "sq" | "neg" | "bool", _) => {
+            Err(Error::WrongArgumentCount(name.to_string(), 1, args.len()))
+        }
+        _ => Err(Error::InvalidExpression(format!(
+            "{} has no exact result",
+            name
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fraction(numer: i64, denom: i64) -> Rational {
+        Rational::new(numer, denom).unwrap()
+    }
+
+    #[test]
+    fn test_rational_arithmetic() {
+        assert_eq!(fraction(2, -4), fraction(-1, 2));
+        assert_eq!(Rational::from_f64(0.1), Ok(fraction(1, 10)));
+        assert_eq!(Rational::from_f64(-2.5), Ok(fraction(-5, 2)));
+
+        let third = fraction(1, 3);
+        let sum = third
+            .checked_add(third)
+            .unwrap()
+            .checked_add(third)
+            .unwrap();
+        assert_eq!(sum, Rational::integer(1));
+        assert_eq!(sum.to_string(), "1");
+        assert_eq!(fraction(-2, 3).to_string(), "-2/3");
+
+        assert_eq!(
+            apply_rational_operator('^', fraction(-2, 1), fraction(2, 3)),
+            Ok(fraction(9, 4))
+        );
+        assert_eq!(
+            apply_rational_operator('%', fraction(3, 1), fraction(-7, 1)),
+            Ok(fraction(-1, 1))
+        );
+        assert_eq!(
+            apply_rational_operator('\\', fraction(2, 1), fraction(-7, 1)),
+            Ok(fraction(-4, 1))
+        );
+        assert_eq!(
+            apply_rational_function("mod", &[fraction(-7, 1), fraction(3, 1)]),
+            Ok(fraction(2, 1))
+        );
+    }
+
+    #[test]
+    fn test_lowest_terms() {
+        for (numer, denom, expected) in [
+            (2, 4, "1/2"),
+            (6, -9, "-2/3"),
+            (-6, -9, "2/3"),
+            (-10, 5, "-2"),
+            (0, -7, "0"),
+            (i64::MAX, i64::MAX, "1"),
+        ] {
+            let reduced = Rational::new(numer, denom).unwrap();
+            assert_eq!(reduced.to_string(), expected);
+            assert!(reduced.denom() > 0);
+        }
+
+        let mut calculator = crate::Calculator::new();
+        assert_eq!(calculator.eval_exact("2/4 + 0").unwrap().to_string(), "1/2");
+        assert_eq!(
+            calculator.eval_exact("3 / -12").unwrap().to_string(),
+            "-1/4"
+        );
+        assert_eq!(
+            calculator
+                .eval_exact("frac(10, 4) * 2")
+                .unwrap()
+                .to_string(),
+            "5"
+        );
+    }
+
+    #[test]
+    fn test_rational_errors() {
+        assert_eq!(Rational::new(1, 0), Err(Error::DivisionByZero));
+        assert_eq!(
+            apply_rational_operator('/', Rational::integer(0), Rational::integer(1)),
+            Err(Error::DivisionByZero)
+        );
+        assert_eq!(
+            apply_rational_operator('^', fraction(-1, 1), Rational::integer(0)),
+            Err(Error::DivisionByZero)
+        );
+        assert!(matches!(
+            apply_rational_operator('^', fraction(1, 2), Rational::integer(2)),
+            Err(Error::DomainError(_))
+        ));
+        assert_eq!(
+            apply_rational_operator('^', Rational::integer(100), Rational::integer(10)),
+            Err(Error::Overflow)
+        );
+        assert_eq!(
+            rational_factorial(Rational::integer(21)),
+            Err(Error::Overflow)
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+use crate::{Error, FUNCTIONS};
+
+type FunctionBody = dyn Fn(&[f64]) -> Result<f64, Error> + Send + Sync;
+
+#[derive(Clone)]
+struct RegisteredFunction {
+    arity: usize,
+    apply: Arc<FunctionBody>,
+}
+
+/// The functions that can be called in expressions, by name. It starts with
+/// the built-in functions, and library users can register their own.
+#[derive(Clone)]
+pub struct FunctionRegistry {
+    functions: HashMap<String, RegisteredFunction>,
+}
+
+impl FunctionRegistry {
+    pub fn new() -> Self {
+        let mut registry = Self {
+            functions: HashMap::new(),
+        };
+        for function in FUNCTIONS {
+            registry.register(function.name, function.arity, function.apply);
+        }
+        registry
+    }
+
+    /// Adds a function taking exactly `arity` arguments, replacing any
+    /// function already registered with that name.
+    pub fn register<F>(&mut self, name: &str, arity: usize, apply: F)
+    where
+        F: Fn(&[f64]) -> Result<f64, Error> + Send + Sync + 'static,
+    {
+        let function = RegisteredFunction {
+            arity,
+            apply: Arc::new(apply),
+        };
+        self.functions.insert(name.to_string(), function);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    /// Registered function names, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.functions.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn call(&self, name: &str, args: &[f64]) -> Result<f64, Error> {
+        let function = self
+            .functions
+            .get(name)
+            .ok_or_else(|| Error::UnknownFunction(name.to_string()))?;
+
+        if args.len() != function.arity {
+            return Err(Error::WrongArgumentCount(
+                name.to_string(),
+                function.arity,
+                args.len(),
+            ));
+        }
+
+        (function.apply)(args)
+    }
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.names()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let mut registry = FunctionRegistry::new();
+        assert!(registry.contains("sqrt"));
+        assert_eq!(registry.call("sq", &[3.0]), Ok(9.0));
+
+        registry.register("double", 1, |args| Ok(args[0] * 2.0));
+        assert_eq!(registry.call("double", &[21.0]), Ok(42.0));
+        assert_eq!(
+            registry.call("double", &[1.0, 2.0]),
+            Err(Error::WrongArgumentCount("double".to_string(), 1, 2))
+        );
+        assert_eq!(
+            registry.call("triple", &[1.0]),
+            Err(Error::UnknownFunction("triple".to_string()))
+        );
+    }
+}
@@ -0,0 +1,1900 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    describe_error, explain_expression, format_as, format_bases, help_topic, is_boolean_expression,
+    parse_radix, precedence_notes, preprocess_input, strip_comment, token_stats, AngleMode,
+    Calculator, Error, NumberFormat, RoundingMode, Token, Value, FORMAT_PRESETS, HELP_DOC,
+    HELP_TOPICS,
+};
+
+/// Options controlling how the interactive calculator presents itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplConfig {
+    /// Suppress the welcome banner and help shown at startup.
+    pub quiet: bool,
+    pub prompt: String,
+    /// Print results without the `Result: ` before them.
+    pub bare_output: bool,
+    /// Print each evaluated line as a JSON object on its own line, with its
+    /// result or error and when it was evaluated.
+    pub json_lines: bool,
+    /// How results are printed.
+    pub format: NumberFormat,
+    /// Base results are printed in: 10, or 16, 8 or 2 for whole numbers.
+    pub base: u32,
+    /// Read `3,14` as 3.14, with `;` separating function arguments.
+    pub decimal_comma: bool,
+    /// Reject implicit multiplication and `%` as a percentage.
+    pub strict: bool,
+    /// Evaluate with exact fractions instead of floating point.
+    pub exact: bool,
+    /// Carry NaN and infinite values through expressions instead of failing.
+    pub propagate_nan: bool,
+    /// Make physical constants such as `c` available.
+    pub physics: bool,
+    /// Whether trigonometric functions work in degrees or radians.
+    pub angle_mode: AngleMode,
+    /// Print the results of comparisons and logic as `true` or `false`.
+    pub bool_display: bool,
+    /// Add each result to a running total, printed after the result.
+    pub accumulate: bool,
+    /// Print the last result again when an empty line is entered.
+    pub empty_repeats: bool,
+    /// Round the result of every operation to `format.precision`, not just
+    /// what is printed.
+    pub round_intermediate: bool,
+    /// Add up sums with compensated summation.
+    pub kahan: bool,
+    /// How long evaluating one line may take, if limited.
+    pub timeout: Option<Duration>,
+    /// Seed for `rand()`, so that a session can be replayed exactly.
+    pub seed: Option<u64>,
+    /// Before the result of an ambiguous expression, print what each way of
+    /// reading it gives.
+    pub ambiguous_report: bool,
+    /// After a result, note where operator precedence changed the order
+    /// operations were applied in.
+    pub explain_precedence: bool,
+    /// Print the number and operator counts after each result.
+    pub show_stats: bool,
+    /// File of `name = expr` definitions to load before the first prompt.
+    pub rc_file: Option<PathBuf>,
+    /// File that entered expressions are loaded from and appended to.
+    pub history_file: Option<PathBuf>,
+    /// Expressions evaluated before the first prompt, e.g. to set variables.
+    pub startup: Vec<String>,
+}
+
+impl Default for ReplConfig {
+    fn default() -> Self {
+        Self {
+            quiet: false,
+            prompt: "> ".to_string(),
+            bare_output: false,
+            json_lines: false,
+            format: NumberFormat::default(),
+            base: 10,
+            decimal_comma: false,
+            strict: false,
+            exact: false,
+            propagate_nan: false,
+            physics: false,
+            angle_mode: AngleMode::default(),
+            bool_display: false,
+            accumulate: false,
+            empty_repeats: false,
+            round_intermediate: false,
+            kahan: false,
+            timeout: None,
+            seed: None,
+            ambiguous_report: false,
+            explain_precedence: false,
+            show_stats: false,
+            rc_file: None,
+            history_file: None,
+            startup: Vec::new(),
+        }
+    }
+}
+
+/// Returns `~/.calcrc` if it exists.
+pub fn default_rc_path() -> Option<PathBuf> {
+    let path = PathBuf::from(std::env::var_os("HOME")?).join(".calcrc");
+    path.exists().then_some(path)
+}
+
+/// Returns `$XDG_CONFIG_HOME/calc/config`, or `~/.config/calc/config`
+/// without that variable, if it exists.
+pub fn default_config_path() -> Option<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    let path = config_home.join("calc").join("config");
+    path.exists().then_some(path)
+}
+
+/// Returns `~/.calc_history`, whether or not it exists yet.
+pub fn default_history_path() -> Option<PathBuf> {
+    Some(PathBuf::from(std::env::var_os("HOME")?).join(".calc_history"))
+}
+
+/// Reads the saved history, one entry per line. A missing file is an empty
+/// history.
+pub fn load_history(path: &Path) -> io::Result<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.lines().map(str::to_string).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn append_history(path: &Path, entry: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", entry)
+}
+
+/// Opens where results should go: a buffered file if `path` is given,
+/// otherwise stdout. An existing file is overwritten.
+pub fn open_output(path: Option<&Path>) -> io::Result<Box<dyn Write>> {
+    match path {
+        Some(path) => match fs::File::create(path) {
+            Ok(file) => Ok(Box::new(BufWriter::new(file))),
+            Err(e) => Err(io::Error::new(
+                e.kind(),
+                format!("could not create {}: {}", path.display(), e),
+            )),
+        },
+        None => Ok(Box::new(io::stdout())),
+    }
+}
+
+/// Runs the interactive calculator on stdin and stdout until `quit` or EOF.
+pub fn run_repl(config: ReplConfig) -> io::Result<()> {
+    let stdin = io::stdin();
+    Repl::new(config, io::stdout(), io::stderr()).run(stdin.lock())
+}
+
+/// Evaluates a single line, as when an expression is given on the command
+/// line, writing the result to `output` and errors to stderr. Returns whether
+/// it succeeded.
+pub fn evaluate_once<O: Write>(config: ReplConfig, line: &str, output: O) -> io::Result<bool> {
+    let mut repl = Repl::new(config, output, io::stderr());
+    let succeeded = repl.run_once(line)?;
+    repl.into_output().0.flush()?;
+    Ok(succeeded)
+}
+
+/// Runs each line of a file as if it had been typed in, without prompts,
+/// writing results to `output` and errors to `errors`. Returns whether every
+/// line succeeded.
+pub fn run_file<O: Write, E: Write>(
+    config: ReplConfig,
+    path: &Path,
+    output: O,
+    errors: E,
+) -> io::Result<bool> {
+    let file = BufReader::new(fs::File::open(path)?);
+    let mut repl = Repl::new(config, output, errors);
+    let succeeded = repl.run_batch(file)?;
+    let (mut output, mut errors) = repl.into_output();
+    output.flush()?;
+    errors.flush()?;
+    Ok(succeeded)
+}
+
+/// Listens on a Unix domain socket at `path`, answering the lines sent over
+/// each connection as if they had been piped in: results and errors are
+/// written back line by line. Connections are served one at a time, each with
+/// its own variables, until the process is stopped. Connections do not load
+/// the rc file or run the startup expressions. A connection that cannot be
+/// accepted or fails is reported to `errors` and the next one is served.
+///
+/// A socket left at `path` by an earlier run is replaced; any other file
+/// there is an error.
+#[cfg(unix)]
+pub fn run_socket<E: Write>(config: ReplConfig, path: &Path, mut errors: E) -> io::Result<()> {
+    let listener = bind_socket(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("could not listen on {}: {}", path.display(), e),
+        )
+    })?;
+    for stream in listener.incoming() {
+        if let Err(e) = stream.and_then(|stream| serve_connection(config.clone(), stream)) {
+            writeln!(errors, "Connection error: {}", e)?;
+        }
+    }
+    Ok(())
+}
+
+/// Binds a listener at `path`, first removing a stale socket there.
+#[cfg(unix)]
+fn bind_socket(path: &Path) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    std::os::unix::net::UnixListener::bind(path)
+}
+
+/// Sockets need a Unix platform.
+#[cfg(not(unix))]
+pub fn run_socket<E: Write>(_config: ReplConfig, path: &Path, _errors: E) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "cannot listen on {}: sockets need a Unix platform",
+            path.display()
+        ),
+    ))
+}
+
+/// Answers one connection until it closes or sends `quit`.
+#[cfg(unix)]
+fn serve_connection(
+    mut config: ReplConfig,
+    stream: std::os::unix::net::UnixStream,
+) -> io::Result<()> {
+    config.quiet = true;
+    config.prompt.clear();
+    config.history_file = None;
+    let input = BufReader::new(stream.try_clone()?);
+    Repl::new(config, stream.try_clone()?, stream).serve(input)
+}
+
+/// Reads a base that results can be shown in: 2, 8, 10 or 16.
+pub(crate) fn parse_base(text: &str) -> Option<u32> {
+    text.parse()
+        .ok()
+        .filter(|base| matches!(base, 2 | 8 | 10 | 16))
+}
+
+/// The `as` target that shows results in `base`, or `None` for decimal.
+fn base_target(base: u32) -> Option<&'static str> {
+    match base {
+        16 => Some("hex"),
+        8 => Some("octal"),
+        2 => Some("binary"),
+        _ => None,
+    }
+}
+
+/// Whether a line is a command rather than an expression. `base` only is
+/// with a single argument, so that `base` can still be a variable.
+fn is_command(words: &[&str]) -> bool {
+    match words.first().map(|word| word.to_lowercase()).as_deref() {
+        Some("base") => words.len() == 2,
+        Some(word) => COMMANDS.contains(&word),
+        None => false,
+    }
+}
+
+/// The words that start a command rather than an expression.
+const COMMANDS: &[&str] = &[
+    "quit",
+    "q",
+    "help",
+    "mode",
+    "rounding",
+    "exact",
+    "empty-repeats",
+    "kahan",
+    "round-intermediate",
+    "accumulate",
+    "arg-separator",
+    "bool-display",
+    "precision?",
+    "reset-precision",
+    "format",
+    "mode?",
+    "base?",
+    "m+",
+    "m-",
+    "mc",
+    "store",
+    "recall",
+    "swap",
+    "seed",
+    "tree",
+    "explain",
+    "bases",
+    "report",
+    "fix",
+    "convert",
+    "def",
+    "begin",
+    "end",
+    "again",
+    "stats",
+    "history",
+];
+
+/// A JSON object such as `{"input":"2 + 3","result":"5","timestamp":1700000000.000}`,
+/// with the result as it would be displayed, or `"error"` with the message
+/// instead. The timestamp is in seconds since the Unix epoch.
+fn json_line(input: &str, outcome: &Result<String, String>, timestamp: Duration) -> String {
+    let (key, value) = match outcome {
+        Ok(result) => ("result", result),
+        Err(message) => ("error", message),
+    };
+    format!(
+        "{{\"input\":{},\"{}\":{},\"timestamp\":{}.{:03}}}",
+        json_string(input),
+        key,
+        json_string(value),
+        timestamp.as_secs(),
+        timestamp.subsec_millis()
+    )
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Shows the 1 and 0 that comparisons give as `true` and `false`.
+fn format_truth(value: &Value) -> String {
+    let word = |value: f64| if value != 0.0 { "true" } else { "false" };
+    match value {
+        Value::Scalar(value) => word(*value).to_string(),
+        Value::Duration(_) | Value::Quantity(..) => value.to_string(),
+        Value::Vector(values) => {
+            let words: Vec<&str> = values.iter().map(|value| word(*value)).collect();
+            format!("[{}]", words.join(", "))
+        }
+    }
+}
+
+/// The calculator's command loop, generic over its input and output so that
+/// it can be driven by scripted input.
+pub struct Repl<O: Write, E: Write> {
+    config: ReplConfig,
+    calculator: Calculator,
+    history: Vec<String>,
+    /// The last expression entered this session, unlike the history, which
+    /// also holds earlier sessions'.
+    last_input: Option<String>,
+    /// Every number produced this session, for `stats`.
+    results: Vec<f64>,
+    /// Lines collected since `begin`, to be run when `end` is entered.
+    block: Option<Vec<String>>,
+    failures: usize,
+    /// Failure messages held back while running a batch, to be reported
+    /// together at the end instead of between the results.
+    deferred_errors: Option<Vec<String>>,
+    output: O,
+    errors: E,
+}
+
+impl<O: Write, E: Write> Repl<O, E> {
+    pub fn new(config: ReplConfig, output: O, errors: E) -> Self {
+        let mut calculator = Calculator::new();
+        calculator.set_decimal_comma(config.decimal_comma);
+        calculator.set_strict(config.strict);
+        calculator.set_propagate_nan(config.propagate_nan);
+        calculator.set_physics(config.physics);
+        calculator.set_angle_mode(config.angle_mode);
+        calculator.set_timeout(config.timeout);
+        calculator.set_kahan(config.kahan);
+        if let Some(seed) = config.seed {
+            calculator.seed(seed);
+        }
+
+        Self {
+            config,
+            calculator,
+            history: Vec::new(),
+            last_input: None,
+            results: Vec::new(),
+            block: None,
+            failures: 0,
+            deferred_errors: None,
+            output,
+            errors,
+        }
+    }
+
+    pub fn calculator(&self) -> &Calculator {
+        &self.calculator
+    }
+
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// The numeric results produced so far, oldest first. Vectors are left
+    /// out, as are previews.
+    pub fn results(&self) -> &[f64] {
+        &self.results
+    }
+
+    /// The text of the last expression entered this session, as `again`
+    /// runs it.
+    pub fn last_input(&self) -> Option<&str> {
+        self.last_input.as_deref()
+    }
+
+    /// Number of lines whose evaluation failed so far.
+    pub fn failures(&self) -> usize {
+        self.failures
+    }
+
+    pub fn into_output(self) -> (O, E) {
+        (self.output, self.errors)
+    }
+
+    /// Answers each line of `input` until it ends or sends `quit`, without
+    /// loading the rc file or history, running the startup expressions or
+    /// showing the banner and prompt.
+    pub fn serve<R: BufRead>(&mut self, input: R) -> io::Result<()> {
+        for line in input.lines() {
+            if !self.handle_line(&line?)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn run<R: BufRead>(&mut self, input: R) -> io::Result<()> {
+        self.load_rc_file()?;
+        self.load_history_file()?;
+        self.run_startup()?;
+
+        if !self.config.quiet {
+            writeln!(self.output, "\nWelcome to the Calculator CLI project\n")?;
+            write!(self.output, "{}", HELP_DOC)?;
+        }
+
+        let mut lines = input.lines();
+        loop {
+            write!(self.output, "{}", self.config.prompt)?;
+            self.output.flush()?;
+
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+
+            if !self.handle_line(&line)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles every line of `input` in order, without a banner or prompts,
+    /// so that a script can build variables up line by line. Failures are
+    /// collected and reported after the last line, with their line numbers
+    /// and a count such as `2 of 10 lines failed`. Returns whether every line
+    /// succeeded.
+    pub fn run_batch<R: BufRead>(&mut self, input: R) -> io::Result<bool> {
+        self.load_rc_file()?;
+        self.run_startup()?;
+
+        let mut lines_run = 0;
+        let mut failed_lines = 0;
+        let mut messages = Vec::new();
+        for (index, line) in input.lines().enumerate() {
+            let line = line?;
+            // Blank lines and lines with only a comment are skipped silently
+            if strip_comment(&line).trim().is_empty() {
+                continue;
+            }
+            lines_run += 1;
+
+            self.deferred_errors = Some(Vec::new());
+            let keep_going = self.handle_line(&line)?;
+            let failures = self.deferred_errors.take().unwrap_or_default();
+            if !failures.is_empty() {
+                failed_lines += 1;
+            }
+            messages.extend(
+                failures
+                    .into_iter()
+                    .map(|message| format!("line {}: {}", index + 1, message)),
+            );
+
+            if !keep_going {
+                break;
+            }
+        }
+
+        for message in &messages {
+            writeln!(self.errors, "{}", message)?;
+        }
+        if failed_lines > 0 {
+            writeln!(
+                self.errors,
+                "{} of {} lines failed",
+                failed_lines, lines_run
+            )?;
+        }
+
+        Ok(self.failures == 0)
+    }
+
+    /// Loads the rc file and handles a single line without prompting.
+    /// Returns whether the line was handled without errors.
+    pub fn run_once(&mut self, line: &str) -> io::Result<bool> {
+        self.load_rc_file()?;
+        self.run_startup()?;
+        self.handle_line(line)?;
+        Ok(self.failures == 0)
+    }
+
+    /// Handles one line of input. Returns `false` when the user asked to quit.
+    pub fn handle_line(&mut self, line: &str) -> io::Result<bool> {
+        if let Some(block) = &mut self.block {
+            if !line.trim().eq_ignore_ascii_case("end") {
+                if !line.trim().is_empty() {
+                    block.push(line.to_string());
+                }
+                return Ok(true);
+            }
+            return self.run_block();
+        }
+
+        if self.config.json_lines {
+            return self.handle_json_line(line);
+        }
+
+        let args: Vec<&str> = line.split_whitespace().collect();
+
+        if args.is_empty() {
+            if self.config.empty_repeats {
+                self.write_last_result()?;
+            }
+            return Ok(true);
+        }
+
+        if let Some(expression) = line.trim_start().strip_prefix('?') {
+            self.preview(expression)?;
+            return Ok(true);
+        }
+
+        match args[0].to_lowercase().as_str() {
+            "quit" | "q" => {
+                writeln!(self.output, "Goodbye!")?;
+                return Ok(false);
+            }
+            "help" => match args.get(1) {
+                None => write!(self.output, "{}", HELP_DOC)?,
+                Some(topic) => match help_topic(topic) {
+                    Some(text) => write!(self.output, "{}", text)?,
+                    None => {
+                        let topics: Vec<&str> = HELP_TOPICS.iter().map(|(name, _)| *name).collect();
+                        writeln!(self.errors, "Usage: help [{}]", topics.join("|"))?;
+                    }
+                },
+            },
+            "mode" => match args.get(1).map(|mode| mode.to_lowercase()).as_deref() {
+                Some("deg" | "degrees") => self.calculator.set_angle_mode(AngleMode::Degrees),
+                Some("rad" | "radians") => self.calculator.set_angle_mode(AngleMode::Radians),
+                _ => writeln!(self.errors, "Usage: mode deg|rad")?,
+            },
+            "rounding" => match args.get(1).map(|mode| mode.to_lowercase()).as_deref() {
+                Some("half-up") => self.config.format.rounding = RoundingMode::HalfUp,
+                Some("half-even") => self.config.format.rounding = RoundingMode::HalfEven,
+                Some("truncate") => self.config.format.rounding = RoundingMode::Truncate,
+                _ => writeln!(self.errors, "Usage: rounding half-up|half-even|truncate")?,
+            },
+            "exact" => match args.get(1).map(|mode| mode.to_lowercase()).as_deref() {
+                Some("on") => self.config.exact = true,
+                Some("off") => self.config.exact = false,
+                _ => writeln!(self.errors, "Usage: exact on|off")?,
+            },
+            "empty-repeats" => match args.get(1).map(|mode| mode.to_lowercase()).as_deref() {
+                Some("on") => self.config.empty_repeats = true,
+                Some("off") => self.config.empty_repeats = false,
+                _ => writeln!(self.errors, "Usage: empty-repeats on|off")?,
+            },
+            "kahan" => match args.get(1).map(|mode| mode.to_lowercase()).as_deref() {
+                Some("on") => self.config.kahan = true,
+                Some("off") => self.config.kahan = false,
+                _ => writeln!(self.errors, "Usage: kahan on|off")?,
+            },
+            "round-intermediate" => match args.get(1).map(|mode| mode.to_lowercase()).as_deref() {
+                Some("on") => self.config.round_intermediate = true,
+                Some("off") => self.config.round_intermediate = false,
+                _ => writeln!(self.errors, "Usage: round-intermediate on|off")?,
+            },
+            "accumulate" => match args.get(1).map(|mode| mode.to_lowercase()).as_deref() {
+                Some("on") => self.config.accumulate = true,
+                Some("off") => self.config.accumulate = false,
+                Some("reset") => self.calculator.reset_total(),
+                _ => writeln!(self.errors, "Usage: accumulate on|off|reset")?,
+            },
+            "arg-separator" => match args.get(1).and_then(|arg| arg.parse::<char>().ok()) {
+                Some(separator) if self.calculator.set_arg_separator(separator).is_ok() => {}
+                _ => writeln!(self.errors, "Usage: arg-separator ,|;")?,
+            },
+            "bool-display" => match args.get(1).map(|mode| mode.to_lowercase()).as_deref() {
+                Some("on") => self.config.bool_display = true,
+                Some("off") => self.config.bool_display = false,
+                _ => writeln!(self.errors, "Usage: bool-display on|off")?,
+            },
+            "precision?" => match self.config.format.precision {
+                Some(precision) if self.config.format.trim_zeros => writeln!(
+                    self.output,
+                    "precision: {}, trailing zeros trimmed",
+                    precision
+                )?,
+                Some(precision) => writeln!(self.output, "precision: {}", precision)?,
+                None => writeln!(self.output, "precision: not set")?,
+            },
+            "reset-precision" => self.config.format.precision = None,
+            "format" => match args.get(1).and_then(|name| NumberFormat::preset(name)) {
+                Some(format) => self.config.format = format,
+                None => {
+                    let presets: Vec<&str> = FORMAT_PRESETS.iter().map(|(name, _)| *name).collect();
+                    writeln!(self.errors, "Usage: format {}", presets.join("|"))?;
+                }
+            },
+            "mode?" => writeln!(self.output, "mode: {}", self.calculator.angle_mode())?,
+            "base?" => writeln!(self.output, "base: {}", self.config.base)?,
+            "base" if args.len() == 2 => match parse_base(args[1]) {
+                Some(base) => self.config.base = base,
+                None => writeln!(self.errors, "Usage: base 2|8|10|16")?,
+            },
+            "m+" => self.calculator.memory_add(),
+            "m-" => self.calculator.memory_subtract(),
+            "mc" => self.calculator.memory_clear(),
+            "store" => self.calculator.memory_store(),
+            "recall" => {
+                self.calculator.memory_recall();
+                self.write_last_result()?;
+            }
+            "swap" => {
+                self.calculator.memory_swap();
+                self.write_last_result()?;
+            }
+            "seed" => match args.get(1).and_then(|seed| seed.parse().ok()) {
+                Some(seed) => self.calculator.seed(seed),
+                None => writeln!(self.errors, "Usage: seed <non-negative integer>")?,
+            },
+            "tree" => {
+                let expression = line.trim_start()["tree".len()..].trim();
+                match self.calculator.parse_ast(expression) {
+                    Ok(expr) => {
+                        for line in expr.tree() {
+                            writeln!(self.output, "{}", line)?;
+                        }
+                    }
+                    Err(e) => writeln!(self.errors, "{}", e)?,
+                }
+            }
+            "explain" => {
+                let expression = line.trim_start()["explain".len()..].trim();
+                match self
+                    .calculator
+                    .parse(expression)
+                    .and_then(|tokens| explain_expression(&tokens))
+                {
+                    Ok(steps) if steps.is_empty() => {
+                        writeln!(self.output, "There is nothing to compute")?;
+                    }
+                    Ok(steps) => {
+                        for step in steps {
+                            writeln!(self.output, "{}", step)?;
+                        }
+                    }
+                    Err(e) => writeln!(self.errors, "{}", e)?,
+                }
+            }
+            "bases" => {
+                let expression = line.trim_start()["bases".len()..].trim();
+                match self.calculator.eval(expression) {
+                    Ok(value) => {
+                        for line in format_bases(value) {
+                            writeln!(self.output, "{}", line)?;
+                        }
+                    }
+                    Err(e) => {
+                        self.report_failure(format!("Error evaluating expression: {}", e), &e)?
+                    }
+                }
+            }
+            "report" => {
+                for (name, value) in self.calculator.variables() {
+                    writeln!(
+                        self.output,
+                        "{} = {}",
+                        name,
+                        self.config.format.format(value)
+                    )?;
+                }
+            }
+            "fix" => match (args.get(1), args.get(2), self.history.last()) {
+                (Some(search), Some(replace), Some(last)) if last.contains(search) => {
+                    let fixed = last.replacen(search, replace, 1);
+                    writeln!(self.output, "{}", fixed)?;
+                    self.record_history(&fixed)?;
+                    self.evaluate(&fixed)?;
+                }
+                (Some(search), Some(_), Some(_)) => {
+                    writeln!(
+                        self.errors,
+                        "The last expression does not contain {}",
+                        search
+                    )?;
+                }
+                (Some(_), Some(_), None) => writeln!(self.errors, "There is no expression to fix")?,
+                _ => writeln!(self.errors, "Usage: fix <search> <replace>")?,
+            },
+            "convert" => match args[1..] {
+                [number, to, base] if to.eq_ignore_ascii_case("to") => {
+                    match parse_radix(number).and_then(|value| format_as(value, base, None)) {
+                        Ok(converted) => writeln!(self.output, "{}", converted)?,
+                        Err(e) => self.report_failure(e.to_string(), &e)?,
+                    }
+                }
+                _ => writeln!(
+                    self.errors,
+                    "Usage: convert <number> to hex|octal|binary|dec"
+                )?,
+            },
+            "def" => {
+                let definition = line.trim_start()["def".len()..].trim();
+                if let Err(e) = self.calculator.define_function(definition) {
+                    self.report_failure(e.to_string(), &e)?;
+                }
+            }
+            "begin" => self.block = Some(Vec::new()),
+            "end" => writeln!(
+                self.errors,
+                "There is no block to end; start one with begin"
+            )?,
+            "again" => match self.last_input().map(str::to_string) {
+                Some(last) => self.evaluate(&last)?,
+                None => writeln!(self.errors, "There is no expression to run again")?,
+            },
+            "stats" => match summarize(&self.results) {
+                Some(summary) => {
+                    let format = &self.config.format;
+                    writeln!(self.output, "Count: {}", summary.count)?;
+                    writeln!(self.output, "Sum: {}", format.format(summary.sum))?;
+                    writeln!(self.output, "Mean: {}", format.format(summary.mean))?;
+                    writeln!(self.output, "Min: {}", format.format(summary.min))?;
+                    writeln!(self.output, "Max: {}", format.format(summary.max))?;
+                }
+                None => writeln!(self.errors, "There are no results yet")?,
+            },
+            "history" => {
+                for (number, entry) in self.history.iter().enumerate() {
+                    writeln!(self.output, "{}: {}", number + 1, entry)?;
+                }
+            }
+            _ => {
+                // A line that is only a comment leaves nothing to run
+                let line = preprocess_input(line);
+                if !line.is_empty() {
+                    self.record_history(&line)?;
+                    self.evaluate(&line)?;
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Handles a line in json-lines mode, where every answer is a JSON object:
+    /// commands, which answer in plain text, are refused, blank and
+    /// comment-only lines are skipped, and `quit` ends the session quietly.
+    fn handle_json_line(&mut self, line: &str) -> io::Result<bool> {
+        let line = preprocess_input(line);
+        let words: Vec<&str> = line.split(' ').collect();
+        if line.is_empty() {
+            return Ok(true);
+        }
+        if matches!(words[0].to_lowercase().as_str(), "quit" | "q") {
+            return Ok(false);
+        }
+        if line.starts_with('?') || is_command(&words) {
+            let refusal = "commands are not available in json-lines mode".to_string();
+            self.write_json_outcome(&line, Err(refusal))?;
+            return Ok(true);
+        }
+
+        self.record_history(&line)?;
+        self.write_json_line(&line)?;
+        Ok(true)
+    }
+
+    /// Runs the lines collected since `begin` in order, as if each had been
+    /// entered on its own, so later lines see variables set by earlier ones.
+    fn run_block(&mut self) -> io::Result<bool> {
+        for line in self.block.take().unwrap_or_default() {
+            if !self.handle_line(&line)? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn evaluate(&mut self, line: &str) -> io::Result<()> {
+        if self.config.json_lines {
+            return self.write_json_line(line);
+        }
+        if let Some((expression, target)) = split_format_clause(line) {
+            return self.evaluate_as(expression, target);
+        }
+
+        match self.calculator.parse(line) {
+            Ok(tokens) => {
+                if self.config.ambiguous_report {
+                    self.report_readings(&tokens)?;
+                }
+                match self.eval_to_string(line) {
+                    Ok(result) => {
+                        let notes = if self.config.explain_precedence {
+                            precedence_notes(&tokens)
+                        } else {
+                            Vec::new()
+                        };
+                        if notes.is_empty() {
+                            self.write_result(&result)?;
+                        } else {
+                            self.write_result(&format!("{} ({})", result, notes.join("; ")))?;
+                        }
+                        if self.config.show_stats {
+                            writeln!(self.output, "{}", token_stats(&tokens))?;
+                        }
+                    }
+                    Err(e) => {
+                        self.report_failure(format!("Error evaluating expression: {}", e), &e)?
+                    }
+                }
+            }
+            Err(e) => self.report_failure(e.to_string(), &e)?,
+        }
+
+        Ok(())
+    }
+
+    /// Evaluates an expression and prints its result formatted as `target`,
+    /// e.g. `hex`, without changing how other results are shown.
+    fn evaluate_as(&mut self, expression: &str, target: &str) -> io::Result<()> {
+        match self.eval_to_string_as(expression, Some(target)) {
+            Ok(text) => self.write_result(&text),
+            Err(e) => self.report_failure(format!("Error evaluating expression: {}", e), &e),
+        }
+    }
+
+    /// Shows what an expression gives without changing the session: the last
+    /// result, variables and history stay as they were.
+    fn preview(&mut self, expression: &str) -> io::Result<()> {
+        let saved = self.calculator.clone();
+        let result_count = self.results.len();
+        let outcome = self.eval_to_string(expression);
+        self.calculator = saved;
+        self.results.truncate(result_count);
+        match outcome {
+            Ok(result) => writeln!(self.output, "Preview: {}", result),
+            Err(e) => self.report_failure(format!("Error evaluating expression: {}", e), &e),
+        }
+    }
+
+    /// Evaluates a line and prints the outcome as a JSON object, flushed
+    /// straight away so that a reader gets it before the next line arrives.
+    fn write_json_line(&mut self, line: &str) -> io::Result<()> {
+        let outcome = self.eval_to_string(line).map_err(|e| e.to_string());
+        self.write_json_outcome(line, outcome)
+    }
+
+    fn write_json_outcome(
+        &mut self,
+        line: &str,
+        outcome: Result<String, String>,
+    ) -> io::Result<()> {
+        if outcome.is_err() {
+            self.failures += 1;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        writeln!(
+            self.output,
+            "{}",
+            json_line(line.trim(), &outcome, timestamp)
+        )?;
+        self.output.flush()
+    }
+
+    /// Prints what each reading of an ambiguous expression gives. Readings
+    /// are evaluated on a copy of the calculator, so only the result printed
+    /// after them changes its state.
+    fn report_readings(&mut self, tokens: &[Token]) -> io::Result<()> {
+        for (i, reading) in self.calculator.readings(tokens).iter().enumerate() {
+            let mut calculator = self.calculator.clone();
+            let result = if self.config.exact {
+                calculator
+                    .evaluate_exact(&reading.tokens)
+                    .map(|result| result.to_string())
+            } else {
+                calculator
+                    .evaluate_value(&reading.tokens)
+                    .map(|result| self.config.format.format_value(&result))
+            };
+            let result = result.unwrap_or_else(|e| format!("error: {}", e));
+            writeln!(
+                self.output,
+                "Reading {}, {}: {}",
+                i + 1,
+                reading.description,
+                result
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Prints a result on its own line, after `Result: ` unless output is
+    /// bare.
+    fn write_result(&mut self, result: &str) -> io::Result<()> {
+        if self.config.bare_output {
+            writeln!(self.output, "{}", result)
+        } else {
+            writeln!(self.output, "Result: {}", result)
+        }
+    }
+
+    fn write_last_result(&mut self) -> io::Result<()> {
+        let last = self.config.format.format(self.calculator.last_result());
+        self.write_result(&last)
+    }
+
+    /// Counts a failed line and reports why, unless a batch run is holding
+    /// failures back until it finishes. Interactive sessions also get a hint
+    /// on how to fix the error.
+    fn report_failure(&mut self, message: String, error: &Error) -> io::Result<()> {
+        self.failures += 1;
+        match &mut self.deferred_errors {
+            Some(deferred) => deferred.push(message),
+            None => {
+                writeln!(self.errors, "{}", message)?;
+                if !self.config.quiet {
+                    writeln!(self.errors, "Hint: {}", describe_error(error))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Passes the summation setting, and the precision and rounding mode for
+    /// intermediate results, on to the calculator, as commands may have
+    /// changed them since the last line.
+    fn sync_settings(&mut self) {
+        self.calculator.set_kahan(self.config.kahan);
+        let format = self.config.format;
+        let rounding = format
+            .precision
+            .filter(|_| self.config.round_intermediate)
+            .map(|precision| (precision, format.rounding));
+        self.calculator.set_intermediate_rounding(rounding);
+    }
+
+    fn eval_to_string(&mut self, line: &str) -> Result<String, Error> {
+        self.eval_to_string_as(line, None)
+    }
+
+    /// Like `eval_to_string`, but formatting the result as `target` (see
+    /// `format_as`) when one is given, instead of in the current base.
+    fn eval_to_string_as(&mut self, line: &str, target: Option<&str>) -> Result<String, Error> {
+        self.sync_settings();
+        let is_boolean = self.config.bool_display
+            && target.is_none()
+            && self
+                .calculator
+                .parse(line)
+                .is_ok_and(|tokens| is_boolean_expression(&tokens));
+
+        let target = target.or(base_target(self.config.base));
+        let (text, is_scalar) = if self.config.exact {
+            let result = self.calculator.eval_exact(line)?;
+            if is_boolean {
+                (format_truth(&Value::Scalar(result.to_f64())), true)
+            } else if target.is_some() {
+                (
+                    self.format_result(&Value::Scalar(result.to_f64()), target)?,
+                    true,
+                )
+            } else {
+                (result.to_string(), true)
+            }
+        } else {
+            let result = self.calculator.eval_value(line)?;
+            let is_scalar = matches!(result, Value::Scalar(_));
+            if is_boolean {
+                (format_truth(&result), is_scalar)
+            } else {
+                (self.format_result(&result, target)?, is_scalar)
+            }
+        };
+
+        if is_scalar {
+            self.results.push(self.calculator.last_result());
+        }
+
+        // Vectors are not added, as the total is a single number
+        if self.config.accumulate && is_scalar {
+            self.calculator.accumulate();
+            let total = self.config.format.format(self.calculator.total());
+            Ok(format!("{} (total: {})", text, total))
+        } else {
+            Ok(text)
+        }
+    }
+
+    /// Formats a result in the current number format, or as `target`.
+    fn format_result(&self, value: &Value, target: Option<&str>) -> Result<String, Error> {
+        let Some(target) = target else {
+            return Ok(self.config.format.format_value(value));
+        };
+        let format = |value: f64| format_as(value, target, self.config.format.precision);
+        match value {
+            Value::Scalar(value) => format(*value),
+            Value::Quantity(value, unit) => Ok(format!("{}{}", format(*value)?, unit)),
+            Value::Vector(values) => {
+                let elements = values
+                    .iter()
+                    .map(|value| format(*value))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(format!("[{}]", elements.join(", ")))
+            }
+            Value::Duration(_) => Err(Error::DomainError(format!(
+                "a duration cannot be shown as {}",
+                target
+            ))),
+        }
+    }
+
+    /// Remembers an entered expression, appending it to the history file.
+    /// If the file cannot be written, history is kept in memory only.
+    fn record_history(&mut self, entry: &str) -> io::Result<()> {
+        self.history.push(entry.to_string());
+        self.last_input = Some(entry.to_string());
+
+        if let Some(path) = &self.config.history_file {
+            if let Err(e) = append_history(path, entry) {
+                writeln!(
+                    self.errors,
+                    "Could not save history to {}: {}",
+                    path.display(),
+                    e
+                )?;
+                self.config.history_file = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn run_startup(&mut self) -> io::Result<()> {
+        for line in std::mem::take(&mut self.config.startup) {
+            self.evaluate(&line)?;
+        }
+
+        Ok(())
+    }
+
+    fn load_history_file(&mut self) -> io::Result<()> {
+        let Some(path) = &self.config.history_file else {
+            return Ok(());
+        };
+
+        match load_history(path) {
+            Ok(history) => self.history = history,
+            Err(e) => writeln!(self.errors, "Could not read {}: {}", path.display(), e)?,
+        }
+
+        Ok(())
+    }
+
+    /// Loads the configured rc file. Problems are reported but never prevent
+    /// the calculator starting.
+    fn load_rc_file(&mut self) -> io::Result<()> {
+        let Some(path) = &self.config.rc_file else {
+            return Ok(());
+        };
+
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                for (line, e) in self.calculator.load_definitions(&contents) {
+                    writeln!(self.errors, "{}:{}: {}", path.display(), line, e)?;
+                }
+            }
+            Err(e) => writeln!(self.errors, "Could not read {}: {}", path.display(), e)?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits a trailing `as <format>` clause off a line, e.g. `255 as hex`
+/// into `255` and `hex`.
+fn split_format_clause(line: &str) -> Option<(&str, &str)> {
+    let (expression, target) = line.trim_end().rsplit_once(" as ")?;
+    let target = target.trim();
+    if target.is_empty() || !target.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    Some((expression, target))
+}
+
+/// What `stats` reports about a session's results.
+#[derive(Debug, PartialEq)]
+struct Summary {
+    count: usize,
+    sum: f64,
+    mean: f64,
+    min: f64,
+    max: f64,
+}
+
+fn summarize(results: &[f64]) -> Option<Summary> {
+    if results.is_empty() {
+        return None;
+    }
+
+    let sum: f64 = results.iter().sum();
+    Some(Summary {
+        count: results.len(),
+        sum,
+        mean: sum / results.len() as f64,
+        min: results.iter().copied().fold(f64::INFINITY, f64::min),
+        max: results.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_script(config: ReplConfig, script: &str) -> (String, String) {
+        let mut repl = Repl::new(config, Vec::new(), Vec::new());
+        repl.run(script.as_bytes()).unwrap();
+        let (output, errors) = repl.into_output();
+        (
+            String::from_utf8(output).unwrap(),
+            String::from_utf8(errors).unwrap(),
+        )
+    }
+
+    /// A config with no banner or prompt, so that only results and errors
+    /// are printed.
+    fn quiet_config() -> ReplConfig {
+        ReplConfig {
+            quiet: true,
+            prompt: String::new(),
+            ..ReplConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let config = ReplConfig {
+            format: NumberFormat {
+                precision: Some(2),
+                ..NumberFormat::default()
+            },
+            ..quiet_config()
+        };
+        let (output, errors) = run_script(config, "x = 2\nx / 3\n\n4 / 0\nquit\n1 + 1\n");
+
+        assert_eq!(output, "Result: 2\nResult: 0.67\nGoodbye!\n");
+        assert_eq!(errors, "Error evaluating expression: Division by zero\n");
+    }
+
+    #[test]
+    fn test_startup_expressions() {
+        let config = ReplConfig {
+            startup: vec!["x = 5".to_string(), "y = x + 1".to_string()],
+            ..quiet_config()
+        };
+        let (output, _) = run_script(config, "x * y\n");
+        assert_eq!(output, "Result: 5\nResult: 6\nResult: 30\n");
+    }
+
+    #[test]
+    fn test_rounding_command() {
+        let config = ReplConfig {
+            format: NumberFormat {
+                precision: Some(0),
+                ..NumberFormat::default()
+            },
+            ..quiet_config()
+        };
+        let script = "2.5\nrounding half-up\n2.5\nrounding truncate\n2.5\nrounding up\n";
+        let (output, errors) = run_script(config, script);
+        assert_eq!(output, "Result: 2\nResult: 3\nResult: 2\n");
+        assert_eq!(errors, "Usage: rounding half-up|half-even|truncate\n");
+    }
+
+    #[test]
+    fn test_exact_command() {
+        let config = quiet_config();
+        let script = "exact on\n1/3 + 1/3 + 1/3\n2/6\nexact off\n1/4\n";
+        let (output, _) = run_script(config, script);
+        assert_eq!(output, "Result: 1\nResult: 1/3\nResult: 0.25\n");
+    }
+
+    #[test]
+    fn test_bool_display_command() {
+        let config = quiet_config();
+        let script = "3 > 2\nbool-display on\n3 > 2\n1 == 2\n(3 > 2) + 1\n[1, 2] >= 2\nexact on\nnot 0\nbool-display off\n3 > 2\n";
+        let (output, _) = run_script(config, script);
+        assert_eq!(
+            output,
+            "Result: 1\nResult: true\nResult: false\nResult: 2\nResult: [false, true]\nResult: true\nResult: 1\n"
+        );
+    }
+
+    #[test]
+    fn test_accumulate_command() {
+        let config = quiet_config();
+        let script = "\
+1 + 1
+accumulate on
+5
+x = 10
+x / 4
+[1, 2]
+1 / 0
+accumulate reset
+7
+accumulate off
+3
+";
+        let (output, errors) = run_script(config, script);
+        assert_eq!(
+            output,
+            "Result: 2\nResult: 5 (total: 5)\nResult: 10 (total: 15)\nResult: 2.5 (total: 17.5)\n\
+             Result: [1, 2]\nResult: 7 (total: 7)\nResult: 3\n"
+        );
+        assert_eq!(errors, "Error evaluating expression: Division by zero\n");
+    }
+
+    #[test]
+    fn test_format_command() {
+        let config = quiet_config();
+        let script = "format currency\n1234.5\n1234\nprecision?\nformat default\n1234.5\nformat\n";
+        let (output, errors) = run_script(config, script);
+        assert_eq!(
+            output,
+            "Result: 1,234.50\nResult: 1,234.00\nprecision: 2\nResult: 1234.5\n"
+        );
+        assert_eq!(errors, "Usage: format default|currency\n");
+    }
+
+    #[test]
+    fn test_ambiguous_report() {
+        let config = ReplConfig {
+            ambiguous_report: true,
+            ..quiet_config()
+        };
+        let script = "50 % -3\n50% - 3\n1 + 2\n";
+        let (output, _) = run_script(config, script);
+        assert_eq!(
+            output,
+            "Reading 1, % as the remainder: 2\nReading 2, % as a percentage: -2.5\nResult: 2\n\
+             Reading 1, % as a percentage: -2.5\nReading 2, % as the remainder: 2\nResult: -2.5\n\
+             Result: 3\n"
+        );
+    }
+
+    #[test]
+    fn test_bases_command() {
+        let (output, errors) = run_script(quiet_config(), "bases 255\nBASES 5 / 2\nbases 1 / 0\n");
+        assert_eq!(
+            output,
+            "decimal: 255\nhex: 0xff\noctal: 0o377\nbinary: 0b11111111\n\
+             decimal: 2.5 (not a whole number)\n"
+        );
+        assert_eq!(errors, "Error evaluating expression: Division by zero\n");
+    }
+
+    #[test]
+    fn test_empty_repeats_command() {
+        let config = quiet_config();
+        let script =
+            "2 + 3\n\nempty-repeats on\n\nx = 7\n   \nempty-repeats off\n\nempty-repeats\n";
+        let (output, errors) = run_script(config, script);
+        assert_eq!(output, "Result: 5\nResult: 5\nResult: 7\nResult: 7\n");
+        assert_eq!(errors, "Usage: empty-repeats on|off\n");
+    }
+
+    #[test]
+    fn test_explain_precedence() {
+        let config = ReplConfig {
+            explain_precedence: true,
+            ..quiet_config()
+        };
+        let (output, _) = run_script(config, "2 + 3 * 4\n2 * 3 + 4\n(2 + 3) * 4\n");
+        assert_eq!(
+            output,
+            "Result: 14 (multiplication was applied before addition)\nResult: 10\nResult: 20\n"
+        );
+    }
+
+    #[test]
+    fn test_fix_command() {
+        let config = quiet_config();
+        let script = "fix 2 3\n2 + 2 * 10\nfix 10 3\nfix 2 4\nfix 7 8\nfix 2\nhistory\n";
+        let (output, errors) = run_script(config, script);
+        assert_eq!(
+            output,
+            "Result: 22\n2 + 2 * 3\nResult: 8\n4 + 2 * 3\nResult: 10\n\
+             1: 2 + 2 * 10\n2: 2 + 2 * 3\n3: 4 + 2 * 3\n"
+        );
+        assert_eq!(
+            errors,
+            "There is no expression to fix\nThe last expression does not contain 7\n\
+             Usage: fix <search> <replace>\n"
+        );
+    }
+
+    #[test]
+    fn test_again_command() {
+        let config = quiet_config();
+        let mut repl = Repl::new(config, Vec::new(), Vec::new());
+        let script = "again\nx = 1 / 8\nformat currency\nagain\nbool-display on\n2 > 1\nagain\n";
+        repl.run(script.as_bytes()).unwrap();
+        assert_eq!(repl.last_input(), Some("2 > 1"));
+        assert_eq!(repl.history().len(), 2);
+
+        let (output, errors) = repl.into_output();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Result: 0.125\nResult: 0.13\nResult: true\nResult: true\n"
+        );
+        assert_eq!(
+            String::from_utf8(errors).unwrap(),
+            "There is no expression to run again\n"
+        );
+    }
+
+    #[test]
+    fn test_format_clause() {
+        let config = quiet_config();
+        let script = "255 as hex\n1000000 as sci\nx = 2 + 2 as binary\nx * 1000\n2.5 as hex\n\
+                      1 as roman\n[1, 10] as hex\n1:30 as hex\nexact on\n1/3 as sci\n\
+                      accumulate on\n8 as octal\n";
+        let (output, errors) = run_script(config, script);
+        assert_eq!(
+            output,
+            "Result: 0xff\nResult: 1e6\nResult: 0b100\nResult: 4000\nResult: [0x1, 0xa]\n\
+             Result: 3.333333333333333e-1\nResult: 0o10 (total: 8)\n"
+        );
+        assert_eq!(
+            errors,
+            "Error evaluating expression: Domain error: hex needs a whole number that fits in \
+             64 bits, not 2.5\n\
+             Error evaluating expression: Invalid argument: as roman\n\
+             Error evaluating expression: Domain error: a duration cannot be shown as hex\n"
+        );
+        assert_eq!(split_format_clause("base as hex"), Some(("base", "hex")));
+        assert_eq!(split_format_clause("2 as 3"), None);
+        assert_eq!(split_format_clause("ash + 1"), None);
+    }
+
+    #[test]
+    fn test_convert_command() {
+        let config = quiet_config();
+        let script = "convert 255 to hex\nconvert 0b1010 to dec\nconvert 0xFF to binary\n\
+                      convert -8 to oct\nconvert 0o17 to hex\nconvert 2.5 to dec\n\
+                      convert 0x1.8p1 to dec\nconvert 0b102 to dec\nconvert inf to hex\n\
+                      convert 2.5 to hex\nconvert 255 hex\n";
+        let (output, errors) = run_script(config, script);
+        assert_eq!(output, "0xff\n10\n0b11111111\n-0o10\n0xf\n2.5\n3\n");
+        assert_eq!(
+            errors,
+            "Invalid number: 0b102\n\
+             Invalid number: inf\n\
+             Domain error: hex needs a whole number that fits in 64 bits, not 2.5\n\
+             Usage: convert <number> to hex|octal|binary|dec\n"
+        );
+    }
+
+    #[test]
+    fn test_round_intermediate_command() {
+        let config = ReplConfig {
+            format: NumberFormat {
+                precision: Some(2),
+                ..NumberFormat::default()
+            },
+            ..quiet_config()
+        };
+        let script = "1.005 * 3 * 3\nround-intermediate on\n1.005 * 3 * 3\nformat default\n\
+                      1.005 * 3 * 3\nround-intermediate off\nformat currency\n1.005 * 3 * 3\n\
+                      round-intermediate\n";
+        let (output, errors) = run_script(config, script);
+        assert_eq!(
+            output,
+            "Result: 9.04\nResult: 9.03\nResult: 9.044999999999998\nResult: 9.04\n"
+        );
+        assert_eq!(errors, "Usage: round-intermediate on|off\n");
+    }
+
+    #[test]
+    fn test_history_is_preprocessed() {
+        let config = quiet_config();
+        let mut repl = Repl::new(config, Vec::new(), Vec::new());
+        repl.run("  6 ×  7   # answer\nx\t=\t2\n# note\nagain\n".as_bytes())
+            .unwrap();
+        assert_eq!(repl.history(), ["6 * 7", "x = 2"]);
+        assert_eq!(repl.calculator().last_result(), 2.0);
+
+        let (output, errors) = repl.into_output();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Result: 42\nResult: 2\nResult: 2\n"
+        );
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_def_command() {
+        let config = quiet_config();
+        let script = "def double(x) = x * 2\ndouble(5)\ndef sq(x) = x\ndouble(double(1)) + 1\n";
+        let (output, errors) = run_script(config, script);
+        assert_eq!(output, "Result: 10\nResult: 5\n");
+        assert_eq!(errors, "Invalid assignment: sq is a built-in function\n");
+    }
+
+    #[test]
+    fn test_kahan_command() {
+        let config = quiet_config();
+        let sum = ["0.1"; 10].join(" + ");
+        let script = format!("{sum}\nkahan on\n{sum}\nkahan off\n{sum}\nkahan\n");
+        let (output, errors) = run_script(config, &script);
+        assert_eq!(
+            output,
+            "Result: 0.9999999999999999\nResult: 1\nResult: 0.9999999999999999\n"
+        );
+        assert_eq!(errors, "Usage: kahan on|off\n");
+    }
+
+    #[test]
+    fn test_block() {
+        let config = quiet_config();
+        let mut repl = Repl::new(config, Vec::new(), Vec::new());
+        let script = "end\nbegin\nprice = 40\n\nprice * 1.25\nEND\nprice - 1\n";
+        repl.run(script.as_bytes()).unwrap();
+        assert_eq!(repl.history(), ["price = 40", "price * 1.25", "price - 1"]);
+
+        let (output, errors) = repl.into_output();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Result: 40\nResult: 50\nResult: 39\n"
+        );
+        assert_eq!(
+            String::from_utf8(errors).unwrap(),
+            "There is no block to end; start one with begin\n"
+        );
+
+        // Nothing runs until the block is ended
+        let (output, _) = run_script(quiet_config(), "begin\n1 + 1\n");
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn test_stats_command() {
+        let config = quiet_config();
+        let mut repl = Repl::new(config, Vec::new(), Vec::new());
+        let script = "stats
+12.5
+rent = 800
+-20
+? 1000
+[1, 2]
+7.5
+stats
+";
+        repl.run(script.as_bytes()).unwrap();
+        assert_eq!(repl.results(), [12.5, 800.0, -20.0, 7.5]);
+
+        let (output, errors) = repl.into_output();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.ends_with(
+            "Count: 4
+Sum: 800
+Mean: 200
+Min: -20
+Max: 800
+"
+        ));
+        assert_eq!(
+            String::from_utf8(errors).unwrap(),
+            "There are no results yet
+"
+        );
+    }
+
+    #[test]
+    fn test_bare_output() {
+        let config = ReplConfig {
+            bare_output: true,
+            ..quiet_config()
+        };
+        let (output, errors) = run_script(config, "2 + 3\n10 / 4\n1 / 0\n");
+        assert_eq!(output, "5\n2.5\n");
+        assert_eq!(errors, "Error evaluating expression: Division by zero\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_socket_connection() {
+        use std::io::Read;
+        use std::os::unix::net::UnixStream;
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        // Startup expressions are for the server's own session, not its clients
+        let config = ReplConfig {
+            startup: vec!["y = 1".to_string()],
+            ..ReplConfig::default()
+        };
+        let handle = std::thread::spawn(move || serve_connection(config, server));
+
+        client.write_all(b"x = 4\nx * 2\n1 / 0\ny\nquit\n").unwrap();
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).unwrap();
+        handle.join().unwrap().unwrap();
+        assert_eq!(
+            replies,
+            "Result: 4\nResult: 8\nError evaluating expression: Division by zero\n\
+             Error evaluating expression: Undefined variable: y\nGoodbye!\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_stale_socket() {
+        let path = std::env::temp_dir().join(format!("calc_socket_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        drop(bind_socket(&path).unwrap());
+        // The first listener is gone, but its socket file is still there
+        assert!(path.exists());
+        assert!(bind_socket(&path).is_ok());
+        fs::remove_file(&path).unwrap();
+
+        // Files that are not sockets are never removed
+        fs::write(&path, "data").unwrap();
+        assert!(bind_socket(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_query_commands() {
+        let config = ReplConfig {
+            format: NumberFormat {
+                precision: Some(3),
+                ..NumberFormat::default()
+            },
+            ..quiet_config()
+        };
+        let script = "precision?\nmode?\nbase?\nmode deg\nmode?\nreset-precision\nprecision?\n\
+                      base 16\nbase?\n255\n1 < 2\n255 as dec\nbase = 3\nbase 3\nbase 10\n255\n";
+        let (output, errors) = run_script(config, script);
+        assert_eq!(
+            output,
+            "precision: 3\nmode: radians\nbase: 10\nmode: degrees\nprecision: not set\n\
+             base: 16\nResult: 0xff\nResult: 0x1\nResult: 255\nResult: 0x3\nResult: 255\n"
+        );
+        assert_eq!(errors, "Usage: base 2|8|10|16\n");
+    }
+
+    #[test]
+    fn test_memory_commands() {
+        let config = quiet_config();
+        let script = "2 + 3\nM+\n4\nm+\n1\nM-\nMR\nMC\nMR + 1\n";
+        let (output, _) = run_script(config, script);
+        assert_eq!(
+            output,
+            "Result: 5\nResult: 4\nResult: 1\nResult: 8\nResult: 1\n"
+        );
+    }
+
+    #[test]
+    fn test_store_recall_swap() {
+        let config = quiet_config();
+        let script = "2 + 3\nstore\n7\nswap\nMR + 1\nrecall\nMR\nswap\n";
+        let (output, _) = run_script(config, script);
+        assert_eq!(
+            output,
+            "Result: 5\nResult: 7\nResult: 5\nResult: 8\nResult: 7\nResult: 7\nResult: 7\n"
+        );
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let at = Duration::from_millis(1_700_000_000_250);
+        assert_eq!(
+            json_line("2 + 3", &Ok("5".to_string()), at),
+            r#"{"input":"2 + 3","result":"5","timestamp":1700000000.250}"#
+        );
+        assert_eq!(
+            json_line("\"a\\b\"", &Err(Error::DivisionByZero.to_string()), at),
+            r#"{"input":"\"a\\b\"","error":"Division by zero","timestamp":1700000000.250}"#
+        );
+
+        let config = ReplConfig {
+            json_lines: true,
+            ..quiet_config()
+        };
+        let script = "2 + 3\n1 / 0\nhelp\nprecision? # shown in JSON too\n? 1\n\n# note\nquit\n4\n";
+        let (output, errors) = run_script(config, script);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with(r#"{"input":"2 + 3","result":"5","timestamp":"#));
+        assert!(lines[1].starts_with(r#"{"input":"1 / 0","error":"Division by zero","timestamp":"#));
+        let refusal = r#""error":"commands are not available in json-lines mode","#;
+        assert!(lines[2].starts_with(&format!(r#"{{"input":"help",{}"#, refusal)));
+        assert!(lines[3].starts_with(&format!(r#"{{"input":"precision?",{}"#, refusal)));
+        assert!(lines[4].starts_with(&format!(r#"{{"input":"? 1",{}"#, refusal)));
+        assert!(lines.iter().all(|line| line.ends_with('}')));
+        assert_eq!(errors, "");
+    }
+
+    #[test]
+    fn test_tree_command() {
+        let (output, errors) = run_script(quiet_config(), "tree 2 + 3 * 4\ntree (2 +\n");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines, ["+", "|-- 2", "`-- *", "    |-- 3", "    `-- 4"]);
+        // The multiplication is the right child of the addition
+        assert_eq!(lines.iter().position(|line| *line == "`-- *"), Some(2));
+        assert_eq!(errors, "Mismatched brackets\n");
+    }
+
+    #[test]
+    fn test_arg_separator_command() {
+        let config = quiet_config();
+        let script = "arg-separator ;\nhypot(3; 4)\narg-separator -\narg-separator ,\nmod(7, 4)\n";
+        let (output, errors) = run_script(config, script);
+        assert_eq!(output, "Result: 5\nResult: 3\n");
+        assert_eq!(errors, "Usage: arg-separator ,|;\n");
+    }
+
+    #[test]
+    fn test_preview() {
+        let config = ReplConfig {
+            empty_repeats: true,
+            ..quiet_config()
+        };
+        let mut repl = Repl::new(config, Vec::new(), Vec::new());
+        repl.run("7\n? 2 + 3\n?x = 3 * 2\n\nhistory\n".as_bytes())
+            .unwrap();
+        assert_eq!(repl.calculator().last_result(), 7.0);
+        assert_eq!(repl.calculator().variable("x"), None);
+        assert_eq!(repl.history(), ["7"]);
+
+        let (output, _) = repl.into_output();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Result: 7\nPreview: 5\nPreview: 6\nResult: 7\n1: 7\n"
+        );
+    }
+
+    #[test]
+    fn test_run_batch_report() {
+        let mut repl = Repl::new(ReplConfig::default(), Vec::new(), Vec::new());
+        let script = "c0 = 1\na = 2\nb = a * 3\nc = b + 1\nreport\n";
+        assert!(repl.run_batch(script.as_bytes()).unwrap());
+
+        let (output, errors) = repl.into_output();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Result: 1\nResult: 2\nResult: 6\nResult: 7\na = 2\nb = 6\nc = 7\nc0 = 1\n"
+        );
+        assert!(errors.is_empty());
+
+        let mut repl = Repl::new(ReplConfig::default(), Vec::new(), Vec::new());
+        assert!(!repl.run_batch("b = a * 3\n".as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn test_run_batch_failure_summary() {
+        let mut repl = Repl::new(ReplConfig::default(), Vec::new(), Vec::new());
+        let script = "x = 4\n1 / 0\n\nx * 2\ny + 1\n2 +\nx - 1\n";
+        assert!(!repl.run_batch(script.as_bytes()).unwrap());
+        assert_eq!(repl.failures(), 3);
+
+        let (output, errors) = repl.into_output();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Result: 4\nResult: 8\nResult: 3\n"
+        );
+        let errors = String::from_utf8(errors).unwrap();
+        let lines: Vec<&str> = errors.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            "line 2: Error evaluating expression: Division by zero"
+        );
+        assert!(lines[1].starts_with("line 5: "));
+        assert!(lines[2].starts_with("line 6: "));
+        assert_eq!(lines[3], "3 of 6 lines failed");
+    }
+
+    #[test]
+    fn test_run_batch_skips_comments() {
+        let mut repl = Repl::new(ReplConfig::default(), Vec::new(), Vec::new());
+        let script = "# Totals\n\n2 + 3\n   \n  # the tax rate\nx = 4 # per cent\n\t\nx * 2\n";
+        assert!(repl.run_batch(script.as_bytes()).unwrap());
+
+        let (output, errors) = repl.into_output();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Result: 5\nResult: 4\nResult: 8\n"
+        );
+        assert_eq!(errors, b"");
+
+        let config = ReplConfig {
+            empty_repeats: true,
+            ..ReplConfig::default()
+        };
+        let mut repl = Repl::new(config, Vec::new(), Vec::new());
+        assert!(!repl
+            .run_batch("2 + 3\n# again\n\n1 / 0\n".as_bytes())
+            .unwrap());
+        let (output, errors) = repl.into_output();
+        assert_eq!(output, b"Result: 5\n");
+        assert_eq!(
+            String::from_utf8(errors).unwrap(),
+            "line 4: Error evaluating expression: Division by zero\n1 of 2 lines failed\n"
+        );
+    }
+
+    #[test]
+    fn test_seed_config() {
+        let config = ReplConfig {
+            seed: Some(42),
+            ..quiet_config()
+        };
+        let script = "rand()\nrand(1, 6)\nrand()\n";
+        let (first, _) = run_script(config.clone(), script);
+        let (second, _) = run_script(config, script);
+        assert_eq!(first.lines().count(), 3);
+        assert_eq!(first, second);
+
+        let (seeded_later, _) = run_script(quiet_config(), &format!("seed 42\n{}", script));
+        assert_eq!(seeded_later, first);
+    }
+
+    #[test]
+    fn test_run_once() {
+        let mut repl = Repl::new(ReplConfig::default(), Vec::new(), Vec::new());
+        assert!(repl.run_once("2 ^ 10").unwrap());
+        assert!(!repl.run_once("1 / 0").unwrap());
+        assert_eq!(repl.failures(), 1);
+        assert_eq!(repl.into_output().0, b"Result: 1024\n");
+    }
+
+    #[test]
+    fn test_history_file() {
+        let path = std::env::temp_dir().join(format!("calc_history_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(load_history(&path).unwrap(), Vec::<String>::new());
+        append_history(&path, "1 + 1").unwrap();
+        append_history(&path, "x = 2").unwrap();
+        assert_eq!(load_history(&path).unwrap(), vec!["1 + 1", "x = 2"]);
+
+        let config = ReplConfig {
+            history_file: Some(path.clone()),
+            ..quiet_config()
+        };
+        let (output, _) = run_script(
+            config,
+            "2 * 3
+history
+",
+        );
+        assert_eq!(output, "Result: 6\n1: 1 + 1\n2: x = 2\n3: 2 * 3\n");
+        assert_eq!(load_history(&path).unwrap().len(), 3);
+
+        // `again` does not reach back into an earlier session
+        let config = ReplConfig {
+            history_file: Some(path.clone()),
+            ..quiet_config()
+        };
+        let (output, errors) = run_script(config, "again\n");
+        assert_eq!(output, "");
+        assert_eq!(errors, "There is no expression to run again\n");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_output_file() {
+        let script = std::env::temp_dir().join(format!("calc_script_{}", std::process::id()));
+        let results = std::env::temp_dir().join(format!("calc_results_{}", std::process::id()));
+        fs::write(&script, "x = 4\nx * 2\n1 / 0\nx + 1\n").unwrap();
+        fs::write(&results, "stale contents\n").unwrap();
+
+        let output = open_output(Some(&results)).unwrap();
+        let mut errors = Vec::new();
+        assert!(!run_file(ReplConfig::default(), &script, output, &mut errors).unwrap());
+        assert_eq!(
+            fs::read_to_string(&results).unwrap(),
+            "Result: 4\nResult: 8\nResult: 5\n"
+        );
+        assert_eq!(
+            String::from_utf8(errors).unwrap(),
+            "line 3: Error evaluating expression: Division by zero\n1 of 4 lines failed\n"
+        );
+
+        let output = open_output(Some(&results)).unwrap();
+        assert!(evaluate_once(ReplConfig::default(), "2 ^ 3", output).unwrap());
+        assert_eq!(fs::read_to_string(&results).unwrap(), "Result: 8\n");
+
+        fs::remove_file(&script).unwrap();
+        fs::remove_file(&results).unwrap();
+    }
+
+    #[test]
+    fn test_help_topics() {
+        let (output, errors) = run_script(
+            quiet_config(),
+            "help functions\nhelp Operators\nhelp bogus\n",
+        );
+        assert!(output.starts_with("Functions:\n"));
+        assert!(output.contains("sqrt(x)"));
+        assert!(output.contains("\nOperators:\n"));
+        assert!(!output.contains("Type 'report'"));
+        assert_eq!(
+            errors,
+            "Usage: help [syntax|operators|functions|commands]\n"
+        );
+    }
+
+    #[test]
+    fn test_error_hints() {
+        let config = ReplConfig {
+            prompt: String::new(),
+            ..ReplConfig::default()
+        };
+        let (_, errors) = run_script(config, "1 / 0\n");
+        assert_eq!(
+            errors,
+            "Error evaluating expression: Division by zero\nHint: Check that the divisor is not zero.\n"
+        );
+
+        let (_, errors) = run_script(quiet_config(), "1 / 0\n");
+        assert_eq!(errors, "Error evaluating expression: Division by zero\n");
+    }
+
+    #[test]
+    fn test_banner_and_help() {
+        let (output, _) = run_script(ReplConfig::default(), "help\n");
+        assert!(output.starts_with("\nWelcome to the Calculator CLI project\n"));
+        assert_eq!(output.matches(HELP_DOC).count(), 2);
+        assert!(output.contains("help functions"));
+
+        let config = ReplConfig {
+            quiet: true,
+            ..ReplConfig::default()
+        };
+        let (output, _) = run_script(config, "");
+        assert_eq!(output, "> ");
+    }
+}
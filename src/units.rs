@@ -0,0 +1,151 @@
+use std::fmt::{Display, Formatter};
+
+use crate::{apply_operator, finite, is_boolean, operator_symbol, Error, Value};
+
+/// What a unit measures. Only quantities of the same dimension can be added,
+/// subtracted or compared.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq)]
+pub enum Dimension {
+    Length,
+    Mass,
+}
+
+/// A unit of measurement, written against its number, e.g. `5km`.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Unit {
+    pub name: &'static str,
+    pub dimension: Dimension,
+    /// The size of the unit in metres for lengths, or grams for masses.
+    pub factor: f64,
+}
+
+const fn unit(name: &'static str, dimension: Dimension, factor: f64) -> Unit {
+    Unit {
+        name,
+        dimension,
+        factor,
+    }
+}
+
+/// The units that numbers can be written with.
+pub const UNITS: &[Unit] = &[
+    unit("mm", Dimension::Length, 0.001),
+    unit("cm", Dimension::Length, 0.01),
+    unit("m", Dimension::Length, 1.0),
+    unit("km", Dimension::Length, 1000.0),
+    unit("mg", Dimension::Mass, 0.001),
+    unit("g", Dimension::Mass, 1.0),
+    unit("kg", Dimension::Mass, 1000.0),
+];
+
+impl Unit {
+    pub fn named(name: &str) -> Option<Unit> {
+        UNITS.iter().find(|unit| unit.name == name).copied()
+    }
+
+    /// Converts `value` in `from` to this unit, failing if they measure
+    /// different things.
+    fn convert(self, value: f64, from: Unit) -> Result<f64, Error> {
+        if self.dimension != from.dimension {
+            return Err(Error::IncompatibleUnits(
+                self.name.to_string(),
+                from.name.to_string(),
+            ));
+        }
+        Ok(value * from.factor / self.factor)
+    }
+}
+
+impl Display for Unit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Lexes a unit name written straight after a number, returning it with its
+/// length. A name that is not a unit is left alone, so `2x` is still `2 * x`.
+pub(crate) fn lex_unit(chars: &[char]) -> Option<(Unit, usize)> {
+    let len = chars
+        .iter()
+        .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+        .count();
+    let name: String = chars[..len].iter().collect();
+    Unit::named(&name).map(|unit| (unit, len))
+}
+
+/// Arithmetic with quantities. Those of the same dimension add, subtract and
+/// compare in the unit on the left (`5km + 300m` is `5.3km`) and divide into
+/// a plain ratio (`1km / 250m` is 4). Quantities scale by numbers
+/// (`2 * 300g` is `600g`).
+pub(crate) fn apply_quantity_operator(
+    op: char,
+    left: &Value,
+    right: &Value,
+) -> Result<Value, Error> {
+    let apply = |b: f64, a: f64| finite(apply_operator(op, b, a)?, false);
+    match (left, right, op) {
+        (Value::Quantity(a, unit), Value::Quantity(b, other), '+' | '-') => {
+            let b = unit.convert(*b, *other)?;
+            Ok(Value::Quantity(apply(b, *a)?, *unit))
+        }
+        (Value::Quantity(a, unit), Value::Quantity(b, other), _) if op == '/' || is_boolean(op) => {
+            let b = unit.convert(*b, *other)?;
+            Ok(Value::Scalar(apply(b, *a)?))
+        }
+        (Value::Quantity(a, unit), Value::Scalar(b), '*' | '/') => {
+            Ok(Value::Quantity(apply(*b, *a)?, *unit))
+        }
+        (Value::Scalar(a), Value::Quantity(b, unit), '*') => {
+            Ok(Value::Quantity(apply(*b, *a)?, *unit))
+        }
+        _ => Err(Error::InvalidExpression(format!(
+            "cannot apply {} to {} and {}",
+            operator_symbol(op),
+            left,
+            right
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metres_unit() -> Unit {
+        Unit::named("m").unwrap()
+    }
+
+    fn km(value: f64) -> Value {
+        Value::Quantity(value, Unit::named("km").unwrap())
+    }
+
+    #[test]
+    fn test_quantity_operators() {
+        let metres = Value::Quantity(300.0, metres_unit());
+        assert_eq!(apply_quantity_operator('+', &km(5.0), &metres), Ok(km(5.3)));
+        assert_eq!(
+            apply_quantity_operator('/', &km(1.0), &Value::Quantity(250.0, metres_unit())),
+            Ok(Value::Scalar(4.0))
+        );
+        assert_eq!(
+            apply_quantity_operator('>', &metres, &km(0.2)),
+            Ok(Value::Scalar(1.0))
+        );
+        assert_eq!(
+            apply_quantity_operator('*', &Value::Scalar(2.0), &km(1.5)),
+            Ok(km(3.0))
+        );
+        assert_eq!(
+            apply_quantity_operator(
+                '+',
+                &km(1.0),
+                &Value::Quantity(1.0, Unit::named("kg").unwrap())
+            ),
+            Err(Error::IncompatibleUnits("km".to_string(), "kg".to_string()))
+        );
+        assert!(matches!(
+            apply_quantity_operator('*', &km(1.0), &km(1.0)),
+            Err(Error::InvalidExpression(_))
+        ));
+    }
+}
@@ -1,4 +1,10 @@
-use calculator_cli::{evaluate_expression, parse_expression, Error, Token};
+use calculator_cli::{
+    calculate_i64, compensated_sum, describe_error, estimate_cost, eval_ast, evaluate_expression,
+    explain_expression, format_number, is_boolean_expression, numeric_derivative, parse_expression,
+    parse_radix, parse_to_ast, precedence_notes, preprocess_input, strip_comment,
+    to_expression_string, token_stats, AngleMode, BatchResult, Calculator, Error, Expr, Rational,
+    RoundingMode, StepInfo, Token, Unit, Value, PHYSICAL_CONSTANTS,
+};
 
 #[test]
 fn test_parse_expression() {
@@ -140,3 +146,1633 @@ fn test_combined_bodmas_expressions() {
     let result = evaluate_expression(&tokens).unwrap();
     assert_eq!(result, 35.0);
 }
+
+#[test]
+fn test_token_stats() {
+    let input = vec!["(", "3", "+", "2", ")", "*", "4"];
+    let tokens = parse_expression(input).unwrap();
+    let stats = token_stats(&tokens);
+    assert_eq!(stats.numbers, 3);
+    assert_eq!(stats.operators, 2);
+    assert_eq!(stats.to_string(), "3 numbers, 2 operators");
+}
+
+#[test]
+fn test_calculator_variables() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("x = 2 * 3"), Ok(6.0));
+    assert_eq!(calculator.eval("x + 1"), Ok(7.0));
+    assert!(matches!(
+        calculator.eval("y + 1"),
+        Err(Error::UndefinedVariable(_))
+    ));
+}
+
+#[test]
+fn test_load_definitions() {
+    let mut calculator = Calculator::new();
+    let contents = "rate = 0.2\n\nbase = 100\n2 + 2\ntotal = base * ( 1 + rate )\nbad = nope\n";
+    let errors = calculator.load_definitions(contents);
+
+    assert_eq!(calculator.variable("rate"), Some(0.2));
+    assert_eq!(calculator.variable("total"), Some(120.0));
+    assert_eq!(errors.len(), 2);
+    assert!(matches!(errors[0], (4, Error::InvalidAssignment(_))));
+    assert!(matches!(errors[1], (6, Error::UndefinedVariable(_))));
+}
+
+#[test]
+fn test_remainder_and_modulo() {
+    let tokens = parse_expression(vec!["-7", "%", "3"]).unwrap();
+    assert_eq!(evaluate_expression(&tokens).unwrap(), -1.0);
+
+    let tokens = parse_expression(vec!["mod(-7,", "3)"]).unwrap();
+    assert_eq!(evaluate_expression(&tokens).unwrap(), 2.0);
+
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("mod(-7, 3) + -7 % 3"), Ok(1.0));
+    assert!(matches!(
+        calculator.eval("mod(1)"),
+        Err(Error::WrongArgumentCount(_, 2, 1))
+    ));
+    assert!(matches!(
+        calculator.eval("nope(1)"),
+        Err(Error::UnknownFunction(_))
+    ));
+}
+
+#[test]
+fn test_hex_float_literals() {
+    let tokens = parse_expression(vec!["0x1p4", "+", "0x1.8p1"]).unwrap();
+    assert_eq!(evaluate_expression(&tokens).unwrap(), 19.0);
+
+    assert!(matches!(
+        parse_expression(vec!["0x1q4"]),
+        Err(Error::InvalidNumber(_))
+    ));
+}
+
+#[test]
+fn test_format_number() {
+    assert_eq!(format_number(8.0, None), "8");
+    assert_eq!(format_number(8.0, Some(2)), "8");
+    assert_eq!(format_number(-12.0, Some(3)), "-12");
+    assert_eq!(format_number(8.5, None), "8.5");
+    assert_eq!(format_number(8.5, Some(2)), "8.50");
+    assert_eq!(format_number(2.0 / 3.0, Some(3)), "0.667");
+}
+
+#[test]
+fn test_comments() {
+    assert_eq!(strip_comment("2 + 3 # add them"), "2 + 3 ");
+
+    let tokens = parse_expression(vec!["2", "+", "3", "#", "add", "them"]).unwrap();
+    assert_eq!(evaluate_expression(&tokens).unwrap(), 5.0);
+
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("x = 4 # four"), Ok(4.0));
+    assert_eq!(
+        calculator.eval("# just a note"),
+        Err(Error::EmptyExpression)
+    );
+
+    let errors = calculator.load_definitions("# constants\ny = x * 2 # doubled\n");
+    assert!(errors.is_empty());
+    assert_eq!(calculator.variable("y"), Some(8.0));
+}
+
+#[test]
+fn test_reciprocal_and_square() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("inv(4)"), Ok(0.25));
+    assert_eq!(
+        calculator
+            .eval("inv(0)")
+            .map_err(|e| e.root_cause().clone()),
+        Err(Error::DivisionByZero)
+    );
+    assert_eq!(calculator.eval("sq(5)"), Ok(25.0));
+    assert_eq!(calculator.eval("sq(-3) + inv(2)"), Ok(9.5));
+}
+
+#[test]
+fn test_seeded_rand() {
+    let mut first = Calculator::new();
+    let mut second = Calculator::new();
+    first.seed(7);
+    second.seed(7);
+
+    for _ in 0..5 {
+        let value = first.eval("rand()").unwrap();
+        assert_eq!(second.eval("rand()").unwrap(), value);
+        assert!((0.0..1.0).contains(&value));
+
+        let value = first.eval("rand(10, 20)").unwrap();
+        assert_eq!(second.eval("rand(10, 20)").unwrap(), value);
+        assert!((10.0..20.0).contains(&value));
+    }
+
+    assert!(matches!(
+        first.eval("rand(1)"),
+        Err(Error::WrongArgumentCount(_, 2, 1))
+    ));
+}
+
+#[test]
+fn test_integer_division() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("7 \\ 2"), Ok(3.0));
+    assert_eq!(calculator.eval("-7 \\ 2"), Ok(-4.0));
+    assert_eq!(calculator.eval("1 + 7 \\ 2"), Ok(4.0));
+    assert_eq!(calculator.eval("7 \\ 2 * 2"), Ok(6.0));
+    assert_eq!(calculator.eval("2 ^ 3 \\ 3"), Ok(2.0));
+    assert_eq!(calculator.eval("7 \\ 0"), Err(Error::DivisionByZero));
+}
+
+#[test]
+fn test_explain_expression() {
+    let tokens = parse_expression(vec!["2", "+", "3", "*", "4"]).unwrap();
+    assert_eq!(
+        explain_expression(&tokens).unwrap(),
+        vec![
+            "Step 1: 3 * 4 (precedence 2)",
+            "Step 2: 2 + step 1 (precedence 1)"
+        ]
+    );
+
+    let tokens = parse_expression(vec!["(", "2", "+", "3", ")", "*", "inv(4)"]).unwrap();
+    assert_eq!(
+        explain_expression(&tokens).unwrap(),
+        vec![
+            "Step 1: 2 + 3 (precedence 1)",
+            "Step 2: inv(4)",
+            "Step 3: step 1 * step 2 (precedence 2)"
+        ]
+    );
+
+    let tokens = parse_expression(vec!["7"]).unwrap();
+    assert!(explain_expression(&tokens).unwrap().is_empty());
+}
+
+#[test]
+fn test_step_limit() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("5! - 3!"), Ok(114.0));
+    assert_eq!(calculator.eval("3 ! ! !"), Err(Error::Overflow));
+    assert_eq!(
+        calculator.eval("10 ! !"),
+        Err(Error::EvaluationLimitExceeded)
+    );
+
+    calculator.set_step_limit(3);
+    assert_eq!(
+        calculator.eval("1 + 2 + 3 + 4 + 5"),
+        Err(Error::EvaluationLimitExceeded)
+    );
+    assert_eq!(calculator.eval("1 + 2 + 3 + 4"), Ok(10.0));
+}
+
+#[test]
+fn test_hypot_and_atan2() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("hypot(3, 4)"), Ok(5.0));
+
+    let radians = calculator.eval("atan2(1, 1)").unwrap();
+    assert!((radians - std::f64::consts::FRAC_PI_4).abs() < 1e-12);
+
+    calculator.set_angle_mode(AngleMode::Degrees);
+    let degrees = calculator.eval("atan2(1, 1)").unwrap();
+    assert!((degrees - 45.0).abs() < 1e-9);
+    assert_eq!(calculator.eval("hypot(3, 4)"), Ok(5.0));
+}
+
+#[test]
+fn test_exp_and_ln() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("exp(0)"), Ok(1.0));
+    assert_eq!(calculator.eval("exp(1)"), Ok(std::f64::consts::E));
+    assert_eq!(calculator.eval("exp(1) - e"), Ok(0.0));
+    assert!((calculator.eval("exp(ln(5))").unwrap() - 5.0).abs() < 1e-12);
+    assert!(matches!(
+        calculator.eval("ln(0)").map_err(|e| e.root_cause().clone()),
+        Err(Error::DomainError(_))
+    ));
+
+    let tokens = parse_expression(vec!["ln(e)", "*", "2"]).unwrap();
+    assert_eq!(evaluate_expression(&tokens).unwrap(), 2.0);
+}
+
+#[test]
+fn test_compound_assignment() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("x = 10"), Ok(10.0));
+    assert_eq!(calculator.eval("x += 5"), Ok(15.0));
+    assert_eq!(calculator.eval("x *= 2"), Ok(30.0));
+    assert_eq!(calculator.eval("x -= 6 / 2"), Ok(27.0));
+    assert_eq!(calculator.eval("x /= 1 + 2"), Ok(9.0));
+    assert_eq!(calculator.variable("x"), Some(9.0));
+    assert_eq!(
+        calculator.eval("y += 1"),
+        Err(Error::UndefinedVariable("y".to_string()))
+    );
+}
+
+#[test]
+fn test_exact_thirds() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("1/3 + 1/3 + 1/3"), Ok(1.0));
+    assert_ne!(calculator.eval("0.1 + 0.2"), Ok(0.3));
+
+    assert_eq!(
+        calculator.eval_exact("1/3 + 1/3 + 1/3"),
+        Ok(Rational::integer(1))
+    );
+    assert_eq!(calculator.eval_exact("0.1 + 0.2"), Rational::new(3, 10));
+    assert_eq!(calculator.eval_exact("(2/3) ^ -2"), Rational::new(9, 4));
+    assert_eq!(calculator.eval_exact("x = 1/4"), Rational::new(1, 4));
+    assert_eq!(calculator.eval_exact("x * 2"), Rational::new(1, 2));
+    assert_eq!(
+        calculator.eval_exact("1 / (3 - 3)"),
+        Err(Error::DivisionByZero)
+    );
+    assert!(matches!(
+        calculator.eval_exact("2 ^ 0.5"),
+        Err(Error::DomainError(_))
+    ));
+    assert!(matches!(
+        calculator
+            .eval_exact("ln(2)")
+            .map_err(|e| e.root_cause().clone()),
+        Err(Error::InvalidExpression(_))
+    ));
+}
+
+#[test]
+fn test_function_error_context() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("sqrt(16)"), Ok(4.0));
+
+    let error = calculator.eval("sqrt(ln(-1))").unwrap_err();
+    assert!(matches!(&error, Error::InFunction(name, _) if name == "ln"));
+    assert!(matches!(error.root_cause(), Error::DomainError(_)));
+    assert!(error.to_string().starts_with("ln: Domain error"));
+
+    let error = calculator.eval("1 + exp(1000)").unwrap_err();
+    assert_eq!(error.to_string(), "exp: Result is not finite");
+    assert_eq!(
+        calculator.eval("sqrt(1, 2)"),
+        Err(Error::WrongArgumentCount("sqrt".to_string(), 1, 2))
+    );
+}
+
+#[test]
+fn test_fraction_literals() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("frac(3, 4)"), Ok(0.75));
+    // A fraction is one number, so it binds tighter than any operator
+    assert_eq!(calculator.eval("2 ^ frac(1, 2)"), Ok(2f64.sqrt()));
+    assert_eq!(calculator.eval("2 ^ 1 / 2"), Ok(1.0));
+    assert!(matches!(
+        calculator
+            .eval("frac(1.5, 2)")
+            .map_err(|e| e.root_cause().clone()),
+        Err(Error::DomainError(_))
+    ));
+    assert_eq!(
+        calculator
+            .eval("frac(1, 0)")
+            .map_err(|e| e.root_cause().clone()),
+        Err(Error::DivisionByZero)
+    );
+
+    assert_eq!(
+        calculator.eval_exact("frac(1, 3) * 3"),
+        Ok(Rational::integer(1))
+    );
+    assert_eq!(calculator.eval_exact("frac(6, -8)"), Rational::new(-3, 4));
+    assert!(matches!(
+        calculator
+            .eval_exact("frac(1, 0.5)")
+            .map_err(|e| e.root_cause().clone()),
+        Err(Error::DomainError(_))
+    ));
+}
+
+#[test]
+fn test_default_function() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("default(x, 0)"), Ok(0.0));
+    assert_eq!(calculator.eval("default(x * 2 + 1, 5) + 1"), Ok(6.0));
+    assert_eq!(calculator.eval("default(x, default(y, 7))"), Ok(7.0));
+    assert_eq!(
+        calculator.eval("default(x, y)"),
+        Err(Error::UndefinedVariable("y".to_string()))
+    );
+    assert_eq!(
+        calculator.eval("x + default(1, 2)"),
+        Err(Error::UndefinedVariable("x".to_string()))
+    );
+    assert_eq!(
+        calculator.eval("sq(x)"),
+        Err(Error::UndefinedVariable("x".to_string()))
+    );
+    assert_eq!(
+        calculator.eval("default(1 / 0, 2)"),
+        Err(Error::DivisionByZero)
+    );
+    assert_eq!(
+        calculator.eval("default(x)"),
+        Err(Error::WrongArgumentCount("default".to_string(), 2, 1))
+    );
+
+    calculator.set_variable("x", 3.0);
+    assert_eq!(calculator.eval("default(x, 0)"), Ok(3.0));
+}
+
+#[test]
+fn test_typographic_operators() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("5 \u{2212} 3"), Ok(2.0));
+    assert_eq!(calculator.eval("5 \u{2013} 3"), Ok(2.0));
+    assert_eq!(calculator.eval("\u{2212}2 * 3"), Ok(-6.0));
+    assert_eq!(calculator.eval("6 \u{00D7} 7 \u{00F7} 2"), Ok(21.0));
+}
+
+#[test]
+fn test_comparisons() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("1 < 2"), Ok(1.0));
+    assert_eq!(calculator.eval("2 <= 1"), Ok(0.0));
+    assert_eq!(calculator.eval("3 >= 3"), Ok(1.0));
+    assert_eq!(calculator.eval("3 > 3"), Ok(0.0));
+    assert_eq!(calculator.eval("1 + 1 == 2"), Ok(1.0));
+    assert_eq!(calculator.eval("2 != 2"), Ok(0.0));
+    assert_eq!(calculator.eval("x = 5 > 3"), Ok(1.0));
+    assert_eq!(calculator.variable("x"), Some(1.0));
+    assert_eq!(
+        calculator.eval_exact("1/3 + 1/3 + 1/3 == 1"),
+        Ok(Rational::integer(1))
+    );
+}
+
+#[test]
+fn test_logical_operators() {
+    let mut calculator = Calculator::new();
+    for (a, b) in [(0.0, 0.0), (0.0, 1.0), (1.0, 0.0), (1.0, 1.0)] {
+        let expected_and = if a != 0.0 && b != 0.0 { 1.0 } else { 0.0 };
+        let expected_or = if a != 0.0 || b != 0.0 { 1.0 } else { 0.0 };
+        assert_eq!(
+            calculator.eval(&format!("{} and {}", a, b)),
+            Ok(expected_and)
+        );
+        assert_eq!(calculator.eval(&format!("{} or {}", a, b)), Ok(expected_or));
+    }
+    assert_eq!(calculator.eval("not 0"), Ok(1.0));
+    assert_eq!(calculator.eval("not 1"), Ok(0.0));
+    assert_eq!(calculator.eval("not -2.5"), Ok(0.0));
+    assert_eq!(calculator.eval("not not 7"), Ok(1.0));
+
+    // Precedence: arithmetic, then comparisons, then not, and, or
+    assert_eq!(calculator.eval("1 + 1 == 2 and not 0"), Ok(1.0));
+    assert_eq!(calculator.eval("not 1 + 1"), Ok(0.0));
+    assert_eq!(calculator.eval("1 or 1 and 0"), Ok(1.0));
+    assert_eq!(calculator.eval("(1 or 1) and 0"), Ok(0.0));
+    assert_eq!(calculator.eval("not 1 == 2"), Ok(1.0));
+    assert!(matches!(
+        calculator.eval("and = 1"),
+        Err(Error::InvalidAssignment(_))
+    ));
+}
+
+#[test]
+fn test_evaluate_with_observer() {
+    let mut calculator = Calculator::new();
+    let tokens = parse_expression(vec!["2", "+", "3", "*", "4"]).unwrap();
+    let mut snapshots = Vec::new();
+    let result = calculator.evaluate_with_observer(&tokens, |stack| snapshots.push(stack.to_vec()));
+    assert_eq!(result, Ok(14.0));
+    assert_eq!(snapshots, vec![vec![2.0, 12.0], vec![14.0]]);
+}
+
+#[test]
+fn test_decimal_comma() {
+    let mut calculator = Calculator::new();
+    assert!(calculator.eval("3,14").is_err());
+
+    calculator.set_decimal_comma(true);
+    assert_eq!(calculator.eval("3,14"), Ok(314.0 / 100.0));
+    assert_eq!(calculator.eval("x = 0,5 + 1,25"), Ok(1.75));
+    assert_eq!(calculator.eval("hypot(3; 4)"), Ok(5.0));
+    assert_eq!(calculator.eval("mod(-7,5; 2)"), Ok(0.5));
+    assert!(calculator.eval("hypot(3, 4)").is_err());
+}
+
+#[test]
+fn test_memory_register() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.memory(), 0.0);
+    calculator.eval("6 * 7").unwrap();
+    calculator.memory_add();
+    calculator.memory_add();
+    assert_eq!(calculator.memory(), 84.0);
+    calculator.eval("4").unwrap();
+    calculator.memory_subtract();
+    assert_eq!(calculator.eval("MR / 2"), Ok(40.0));
+    assert_eq!(calculator.last_result(), 40.0);
+
+    // A failed evaluation leaves the last result alone
+    assert!(calculator.eval("1 / 0").is_err());
+    assert_eq!(calculator.last_result(), 40.0);
+
+    calculator.memory_clear();
+    assert_eq!(calculator.eval("MR"), Ok(0.0));
+    assert!(matches!(
+        calculator.eval("MR = 3"),
+        Err(Error::InvalidAssignment(_))
+    ));
+}
+
+#[test]
+fn test_strict_mode() {
+    let mut calculator = Calculator::new();
+    calculator.set_variable("x", 3.0);
+    assert_eq!(calculator.eval("2x"), Ok(6.0));
+    assert_eq!(calculator.eval("2(3 + 1)"), Ok(8.0));
+    assert_eq!(calculator.eval("(1 + 2)(3 + 4)"), Ok(21.0));
+    assert_eq!(calculator.eval("(1 + 1)3"), Ok(6.0));
+    assert_eq!(calculator.eval("2 sq(3)"), Ok(18.0));
+    assert_eq!(calculator.eval("50%"), Ok(0.5));
+    assert_eq!(calculator.eval("200 * 15% + 1"), Ok(31.0));
+    assert_eq!(calculator.eval("7 % 3"), Ok(1.0));
+    assert!(calculator.eval("2 3").is_err());
+
+    calculator.set_strict(true);
+    for input in ["2x", "2(3 + 1)", "(1 + 2)(3 + 4)", "50%", "200 * 15% + 1"] {
+        assert!(
+            matches!(calculator.eval(input), Err(Error::InvalidExpression(_))),
+            "{} should be rejected in strict mode",
+            input
+        );
+    }
+    assert_eq!(calculator.eval("2 * x"), Ok(6.0));
+    assert_eq!(calculator.eval("7 % 3"), Ok(1.0));
+}
+
+#[test]
+fn test_superscript_powers() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("5²"), Ok(25.0));
+    assert_eq!(calculator.eval("2³"), Ok(8.0));
+    assert_eq!(calculator.eval("(1 + 2)² + 1"), Ok(10.0));
+    assert_eq!(calculator.eval("2 * 3²"), Ok(18.0));
+    assert_eq!(calculator.eval("x = 4"), Ok(4.0));
+    assert_eq!(calculator.eval("x³ - x²"), Ok(48.0));
+    assert!(calculator.eval("²").is_err());
+}
+
+#[test]
+fn test_register_function() {
+    let mut calculator = Calculator::new();
+    assert_eq!(
+        calculator.eval("double(4)"),
+        Err(Error::UnknownFunction("double".to_string()))
+    );
+
+    calculator.register_function("double", 1, |args| Ok(args[0] * 2.0));
+    assert!(calculator.functions().contains("double"));
+    assert_eq!(calculator.eval("double(4) + 1"), Ok(9.0));
+    assert_eq!(calculator.eval("double(sq(3))"), Ok(18.0));
+    assert_eq!(
+        calculator.eval("double(1, 2)"),
+        Err(Error::WrongArgumentCount("double".to_string(), 1, 2))
+    );
+
+    calculator.register_function("positive", 1, |args| {
+        if args[0] > 0.0 {
+            Ok(args[0])
+        } else {
+            Err(Error::DomainError("not positive".to_string()))
+        }
+    });
+    assert_eq!(
+        calculator
+            .eval("positive(-1)")
+            .map_err(|e| e.root_cause().clone()),
+        Err(Error::DomainError("not positive".to_string()))
+    );
+}
+
+#[test]
+fn test_gamma_and_factorial_functions() {
+    let mut calculator = Calculator::new();
+    assert!((calculator.eval("gamma(5)").unwrap() - 24.0).abs() < 1e-9);
+    assert!((calculator.eval("factorial(0.5)").unwrap() - 0.886_226_925).abs() < 1e-9);
+    assert_eq!(calculator.eval("factorial(5)"), Ok(120.0));
+    assert_eq!(calculator.eval("factorial(4) == 4!"), Ok(1.0));
+    assert!(matches!(
+        calculator
+            .eval("factorial(-1)")
+            .map_err(|e| e.root_cause().clone()),
+        Err(Error::DomainError(_))
+    ));
+}
+
+#[test]
+fn test_vectors() {
+    let mut calculator = Calculator::new();
+    assert_eq!(
+        calculator.eval_value("[1, 2, 3] + [10, 20, 30]"),
+        Ok(Value::Vector(vec![11.0, 22.0, 33.0]))
+    );
+    assert_eq!(
+        calculator.eval_value("[1, 2, 3] + 1"),
+        Ok(Value::Vector(vec![2.0, 3.0, 4.0]))
+    );
+    assert_eq!(
+        calculator.eval_value("2 ^ [1, 2, 3]"),
+        Ok(Value::Vector(vec![2.0, 4.0, 8.0]))
+    );
+    assert_eq!(
+        calculator.eval_value("sq([-1, 2]) * 2"),
+        Ok(Value::Vector(vec![2.0, 8.0]))
+    );
+    assert_eq!(calculator.eval_value("[]"), Ok(Value::Vector(vec![])));
+    assert_eq!(calculator.eval_value("1 + 2"), Ok(Value::Scalar(3.0)));
+    assert_eq!(
+        calculator.eval_value("[1, 2] + 1").unwrap().to_string(),
+        "[2, 3]"
+    );
+
+    assert_eq!(
+        calculator.eval_value("[1, 2, 3] + [1, 2]"),
+        Err(Error::ShapeMismatch(3, 2))
+    );
+    assert_eq!(
+        calculator.eval_value("[1, 0] / 0"),
+        Err(Error::DivisionByZero)
+    );
+    assert!(matches!(
+        calculator.eval_value("[1, [2]]"),
+        Err(Error::InvalidExpression(_))
+    ));
+    assert!(matches!(
+        calculator.eval("[1, 2]"),
+        Err(Error::InvalidExpression(_))
+    ));
+    assert!(matches!(
+        calculator.eval_value("v = [1, 2]"),
+        Err(Error::InvalidAssignment(_))
+    ));
+    assert_eq!(
+        calculator.eval_value("[1, 2)"),
+        Err(Error::MismatchedBracket)
+    );
+    assert_eq!(
+        calculator.eval_value("(1 + 2]"),
+        Err(Error::MismatchedBracket)
+    );
+    assert_eq!(
+        calculator.eval_value("[1, 2"),
+        Err(Error::MismatchedBracket)
+    );
+}
+
+#[test]
+fn test_is_boolean_expression() {
+    let calculator = Calculator::new();
+    let is_boolean = |input: &str| is_boolean_expression(&calculator.parse(input).unwrap());
+    assert!(is_boolean("3 > 2"));
+    assert!(is_boolean("1 + 1 == 2 and not 0"));
+    assert!(is_boolean("not 1"));
+    assert!(is_boolean("x = 1 != 2"));
+    assert!(!is_boolean("(3 > 2) + 1"));
+    assert!(!is_boolean("2 * 3"));
+}
+
+#[test]
+fn test_left_fold_chains() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("10 - 3 - 2"), Ok(5.0));
+    assert_eq!(calculator.eval("100 / 5 / 2"), Ok(10.0));
+    assert_eq!(calculator.eval("2 - 3 - 4"), Ok(-5.0));
+    assert_eq!(calculator.eval("2-3-4"), Ok(-5.0));
+    assert_eq!(calculator.eval("10 - 3 + 2"), Ok(9.0));
+    assert_eq!(calculator.eval("100 / 5 * 2"), Ok(40.0));
+    assert_eq!(calculator.eval("8 \\ 2 \\ 2"), Ok(2.0));
+    assert_eq!(calculator.eval("20 % 7 % 4"), Ok(2.0));
+    assert_eq!(calculator.eval("10 - 3 - 2 == 5"), Ok(1.0));
+}
+
+#[test]
+fn test_evaluation_writes_nothing_to_stdout() {
+    // With --output every result goes to the file, so anything left on
+    // stdout would have been printed by the evaluation itself
+    let results = std::env::temp_dir().join(format!("calc_no_stdout_{}", std::process::id()));
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_calculator-cli"))
+        .env_remove("HOME")
+        .args(["--output", results.to_str().unwrap(), "--seed", "1"])
+        .arg("sq([1, 2]) + frac(1, 2) + default(x, rand()) + gamma(0.5) > 1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(output.stderr.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(&results).unwrap(),
+        "Result: [1, 1]\n"
+    );
+    std::fs::remove_file(&results).unwrap();
+}
+
+#[test]
+fn test_describe_error() {
+    let errors = [
+        Error::InvalidNumber("1.2.3".to_string()),
+        Error::InvalidOperator("$".to_string()),
+        Error::InvalidExpression("?".to_string()),
+        Error::ConsecutiveOperators,
+        Error::DivisionByZero,
+        Error::TooManyOperators,
+        Error::EmptyExpression,
+        Error::MismatchedBracket,
+        Error::EmptyBrackets,
+        Error::NotFinite,
+        Error::UndefinedVariable("x".to_string()),
+        Error::InvalidAssignment("2".to_string()),
+        Error::UnknownFunction("f".to_string()),
+        Error::WrongArgumentCount("sq".to_string(), 1, 2),
+        Error::InvalidArgument("--bogus".to_string()),
+        Error::DomainError("sqrt(-1)".to_string()),
+        Error::EvaluationLimitExceeded,
+        Error::Overflow,
+        Error::ShapeMismatch(3, 2),
+        Error::Timeout(std::time::Duration::from_millis(5)),
+        Error::RecursionLimit("f".to_string()),
+        Error::NotAnInteger("2.5".to_string()),
+        Error::IncompatibleUnits("m".to_string(), "s".to_string()),
+    ];
+    for error in &errors {
+        assert!(!describe_error(error).is_empty(), "no hint for {:?}", error);
+    }
+
+    assert_eq!(
+        describe_error(&Error::DivisionByZero),
+        "Check that the divisor is not zero."
+    );
+    let wrapped = Error::InFunction("inv".to_string(), Box::new(Error::DivisionByZero));
+    assert_eq!(
+        describe_error(&wrapped),
+        describe_error(&Error::DivisionByZero)
+    );
+}
+
+#[test]
+fn test_running_total() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.total(), 0.0);
+    for (input, total) in [("5", 5.0), ("10 * 2", 25.0), ("-7.5", 17.5)] {
+        calculator.eval(input).unwrap();
+        calculator.accumulate();
+        assert_eq!(calculator.total(), total);
+    }
+
+    calculator.reset_total();
+    assert_eq!(calculator.total(), 0.0);
+    assert_eq!(calculator.last_result(), -7.5);
+}
+
+#[test]
+fn test_unary_operators() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("-5"), Ok(-5.0));
+    assert_eq!(calculator.eval("--5"), Ok(5.0));
+    assert_eq!(calculator.eval("- - 5"), Ok(5.0));
+    assert_eq!(calculator.eval("+5"), Ok(5.0));
+    assert_eq!(calculator.eval("5!"), Ok(120.0));
+    assert_eq!(calculator.eval("-3!"), Ok(-6.0));
+    assert_eq!(calculator.eval("-2 ^ 2"), Ok(-4.0));
+    assert_eq!(calculator.eval("2 ^ -2"), Ok(0.25));
+    assert_eq!(calculator.eval("-(2 + 3)"), Ok(-5.0));
+    assert_eq!(calculator.eval("3 - -2"), Ok(5.0));
+    assert_eq!(calculator.eval("x = 4"), Ok(4.0));
+    assert_eq!(calculator.eval("-x + neg(x)"), Ok(-8.0));
+    assert_eq!(
+        calculator.eval_value("[-1, -x] * -1"),
+        Ok(Value::Vector(vec![1.0, 4.0]))
+    );
+    assert_eq!(
+        calculator.eval_exact("-(1/3) + neg(-1/3)"),
+        Ok(Rational::integer(0))
+    );
+
+    let tokens = calculator.parse("-5").unwrap();
+    assert_eq!(tokens, vec![Token::UnaryOperator('-'), Token::Number(5.0)]);
+    assert_eq!(
+        explain_expression(&tokens).unwrap(),
+        vec!["Step 1: - 5 (precedence 3)"]
+    );
+}
+
+#[test]
+fn test_durations() {
+    let mut calculator = Calculator::new();
+    let duration = |calculator: &mut Calculator, input: &str| {
+        calculator.eval_value(input).map(|value| value.to_string())
+    };
+    assert_eq!(
+        duration(&mut calculator, "1:30 + 0:45"),
+        Ok("2:15".to_string())
+    );
+    assert_eq!(
+        duration(&mut calculator, "1:30 * 3"),
+        Ok("4:30".to_string())
+    );
+    assert_eq!(
+        duration(&mut calculator, "2 * 0:45"),
+        Ok("1:30".to_string())
+    );
+    assert_eq!(
+        duration(&mut calculator, "0:45 / 2"),
+        Ok("0:22:30".to_string())
+    );
+    assert_eq!(
+        duration(&mut calculator, "0:30 - 1:00"),
+        Ok("-0:30".to_string())
+    );
+    assert_eq!(
+        duration(&mut calculator, "-(1:05:30)"),
+        Ok("-1:05:30".to_string())
+    );
+    assert_eq!(calculator.eval_value("1:30"), Ok(Value::Duration(90.0)));
+    assert_eq!(calculator.eval_value("3:00 / 1:30"), Ok(Value::Scalar(2.0)));
+    assert_eq!(calculator.eval_value("1:30 > 0:45"), Ok(Value::Scalar(1.0)));
+
+    assert!(matches!(
+        calculator.eval_value("1:30 + 5"),
+        Err(Error::InvalidExpression(_))
+    ));
+    assert!(matches!(
+        calculator.eval_value("sqrt(1:30)"),
+        Err(Error::InFunction(..))
+    ));
+    assert!(matches!(
+        calculator.eval("1:30"),
+        Err(Error::InvalidExpression(_))
+    ));
+    assert_eq!(
+        calculator.eval_value("1:75"),
+        Err(Error::InvalidNumber("1:75".to_string()))
+    );
+    assert_eq!(
+        calculator.parse("1:30").unwrap(),
+        vec![Token::Duration(90.0)]
+    );
+}
+
+#[test]
+fn test_readings() {
+    let mut calculator = Calculator::new();
+    let tokens = calculator.parse("50 % (3)").unwrap();
+    let readings = calculator.readings(&tokens);
+    assert_eq!(readings.len(), 2);
+    assert_eq!(readings[0].description, "% as the remainder");
+    assert_eq!(readings[0].tokens, tokens);
+    assert_eq!(readings[1].description, "% as a percentage");
+    assert_eq!(calculator.evaluate(&readings[0].tokens), Ok(2.0));
+    assert_eq!(calculator.evaluate(&readings[1].tokens), Ok(1.5));
+
+    let tokens = calculator.parse("x = 7 % -2").unwrap();
+    let results: Vec<f64> = calculator
+        .readings(&tokens)
+        .iter()
+        .map(|reading| calculator.clone().evaluate(&reading.tokens).unwrap())
+        .collect();
+    assert_eq!(results, vec![1.0, -1.93]);
+
+    for unambiguous in ["50 % 3", "50%", "2 + 3", "50% * 3"] {
+        let tokens = calculator.parse(unambiguous).unwrap();
+        assert!(calculator.readings(&tokens).is_empty(), "{}", unambiguous);
+    }
+
+    calculator.set_strict(true);
+    let tokens = calculator.parse("50 % -3").unwrap();
+    assert!(calculator.readings(&tokens).is_empty());
+}
+
+#[test]
+fn test_relative_percent() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("200 + 10%"), Ok(220.0));
+    assert_eq!(calculator.eval("200 - 10%"), Ok(180.0));
+    assert_eq!(calculator.eval("(150 + 50) + (10%)"), Ok(220.0));
+    assert_eq!(calculator.eval("x = 80"), Ok(80.0));
+    assert_eq!(calculator.eval("x - 25%"), Ok(60.0));
+    assert_eq!(calculator.eval("x += 50%"), Ok(120.0));
+
+    // Only a percentage added or subtracted directly is relative
+    assert_eq!(calculator.eval("200 * 10%"), Ok(20.0));
+    assert_eq!(calculator.eval("200 + 10% * 2"), Ok(200.2));
+    assert_eq!(calculator.eval("10% + 200"), Ok(200.1));
+    assert_eq!(calculator.eval("200 + 10"), Ok(210.0));
+
+    assert_eq!(
+        calculator.eval_value("1:30 + 10%"),
+        Ok(Value::Duration(99.0))
+    );
+    assert_eq!(
+        calculator.eval_value("[100, 200] - 50%"),
+        Ok(Value::Vector(vec![50.0, 100.0]))
+    );
+    assert_eq!(calculator.eval_exact("30 + 10%"), Ok(Rational::integer(33)));
+}
+
+#[test]
+fn test_to_expression_string() {
+    let calculator = Calculator::new();
+    let roundtrip = |input: &str| {
+        let tokens = calculator.parse(input).unwrap();
+        let text = to_expression_string(&tokens);
+        assert_eq!(calculator.parse(&text).unwrap(), tokens, "{}", text);
+        text
+    };
+
+    assert_eq!(roundtrip("2 + 3 * 4"), "2 + 3 * 4");
+    assert_eq!(roundtrip("2+3*4"), "2 + 3 * 4");
+    assert_eq!(roundtrip("-sqrt( x )+50%"), "-sqrt(x) + 50%");
+    assert_eq!(
+        roundtrip("mod(-7,3) <= 2 and not 0"),
+        "mod(-7, 3) <= 2 and not 0"
+    );
+    assert_eq!(roundtrip("50 % -3"), "50 % -3");
+    assert_eq!(roundtrip("2(3 + 1)"), "2 * (3 + 1)");
+    assert_eq!(roundtrip("[1, 2] * 1:30 + 5!"), "[1, 2] * 1:30 + 5!");
+    assert_eq!(roundtrip("0x10 - 5²"), "16 - 5 ^ 2");
+
+    assert_eq!(Token::Operator('+').operator_char(), Some('+'));
+    assert_eq!(Token::UnaryOperator('!').operator_char(), Some('!'));
+    assert_eq!(Token::Number(1.0).operator_char(), None);
+}
+
+#[test]
+fn test_nan_propagation() {
+    let mut calculator = Calculator::new();
+    assert!(!calculator.propagate_nan());
+    assert_eq!(calculator.eval("isinf(1e308 * 10)"), Err(Error::NotFinite));
+    assert_eq!(calculator.eval("isnan(1) + isinf(1)"), Ok(0.0));
+
+    calculator.set_propagate_nan(true);
+    assert_eq!(calculator.eval("isinf(1e308 * 10)"), Ok(1.0));
+    assert_eq!(calculator.eval("isinf(-1e308 * 10)"), Ok(1.0));
+    assert_eq!(calculator.eval("isnan(1e308 * 10)"), Ok(0.0));
+    assert_eq!(calculator.eval("isnan(1e308 * 10 - 1e308 * 10)"), Ok(1.0));
+    assert_eq!(calculator.eval("1e308 * 10"), Ok(f64::INFINITY));
+    assert!(calculator.eval("(1e308 * 10) * 0").unwrap().is_nan());
+    // Division by zero is still an error rather than NaN
+    assert_eq!(calculator.eval("isnan(0 / 0)"), Err(Error::DivisionByZero));
+}
+
+#[test]
+fn test_units() {
+    let mut calculator = Calculator::new();
+    let km = Unit::named("km").unwrap();
+    let text = |calculator: &mut Calculator, input: &str| {
+        calculator.eval_value(input).map(|value| value.to_string())
+    };
+
+    assert_eq!(
+        calculator.eval_value("5km + 300m"),
+        Ok(Value::Quantity(5.3, km))
+    );
+    assert_eq!(text(&mut calculator, "300m + 5km"), Ok("5300m".to_string()));
+    assert_eq!(
+        text(&mut calculator, "2 * 750g - 0.5kg"),
+        Ok("1000g".to_string())
+    );
+    assert_eq!(
+        text(&mut calculator, "-(12cm) + 1m"),
+        Ok("88cm".to_string())
+    );
+    assert_eq!(text(&mut calculator, "1500mm / 3"), Ok("500mm".to_string()));
+    assert_eq!(calculator.eval_value("1km / 250m"), Ok(Value::Scalar(4.0)));
+    assert_eq!(calculator.eval_value("1kg > 999g"), Ok(Value::Scalar(1.0)));
+    assert_eq!(text(&mut calculator, "2km + 10%"), Ok("2.2km".to_string()));
+
+    assert_eq!(
+        calculator.eval_value("5km + 3kg"),
+        Err(Error::IncompatibleUnits("km".to_string(), "kg".to_string()))
+    );
+    assert!(matches!(
+        calculator.eval_value("5km + 3"),
+        Err(Error::InvalidExpression(_))
+    ));
+    assert!(matches!(
+        calculator.eval_value("sqrt(4m)"),
+        Err(Error::InFunction(..))
+    ));
+
+    // Only known unit names are units; others are still implicit products
+    assert_eq!(calculator.eval("x = 4"), Ok(4.0));
+    assert_eq!(calculator.eval("2x"), Ok(8.0));
+    assert_eq!(
+        calculator.parse("5km").unwrap(),
+        vec![Token::Quantity(5.0, km)]
+    );
+}
+
+#[test]
+fn test_evaluate_rpn_steps() {
+    let mut calculator = Calculator::new();
+    let rpn = vec![
+        Token::Number(2.0),
+        Token::Number(3.0),
+        Token::Number(4.0),
+        Token::Operator('*'),
+        Token::Operator('+'),
+    ];
+    let (result, steps) = calculator.evaluate_rpn_steps(&rpn).unwrap();
+    assert_eq!(result, 14.0);
+    assert_eq!(
+        steps,
+        vec![
+            StepInfo {
+                operation: "*".to_string(),
+                operands: vec![3.0, 4.0],
+                result: 12.0,
+            },
+            StepInfo {
+                operation: "+".to_string(),
+                operands: vec![2.0, 12.0],
+                result: 14.0,
+            },
+        ]
+    );
+
+    let rpn = vec![
+        Token::Number(9.0),
+        Token::Call("sqrt".to_string(), 1),
+        Token::UnaryOperator('-'),
+    ];
+    let (result, steps) = calculator.evaluate_rpn_steps(&rpn).unwrap();
+    assert_eq!(result, -3.0);
+    assert_eq!(steps[0].operation, "sqrt");
+    assert_eq!(steps[1].operands, vec![3.0]);
+
+    let rpn = vec![Token::Number(2.0), Token::Operator('+')];
+    assert!(matches!(
+        calculator.evaluate_rpn_steps(&rpn),
+        Err(Error::InvalidExpression(_))
+    ));
+}
+
+#[test]
+fn test_physical_constants() {
+    let mut calculator = Calculator::new();
+    assert_eq!(
+        calculator.eval("c"),
+        Err(Error::UndefinedVariable("c".to_string()))
+    );
+
+    calculator.set_physics(true);
+    assert_eq!(calculator.eval("c"), Ok(299_792_458.0));
+    assert_eq!(calculator.eval("2 * NA"), Ok(1.204_428_152e24));
+    assert_eq!(
+        calculator.eval("h * c / 1"),
+        Ok(6.626_070_15e-34 * 299_792_458.0)
+    );
+    assert_eq!(calculator.eval("2kg / 1kg * gn"), Ok(19.6133));
+
+    // Variables take precedence over constants
+    assert_eq!(calculator.eval("c = 3"), Ok(3.0));
+    assert_eq!(calculator.eval("c * 2"), Ok(6.0));
+
+    assert!(PHYSICAL_CONSTANTS.iter().any(|(name, _, _)| *name == "G"));
+}
+
+#[test]
+fn test_pipe() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("16 |> sqrt |> sqrt"), Ok(2.0));
+    assert_eq!(calculator.eval("16|>sqrt"), Ok(4.0));
+    // Everything before the pipe is its operand
+    assert_eq!(calculator.eval("16 + 9 |> sqrt"), Ok(5.0));
+    assert_eq!(calculator.eval("(16 |> sqrt) + 1"), Ok(5.0));
+    assert_eq!(calculator.eval("mod(16 |> sqrt, 3)"), Ok(1.0));
+    assert_eq!(calculator.eval("50% |> sq"), Ok(0.25));
+
+    calculator.register_function("double", 1, |args| Ok(args[0] * 2.0));
+    assert_eq!(calculator.eval("x = 3 |> double"), Ok(6.0));
+    assert_eq!(calculator.variable("x"), Some(6.0));
+
+    assert_eq!(
+        calculator.eval("4 |> mod"),
+        Err(Error::WrongArgumentCount("mod".to_string(), 2, 1))
+    );
+    assert!(matches!(
+        calculator.eval("4 |> 2"),
+        Err(Error::InvalidExpression(_))
+    ));
+    let tokens = calculator.parse("9 |> sqrt").unwrap();
+    assert_eq!(to_expression_string(&tokens), "9 |> sqrt");
+}
+
+#[test]
+fn test_calculate_i64() {
+    assert_eq!(calculate_i64("6 / 2"), Ok(3));
+    assert_eq!(calculate_i64("-2 ^ 10"), Ok(-1024));
+    assert_eq!(
+        calculate_i64("7 / 2"),
+        Err(Error::NotAnInteger("3.5".to_string()))
+    );
+    assert_eq!(calculate_i64("2 ^ 63"), Err(Error::Overflow));
+    assert_eq!(calculate_i64("-2 ^ 63"), Ok(i64::MIN));
+    assert_eq!(calculate_i64("1 / 0"), Err(Error::DivisionByZero));
+}
+
+#[test]
+fn test_precedence_notes() {
+    let calculator = Calculator::new();
+    let notes = |input: &str| precedence_notes(&calculator.parse(input).unwrap());
+
+    assert_eq!(
+        notes("2 + 3 * 4"),
+        vec!["multiplication was applied before addition"]
+    );
+    assert_eq!(
+        notes("1 - 2 * 3 ^ 2"),
+        vec![
+            "multiplication was applied before subtraction",
+            "exponentiation was applied before subtraction",
+            "exponentiation was applied before multiplication",
+        ]
+    );
+    assert_eq!(
+        notes("2 ^ 3 ^ 2"),
+        vec!["exponentiation was applied from right to left"]
+    );
+    assert_eq!(
+        notes("x == 1 + 1"),
+        vec!["addition was applied before comparison"]
+    );
+    assert!(notes("2 * 3 + 4").is_empty());
+    assert!(notes("(2 + 3) * 4").is_empty());
+    assert!(notes("mod(2 + 3, 4 * 5)").is_empty());
+    assert!(notes("8 - 3 - 2").is_empty());
+}
+
+#[test]
+fn test_eval_batch() {
+    let mut calculator = Calculator::new();
+    let results = calculator.eval_batch(["x = 4", "x * 2", "y"], true);
+    let values: Vec<_> = results.iter().map(|batch| batch.result.clone()).collect();
+    assert_eq!(
+        values,
+        vec![
+            Ok(4.0),
+            Ok(8.0),
+            Err(Error::UndefinedVariable("y".to_string()))
+        ]
+    );
+    assert!(results.iter().all(|batch| batch.elapsed.is_some()));
+    assert_eq!(calculator.variable("x"), Some(4.0));
+
+    // A long sum takes well over the clock's resolution, so its time cannot
+    // round down to zero
+    let sum = ["1"; 20_000].join(" + ");
+    let timed = calculator.eval_batch([sum.as_str()], true);
+    assert_eq!(timed[0].result, Ok(20_000.0));
+    assert!(timed[0]
+        .elapsed
+        .is_some_and(|elapsed| elapsed > std::time::Duration::ZERO));
+
+    let untimed = calculator.eval_batch(vec!["x + 1"], false);
+    assert_eq!(
+        untimed,
+        vec![BatchResult {
+            result: Ok(5.0),
+            elapsed: None,
+        }]
+    );
+}
+
+#[test]
+fn test_negative_base_powers() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("(-8) ^ (1/3)"), Ok(-2.0));
+    assert_eq!(calculator.eval("(-8) ^ (2/3)"), Ok(4.0));
+    assert!((calculator.eval("(-32) ^ 0.2").unwrap() + 2.0).abs() < 1e-12);
+    assert_eq!(calculator.eval("(-2) ^ 3"), Ok(-8.0));
+    assert_eq!(calculator.eval("(-2) ^ -1"), Ok(-0.5));
+    assert_eq!(
+        calculator.eval("(-4) ^ 0.5"),
+        Err(Error::DomainError("-4 ^ 0.5 has no real value".to_string()))
+    );
+    assert!(matches!(
+        calculator.eval("(-8) ^ 0.1"),
+        Err(Error::DomainError(_))
+    ));
+}
+
+#[test]
+fn test_bare_decimal_points() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval(".5 + .5"), Ok(1.0));
+    assert_eq!(calculator.eval("5. * 2"), Ok(10.0));
+    assert_eq!(calculator.eval("-.25"), Ok(-0.25));
+    assert_eq!(calculator.eval("2.^3."), Ok(8.0));
+    assert_eq!(calculator.eval("(.5)"), Ok(0.5));
+    assert_eq!(calculator.eval(".5e1"), Ok(5.0));
+    assert_eq!(
+        calculator.eval_exact(".1 + .2").unwrap().to_string(),
+        "3/10"
+    );
+
+    calculator.set_decimal_comma(true);
+    assert_eq!(calculator.eval(",5 + 5,"), Ok(5.5));
+}
+
+#[test]
+fn test_eval_ast() {
+    let x = || Expr::Var("x".to_string());
+    // (x + 1) * sqrt(16) - 2 ^ 3
+    let expr = (x() + 1.0.into()) * Expr::Call("sqrt".to_string(), vec![16.0.into()])
+        - Expr::binary('^', 2.0.into(), 3.0.into());
+
+    let mut calculator = Calculator::new();
+    assert_eq!(
+        calculator.eval_ast(&expr),
+        Err(Error::UndefinedVariable("x".to_string()))
+    );
+    calculator.set_variable("x", 4.0);
+    assert_eq!(calculator.eval_ast(&expr), Ok(12.0));
+
+    assert_eq!(
+        eval_ast(&-Expr::Var("e".to_string())),
+        Ok(-std::f64::consts::E)
+    );
+    assert_eq!(
+        eval_ast(&(Expr::Num(1.0) / 0.0.into())),
+        Err(Error::DivisionByZero)
+    );
+    assert!(matches!(
+        eval_ast(&Expr::Call("hypot".to_string(), vec![3.0.into()])),
+        Err(Error::WrongArgumentCount(..))
+    ));
+
+    let parsed = parse_to_ast("2 * (3 + 4) - 5!").unwrap();
+    assert_eq!(eval_ast(&parsed), Ok(-106.0));
+    assert_eq!(eval_ast(&parse_to_ast("200 + 10%").unwrap()), Ok(220.0));
+}
+
+#[test]
+fn test_negative_zero_display() {
+    let mut calculator = Calculator::new();
+    let result = calculator.eval("0 * -1").unwrap();
+    // The sign is kept for further calculation, but not shown
+    assert!(result.is_sign_negative());
+    assert_eq!(format_number(result, None), "0");
+    assert_eq!(
+        calculator.eval("atan2(0 * -1, -1)"),
+        Ok(-std::f64::consts::PI)
+    );
+    assert_eq!(
+        format_number(calculator.eval("-1 * 0").unwrap(), Some(3)),
+        "0"
+    );
+}
+
+#[test]
+fn test_eval_shared() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Calculator>();
+    assert_send_sync::<Value>();
+    assert_send_sync::<Error>();
+    assert_send_sync::<Expr>();
+
+    let mut calculator = Calculator::new();
+    calculator.eval("x = 10").unwrap();
+    calculator.register_function("double", 1, |args| Ok(args[0] * 2.0));
+
+    let calculator = &calculator;
+    let results: Vec<Result<f64, Error>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..8)
+            .map(|i| scope.spawn(move || calculator.eval_shared(&format!("double(x) + {}", i))))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    });
+    let expected: Vec<Result<f64, Error>> = (0..8).map(|i| Ok(20.0 + f64::from(i))).collect();
+    assert_eq!(results, expected);
+
+    // Assignments only change the copy each call works on
+    assert_eq!(calculator.eval_shared("x = 1"), Ok(1.0));
+    assert_eq!(calculator.variable("x"), Some(10.0));
+    assert_eq!(
+        calculator.eval_shared("y + 1"),
+        Err(Error::UndefinedVariable("y".to_string()))
+    );
+}
+
+#[test]
+fn test_arg_separator() {
+    let mut calculator = Calculator::new();
+    calculator.register_function("max", 2, |args| Ok(args[0].max(args[1])));
+    assert_eq!(calculator.arg_separator(), ',');
+    assert!(calculator.eval("max(1; 2)").is_err());
+
+    calculator.set_arg_separator(';').unwrap();
+    assert_eq!(calculator.eval("max(1; 2)"), Ok(2.0));
+    assert_eq!(calculator.eval("hypot(3;4) + 0.5"), Ok(5.5));
+    assert_eq!(
+        calculator.eval_value("[1; 2] * 2"),
+        Ok(Value::Vector(vec![2.0, 4.0]))
+    );
+    assert_eq!(
+        calculator.eval("max(1, 2)"),
+        Err(Error::InvalidExpression(",".to_string()))
+    );
+
+    // A decimal comma always takes ; for arguments
+    calculator.set_arg_separator(',').unwrap();
+    calculator.set_decimal_comma(true);
+    assert_eq!(calculator.eval("max(1,5; 2)"), Ok(2.0));
+    assert_eq!(calculator.eval("max(2,5; 2)"), Ok(2.5));
+
+    assert!(matches!(
+        calculator.set_arg_separator('|'),
+        Err(Error::InvalidArgument(_))
+    ));
+    assert_eq!(calculator.arg_separator(), ',');
+}
+
+#[test]
+fn test_estimate_cost() {
+    let cost = |input: &str| estimate_cost(&parse_expression(vec![input]).unwrap());
+    assert_eq!(cost("2 + 3"), 1);
+    assert_eq!(cost("100 !"), 100);
+    assert!(cost("100 !") > cost("2 + 3") * 50);
+    assert_eq!(cost("factorial(20) + 1"), 21);
+    assert_eq!(cost("2 ^ 3 * 4"), 11);
+    assert_eq!(cost("(2 + 3)!"), 1001);
+    assert_eq!(cost("16 |> sqrt"), 1);
+    assert_eq!(cost("42"), 0);
+    assert_eq!(cost("1e300 !"), u64::MAX);
+}
+
+#[test]
+fn test_bool_function() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("bool(3.7)"), Ok(1.0));
+    assert_eq!(calculator.eval("bool(0)"), Ok(0.0));
+    assert_eq!(calculator.eval("bool(-0.5)"), Ok(1.0));
+
+    calculator.eval("x = 4").unwrap();
+    assert_eq!(calculator.eval("bool(x) * 5"), Ok(5.0));
+    assert_eq!(calculator.eval("bool(x - 4) * 5 + 1"), Ok(1.0));
+    assert_eq!(calculator.eval("bool(x > 2) + bool(x < 2)"), Ok(1.0));
+    assert_eq!(
+        calculator.eval_exact("bool(1/3) * 2").unwrap().to_string(),
+        "2"
+    );
+    assert_eq!(
+        calculator.eval("bool(1, 2)"),
+        Err(Error::WrongArgumentCount("bool".to_string(), 1, 2))
+    );
+}
+
+#[test]
+fn test_unary_minus_and_power() {
+    // A sign binds more loosely than ^, so -2 ^ 2 is -(2 ^ 2)
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("-2 ^ 2"), Ok(-4.0));
+    assert_eq!(calculator.eval("(-2) ^ 2"), Ok(4.0));
+    assert_eq!(calculator.eval("-2 ^ -2"), Ok(-0.25));
+    assert_eq!(calculator.eval("3 * -2 ^ 2"), Ok(-12.0));
+    assert_eq!(calculator.eval("-2 ^ 3 ^ 2"), Ok(-512.0));
+    calculator.eval("x = 3").unwrap();
+    assert_eq!(calculator.eval("-x ^ 2"), Ok(-9.0));
+    assert_eq!(calculator.eval("1 - -x ^ 2"), Ok(10.0));
+    assert_eq!(calculator.eval_exact("-2 ^ 2").unwrap().to_string(), "-4");
+    assert_eq!(calculator.eval_exact("(-2) ^ 2").unwrap().to_string(), "4");
+}
+
+#[test]
+fn test_numeric_derivative() {
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-6 * b.abs().max(1.0);
+    assert!(close(numeric_derivative(|x| x * x, 3.0), 6.0));
+    assert!(close(numeric_derivative(f64::sin, 0.0), 1.0));
+    assert!(close(numeric_derivative(f64::exp, 10.0), 10f64.exp()));
+    assert!(close(numeric_derivative(|x| 1e6 * x, -2e8), 1e6));
+
+    // Differentiating an expression with the evaluator
+    let calculator = Calculator::new();
+    let tokens = calculator.parse("x ^ 3 - 2x").unwrap();
+    let f = |x: f64| {
+        let mut calculator = calculator.clone();
+        calculator.set_variable("x", x);
+        calculator.evaluate(&tokens).unwrap_or(f64::NAN)
+    };
+    assert!(close(numeric_derivative(f, 2.0), 10.0));
+}
+
+#[test]
+fn test_timeout() {
+    use std::time::Duration;
+
+    let mut calculator = Calculator::new();
+    calculator.register_function("slow", 1, |args| {
+        std::thread::sleep(Duration::from_millis(20));
+        Ok(args[0])
+    });
+    assert_eq!(calculator.eval("slow(2) + 1"), Ok(3.0));
+
+    let limit = Duration::from_millis(5);
+    calculator.set_timeout(Some(limit));
+    assert_eq!(calculator.timeout(), Some(limit));
+    assert_eq!(calculator.eval("slow(2) + 1"), Err(Error::Timeout(limit)));
+    assert_eq!(calculator.eval("slow(2)"), Err(Error::Timeout(limit)));
+    assert_eq!(calculator.eval("2 + 1"), Ok(3.0));
+    assert_eq!(
+        Error::Timeout(limit).to_string(),
+        "The expression took longer than 5 ms to evaluate"
+    );
+
+    calculator.set_timeout(None);
+    assert_eq!(calculator.eval("slow(2)"), Ok(2.0));
+}
+
+#[test]
+fn test_factorial_overflow() {
+    let mut calculator = Calculator::new();
+    let largest = calculator.eval("170!").unwrap();
+    assert!(largest.is_finite());
+    assert!((largest / 7.257_415_615_307_994e306 - 1.0).abs() < 1e-12);
+    assert_eq!(calculator.eval("171!"), Err(Error::Overflow));
+    assert_eq!(
+        calculator.eval("factorial(200)"),
+        Err(Error::InFunction(
+            "factorial".to_string(),
+            Box::new(Error::Overflow)
+        ))
+    );
+    assert_eq!(
+        Error::Overflow.to_string(),
+        "Result is too large to represent"
+    );
+}
+
+#[test]
+fn test_parse_radix() {
+    assert_eq!(parse_radix("255"), Ok(255.0));
+    assert_eq!(parse_radix("-2.5"), Ok(-2.5));
+    assert_eq!(parse_radix("0xff"), Ok(255.0));
+    assert_eq!(parse_radix("0XFF"), Ok(255.0));
+    assert_eq!(parse_radix("0o17"), Ok(15.0));
+    assert_eq!(parse_radix("-0b1010"), Ok(-10.0));
+    assert_eq!(parse_radix("+0b1"), Ok(1.0));
+    assert_eq!(parse_radix("0x1.8p1"), Ok(3.0));
+    assert_eq!(parse_radix("1.5e3"), Ok(1500.0));
+    assert_eq!(parse_radix("1e400"), Err(Error::NotFinite));
+    for invalid in [
+        "0b", "0b2", "0o+1", "0x+1", "0xg", "1.5x", "inf", "NaN", "ten", "",
+    ] {
+        assert_eq!(
+            parse_radix(invalid),
+            Err(Error::InvalidNumber(invalid.to_string()))
+        );
+    }
+}
+
+#[test]
+fn test_intermediate_rounding() {
+    // Three payments of 1/3 each, at two decimals
+    let mut calculator = Calculator::new();
+    let exact = calculator.eval("100 / 3 * 3").unwrap();
+    assert!((exact - 100.0).abs() < 1e-9);
+
+    calculator.set_intermediate_rounding(Some((2, RoundingMode::HalfEven)));
+    assert_eq!(
+        calculator.intermediate_rounding(),
+        Some((2, RoundingMode::HalfEven))
+    );
+    assert_eq!(calculator.eval("100 / 3 * 3"), Ok(99.99));
+    assert_eq!(calculator.eval("0.125 + 0"), Ok(0.12));
+    assert_eq!(calculator.eval("-(2 / 3)"), Ok(-0.67));
+
+    calculator.set_intermediate_rounding(Some((0, RoundingMode::Truncate)));
+    assert_eq!(calculator.eval("7 / 2 * 2"), Ok(6.0));
+
+    calculator.set_intermediate_rounding(None);
+    assert_eq!(calculator.eval("7 / 2 * 2"), Ok(7.0));
+}
+
+#[test]
+fn test_float_remainder() {
+    let mut calculator = Calculator::new();
+    assert_eq!(calculator.eval("17 % 5"), Ok(2.0));
+    assert_eq!(calculator.eval("5.5 % 2"), Ok(1.5));
+    assert_eq!(calculator.eval("7.5 % -2"), Ok(1.5));
+    assert_eq!(calculator.eval("-5.5 % 2"), Ok(-1.5));
+    assert_eq!(calculator.eval("0.75 % 0.25"), Ok(0.0));
+    // Same precedence as * and /, applied left to right
+    assert_eq!(calculator.eval("2 + 7 % 4 * 2"), Ok(8.0));
+    assert_eq!(calculator.eval("5 % 0"), Err(Error::DivisionByZero));
+    assert_eq!(calculator.eval("5.5 % 0.0"), Err(Error::DivisionByZero));
+
+    let tokens = parse_expression(vec!["5.5", "%", "2"]).unwrap();
+    assert_eq!(evaluate_expression(&tokens), Ok(1.5));
+}
+
+#[test]
+fn test_preprocess_input() {
+    for messy in [
+        "5 - 3",
+        "  5 − 3  ",
+        "5\t\u{2013}  3 # change",
+        "5\u{00A0}-\u{00A0}3",
+    ] {
+        assert_eq!(preprocess_input(messy), "5 - 3");
+    }
+    assert_eq!(preprocess_input("6 × 7 ÷ (2 ‐ 1)"), "6 * 7 / (2 - 1)");
+    assert_eq!(preprocess_input("x  =  2"), "x = 2");
+    assert_eq!(preprocess_input("# only a comment"), "");
+    assert_eq!(preprocess_input(""), "");
+
+    let mut calculator = Calculator::new();
+    let messy = "  6 × 7\t− 2 # answer";
+    assert_eq!(
+        calculator.eval(&preprocess_input(messy)),
+        calculator.eval(messy)
+    );
+}
+
+#[test]
+fn test_user_functions() {
+    let mut calculator = Calculator::new();
+    calculator.define_function("double(x) = x * 2").unwrap();
+    assert_eq!(calculator.eval("double(5)"), Ok(10.0));
+    assert_eq!(calculator.eval("double(double(1.5)) - 1"), Ok(5.0));
+    assert_eq!(calculator.user_functions(), ["double"]);
+
+    // The parameter hides a variable of the same name only during the call
+    calculator.eval("x = 100").unwrap();
+    calculator.define_function("offset(x) = x + y").unwrap();
+    assert_eq!(
+        calculator.eval("offset(1)"),
+        Err(Error::InFunction(
+            "offset".to_string(),
+            Box::new(Error::UndefinedVariable("y".to_string()))
+        ))
+    );
+    calculator.eval("y = 0.5").unwrap();
+    assert_eq!(calculator.eval("offset(1) + x"), Ok(101.5));
+    assert_eq!(calculator.variable("x"), Some(100.0));
+
+    // Redefining replaces the function
+    calculator.define_function("double(n) = n + n + 0").unwrap();
+    assert_eq!(calculator.eval("double(4)"), Ok(8.0));
+    assert_eq!(
+        calculator.eval("double(1, 2)"),
+        Err(Error::WrongArgumentCount("double".to_string(), 1, 2))
+    );
+
+    calculator
+        .define_function("forever(x) = forever(x + 1)")
+        .unwrap();
+    assert_eq!(
+        calculator.eval("forever(0)"),
+        Err(Error::RecursionLimit("forever".to_string()))
+    );
+    // A failed call leaves no parameter behind
+    assert_eq!(calculator.variable("n"), None);
+
+    for invalid in [
+        "double x = 2",
+        "2(x) = x",
+        "f(2) = x",
+        "f(x) x",
+        "sqrt(x) = x",
+    ] {
+        assert!(
+            matches!(
+                calculator.define_function(invalid),
+                Err(Error::InvalidAssignment(_))
+            ),
+            "{} was accepted",
+            invalid
+        );
+    }
+    assert_eq!(
+        calculator.define_function("f(x) = (x"),
+        Err(Error::MismatchedBracket)
+    );
+}
+
+#[test]
+fn test_kahan_summation() {
+    let tenths = ["0.1"; 10].join(" + ");
+    let mut calculator = Calculator::new();
+    assert!(!calculator.kahan());
+    let naive = calculator.eval(&tenths).unwrap();
+    assert_eq!(naive, 0.9999999999999999);
+
+    calculator.set_kahan(true);
+    assert_eq!(calculator.eval(&tenths), Ok(1.0));
+    // Many small values: the compensated sum is closer to the exact 10
+    let many = vec!["0.001"; 10_000].join(" + ");
+    let compensated = calculator.eval(&many).unwrap();
+    calculator.set_kahan(false);
+    let plain = calculator.eval(&many).unwrap();
+    assert!((compensated - 10.0).abs() < (plain - 10.0).abs());
+    assert!((compensated - 10.0).abs() < 1e-14);
+
+    // Large terms that cancel no longer swallow the small ones
+    calculator.set_kahan(true);
+    assert_eq!(calculator.eval("1e100 + 1 - 1e100"), Ok(1.0));
+    assert_eq!(calculator.eval("2 * 3 + 4 - 2 ^ 2"), Ok(6.0));
+    assert_eq!(calculator.eval("10 - (2 - 3) + 1"), Ok(12.0));
+    assert_eq!(calculator.eval("-1 + 2 - -3"), Ok(4.0));
+    assert_eq!(calculator.eval("200 + 10%"), Ok(220.0));
+    assert_eq!(
+        calculator.eval("1 + x"),
+        Err(Error::UndefinedVariable("x".to_string()))
+    );
+    assert_eq!(calculator.eval("1 + 1 / 0"), Err(Error::DivisionByZero));
+
+    // The sum counts towards the step limit and is rounded like any result
+    calculator.set_step_limit(3);
+    assert_eq!(
+        calculator.eval("1 + 2 + 3 + 4 + 5"),
+        Err(Error::EvaluationLimitExceeded)
+    );
+    assert_eq!(calculator.eval("1 + 2 + 3 + 4"), Ok(10.0));
+    calculator.set_intermediate_rounding(Some((2, RoundingMode::HalfEven)));
+    assert_eq!(calculator.eval("0.125 + 0.5"), Ok(0.62));
+
+    assert_eq!(compensated_sum(&[]), 0.0);
+    assert_eq!(compensated_sum(&[1e100, 1.0, -1e100]), 1.0);
+}
+
+#[test]
+fn test_deterministic_results() {
+    // IEEE 754 arithmetic is correctly rounded, so these bits are the same
+    // on every platform and every run
+    let mut calculator = Calculator::new();
+    for (input, bits) in [
+        ("0.1 + 0.2", 0x3FD3_3333_3333_3334_u64),
+        ("1 / 3", 0x3FD5_5555_5555_5555),
+        ("sqrt(2)", 0x3FF6_A09E_667F_3BCD),
+    ] {
+        let first = calculator.eval(input).unwrap();
+        assert_eq!(first.to_bits(), bits, "{}", input);
+        assert_eq!(calculator.eval(input).unwrap().to_bits(), first.to_bits());
+    }
+}
+
+#[test]
+fn test_user_functions_share_step_limit() {
+    // Each level calls the one below twice, so f20(1) takes millions of steps
+    let mut calculator = Calculator::new();
+    calculator.define_function("f0(x) = x + 1").unwrap();
+    for i in 1..=20 {
+        let definition = format!("f{}(x) = f{}(x) + f{}(x)", i, i - 1, i - 1);
+        calculator.define_function(&definition).unwrap();
+    }
+    assert_eq!(calculator.eval("f3(1)"), Ok(16.0));
+
+    calculator.set_step_limit(10_000);
+    let result = calculator.eval("f20(1)");
+    assert_eq!(
+        result.as_ref().map_err(Error::root_cause),
+        Err(&Error::EvaluationLimitExceeded)
+    );
+    assert_eq!(calculator.variable("x"), None);
+}
+
+#[test]
+fn test_variables_beat_units() {
+    let mut calculator = Calculator::new();
+    assert!(matches!(
+        calculator.eval_value("2m"),
+        Ok(Value::Quantity(..))
+    ));
+
+    calculator.eval("m = 3").unwrap();
+    assert_eq!(calculator.eval("2m"), Ok(6.0));
+    assert_eq!(calculator.eval("2m + 1"), Ok(7.0));
+    // Other units are still units
+    assert!(matches!(
+        calculator.eval_value("2km"),
+        Ok(Value::Quantity(..))
+    ));
+
+    calculator.define_function("twice(g) = 2g").unwrap();
+    assert_eq!(calculator.eval("twice(4)"), Ok(8.0));
+
+    calculator.set_strict(true);
+    assert!(matches!(
+        calculator.eval("2m"),
+        Err(Error::InvalidExpression(_))
+    ));
+}